@@ -0,0 +1,169 @@
+use crate::error::Error;
+use crate::file::{self, CacheRecord, Query, Source};
+use crate::Run;
+use axum::extract::{Query as QueryParams, State};
+use axum::http::StatusCode;
+use axum::response::sse::{Event, KeepAlive, Sse};
+use axum::response::{IntoResponse, Response};
+use axum::routing::get;
+use axum::{Json, Router};
+use futures_util::{Stream, StreamExt};
+use serde::Deserialize;
+use std::convert::Infallible;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio_stream::wrappers::BroadcastStream;
+
+#[derive(Debug, Deserialize)]
+struct HistoryParams {
+    since: Option<String>,
+    until: Option<String>,
+    last: Option<usize>,
+}
+
+fn error(status: StatusCode, message: String) -> Response {
+    (status, Json(serde_json::json!({ "error": message }))).into_response()
+}
+
+/// Reads and checks the whole store, off the async workers.
+async fn load_records(run: &Arc<Run>) -> Result<Vec<CacheRecord>, Error> {
+    let reader = run.clone();
+    tokio::task::spawn_blocking(move || file::get_data_from_file(&reader.output_dir, reader.key.as_deref()))
+        .await
+        .map_err(|e| Error::io(&run.output_dir, std::io::Error::other(e)))?
+}
+
+/// `GET /price/latest`: the most recent record in the store that stands for
+/// a whole run, i.e. not one client's part of a `multi` run.
+async fn latest(State(run): State<Arc<Run>>) -> Response {
+    let records = match load_records(&run).await {
+        Ok(records) => records,
+        Err(e) => return error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    };
+    match records.iter().rfind(|record| !matches!(record.source, Source::Client(_))) {
+        Some(record) => Json(record).into_response(),
+        None => error(StatusCode::NOT_FOUND, "no cached price yet".to_string()),
+    }
+}
+
+/// `GET /price/history?since=&until=&last=`: the same filters as read mode.
+async fn history(State(run): State<Arc<Run>>, QueryParams(params): QueryParams<HistoryParams>) -> Response {
+    let since = match params.since.as_deref().map(|since| crate::parse_time(since, false)).transpose() {
+        Ok(since) => since,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let until = match params.until.as_deref().map(|until| crate::parse_time(until, true)).transpose() {
+        Ok(until) => until,
+        Err(e) => return error(StatusCode::BAD_REQUEST, e.to_string()),
+    };
+    let query = Query { since, until, last: params.last };
+    match load_records(&run).await {
+        Ok(records) => Json(query.apply(records)).into_response(),
+        Err(e) => error(StatusCode::INTERNAL_SERVER_ERROR, e.to_string()),
+    }
+}
+
+/// `GET /price/stream`: server-sent events, one `tick` event per price.
+async fn stream(State(run): State<Arc<Run>>) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    let ticks = BroadcastStream::new(run.telemetry.subscribe()).filter_map(|tick| async move {
+        // A lagging subscriber just skips the ticks it missed.
+        let tick = tick.ok()?;
+        Event::default().event("tick").json_data(tick).ok().map(Ok)
+    });
+    Sse::new(ticks).keep_alive(KeepAlive::default())
+}
+
+/// Serves the price API on `listener`, bound by the caller so that a busy
+/// address is reported before anything runs.
+pub async fn serve(listener: TcpListener, run: Arc<Run>) -> std::io::Result<()> {
+    let app = Router::new()
+        .route("/price/latest", get(latest))
+        .route("/price/history", get(history))
+        .route("/price/stream", get(stream))
+        .with_state(run);
+    axum::serve(listener, app).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::PriceData;
+    use std::net::SocketAddr;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use tokio::net::TcpStream;
+
+    async fn start(name: &str) -> (SocketAddr, Arc<Run>) {
+        let dir = std::env::temp_dir().join(format!("simple-api-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let run = Arc::new(Run::replay(Vec::new(), dir));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, run.clone()));
+        (addr, run)
+    }
+
+    async fn request(addr: SocketAddr, path: &str) -> TcpStream {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, addr);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        stream
+    }
+
+    async fn get(addr: SocketAddr, path: &str) -> (u16, serde_json::Value) {
+        let mut response = String::new();
+        request(addr, path).await.read_to_string(&mut response).await.unwrap();
+        let status = response[9..12].parse().unwrap();
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    fn write(run: &Run, source: Source, average: f64) {
+        let record = CacheRecord::now(source, PriceData::new(vec![average.into()], average.into()));
+        match record.source {
+            Source::Client(id) => file::write_client_data_to_file(&run.output_dir, id, &record, None).unwrap(),
+            _ => file::write_data_to_file(&run.output_dir, &record, None).unwrap(),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_latest_and_history() {
+        let (addr, run) = start("records").await;
+        assert_eq!(get(addr, "/price/latest").await.0, 404);
+
+        write(&run, Source::Window { seconds: 60, partial: false }, 100.0);
+        write(&run, Source::Aggregate { clients: 2 }, 101.0);
+        write(&run, Source::Client(0), 102.0);
+        let (status, latest) = get(addr, "/price/latest").await;
+        assert_eq!((status, latest["average"].as_f64()), (200, Some(101.0)));
+
+        let (status, history) = get(addr, "/price/history?last=2").await;
+        let averages: Vec<_> = history.as_array().unwrap().iter().map(|record| record["average"].as_f64()).collect();
+        assert_eq!((status, averages), (200, vec![Some(101.0), Some(102.0)]));
+        let (status, body) = get(addr, "/price/history?since=yesterday").await;
+        assert_eq!(status, 400);
+        assert!(body["error"].as_str().unwrap().contains("invalid time"));
+        std::fs::remove_dir_all(&run.output_dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_pushes_ticks() {
+        let (addr, run) = start("stream").await;
+        let mut stream = request(addr, "/price/stream").await;
+        let mut received = String::new();
+        let mut buffer = [0u8; 1024];
+        // The handler subscribes before it answers, so ticks sent once the
+        // headers are in reach this stream.
+        while !received.contains("\r\n\r\n") {
+            let read = stream.read(&mut buffer).await.unwrap();
+            received.push_str(std::str::from_utf8(&buffer[..read]).unwrap());
+        }
+        assert!(received.contains("text/event-stream"), "{}", received);
+        run.telemetry.tick(3, 100.5);
+        while !received.contains("\"price\":100.5") {
+            let read = stream.read(&mut buffer).await.unwrap();
+            assert!(read > 0, "stream closed: {}", received);
+            received.push_str(std::str::from_utf8(&buffer[..read]).unwrap());
+        }
+        assert!(received.contains("event: tick\ndata: {\"client\":3,"), "{}", received);
+    }
+}
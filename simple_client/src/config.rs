@@ -0,0 +1,346 @@
+use crate::error::Error;
+use crate::price::Arithmetic;
+use clap::ArgMatches;
+use std::fmt;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+const DEFAULT_CONFIG_FILE: &str = "simple.toml";
+const CONFIG_ENV: &str = "SIMPLE_CONFIG";
+
+/// A setting that can come from the config file, an environment variable or
+/// a command line flag, in increasing order of precedence.
+struct Key {
+    name: &'static str,
+    env: &'static str,
+    arg: &'static str,
+}
+
+const WEBSOCKET_URL: Key = Key { name: "websocket_url", env: "SIMPLE_WEBSOCKET_URL", arg: "websocket-url" };
+const CONNECTION_TIMEOUT: Key = Key { name: "connection_timeout", env: "SIMPLE_CONNECTION_TIMEOUT", arg: "connection-timeout" };
+const TIMES: Key = Key { name: "times", env: "SIMPLE_TIMES", arg: "times" };
+const CLIENTS: Key = Key { name: "clients", env: "SIMPLE_CLIENTS", arg: "clients" };
+const OUTPUT_DIR: Key = Key { name: "output_dir", env: "SIMPLE_OUTPUT_DIR", arg: "output-dir" };
+const KEY_FILE: Key = Key { name: "key_file", env: "SIMPLE_KEY_FILE", arg: "key-file" };
+const WINDOW: Key = Key { name: "window", env: "SIMPLE_WINDOW", arg: "window" };
+const SLIDE: Key = Key { name: "slide", env: "SIMPLE_SLIDE", arg: "slide" };
+const METRICS_ADDR: Key = Key { name: "metrics_addr", env: "SIMPLE_METRICS_ADDR", arg: "metrics-addr" };
+const LISTEN_ADDR: Key = Key { name: "listen_addr", env: "SIMPLE_LISTEN_ADDR", arg: "listen" };
+const ARITHMETIC: Key = Key { name: "arithmetic", env: "SIMPLE_ARITHMETIC", arg: "arithmetic" };
+
+const KEYS: [&Key; 11] = [
+    &WEBSOCKET_URL,
+    &CONNECTION_TIMEOUT,
+    &TIMES,
+    &CLIENTS,
+    &OUTPUT_DIR,
+    &KEY_FILE,
+    &WINDOW,
+    &SLIDE,
+    &METRICS_ADDR,
+    &LISTEN_ADDR,
+    &ARITHMETIC,
+];
+
+/// Where an effective setting came from.
+#[derive(Debug, Clone)]
+pub enum Origin {
+    Default,
+    File(PathBuf),
+    Env(&'static str),
+    Cli(&'static str),
+}
+
+impl fmt::Display for Origin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Origin::Default => write!(f, "default"),
+            Origin::File(path) => write!(f, "config file {}", path.display()),
+            Origin::Env(name) => write!(f, "env {}", name),
+            Origin::Cli(arg) => write!(f, "flag --{}", arg),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Setting<T> {
+    pub value: T,
+    pub origin: Origin,
+}
+
+/// The effective settings after layering defaults, config file, environment
+/// and command line.
+#[derive(Debug)]
+pub struct Settings {
+    pub config_file: Option<PathBuf>,
+    pub websocket_url: Setting<String>,
+    pub connection_timeout: Setting<u64>,
+    pub times: Setting<u64>,
+    pub clients: Setting<usize>,
+    pub output_dir: Setting<PathBuf>,
+    pub key_file: Setting<Option<PathBuf>>,
+    pub window: Setting<u64>,
+    pub slide: Setting<Option<u64>>,
+    pub metrics_addr: Setting<Option<SocketAddr>>,
+    pub listen_addr: Setting<SocketAddr>,
+    pub arithmetic: Setting<Arithmetic>,
+}
+
+impl Settings {
+    pub fn connection_timeout(&self) -> Duration {
+        Duration::from_secs(self.connection_timeout.value)
+    }
+
+    pub fn window(&self) -> Duration {
+        Duration::from_secs(self.window.value)
+    }
+
+    pub fn slide(&self) -> Option<Duration> {
+        self.slide.value.map(Duration::from_secs)
+    }
+
+    /// Prints the effective settings as TOML, noting where each came from.
+    pub fn print(&self) {
+        match &self.config_file {
+            Some(path) => println!("# effective settings, config file: {}", path.display()),
+            None => println!("# effective settings, no config file"),
+        }
+        print_line(WEBSOCKET_URL.name, Some(quote(&self.websocket_url.value)), &self.websocket_url.origin);
+        print_line(CONNECTION_TIMEOUT.name, Some(self.connection_timeout.value.to_string()), &self.connection_timeout.origin);
+        print_line(TIMES.name, Some(self.times.value.to_string()), &self.times.origin);
+        print_line(CLIENTS.name, Some(self.clients.value.to_string()), &self.clients.origin);
+        print_line(OUTPUT_DIR.name, Some(quote(&self.output_dir.value.display().to_string())), &self.output_dir.origin);
+        print_line(KEY_FILE.name, self.key_file.value.as_ref().map(|path| quote(&path.display().to_string())), &self.key_file.origin);
+        print_line(WINDOW.name, Some(self.window.value.to_string()), &self.window.origin);
+        print_line(SLIDE.name, self.slide.value.map(|slide| slide.to_string()), &self.slide.origin);
+        print_line(METRICS_ADDR.name, self.metrics_addr.value.map(|addr| quote(&addr.to_string())), &self.metrics_addr.origin);
+        print_line(LISTEN_ADDR.name, Some(quote(&self.listen_addr.value.to_string())), &self.listen_addr.origin);
+        print_line(ARITHMETIC.name, Some(quote(&self.arithmetic.value.to_string())), &self.arithmetic.origin);
+    }
+}
+
+fn quote(value: &str) -> String {
+    toml::Value::String(value.to_string()).to_string()
+}
+
+fn print_line(name: &str, value: Option<String>, origin: &Origin) {
+    match value {
+        Some(value) => println!("{:<40} # {}", format!("{} = {}", name, value), origin),
+        None => println!("{:<40} # {}", format!("# {} is not set", name), origin),
+    }
+}
+
+struct Raw {
+    value: String,
+    origin: Origin,
+}
+
+struct Layers<'a> {
+    matches: &'a ArgMatches,
+    env: &'a dyn Fn(&str) -> Option<String>,
+    file: Option<(PathBuf, toml::Table)>,
+}
+
+impl Layers<'_> {
+    fn lookup(&self, key: &Key) -> Result<Option<Raw>, String> {
+        if let Some(value) = self.matches.get_one::<String>(key.arg) {
+            return Ok(Some(Raw { value: value.clone(), origin: Origin::Cli(key.arg) }));
+        }
+        if let Some(value) = (self.env)(key.env) {
+            return Ok(Some(Raw { value, origin: Origin::Env(key.env) }));
+        }
+        let Some((path, table)) = &self.file else {
+            return Ok(None);
+        };
+        let value = match table.get(key.name) {
+            None => return Ok(None),
+            Some(toml::Value::String(value)) => value.clone(),
+            Some(toml::Value::Integer(value)) => value.to_string(),
+            Some(other) => {
+                return Err(format!(
+                    "{} in {} must be a string or an integer, found {}",
+                    key.name,
+                    path.display(),
+                    other.type_str()
+                ));
+            }
+        };
+        Ok(Some(Raw { value, origin: Origin::File(path.clone()) }))
+    }
+
+    fn get<T>(&self, key: &Key, default: T, parse: impl Fn(&str) -> Result<T, String>) -> Result<Setting<T>, String> {
+        match self.lookup(key)? {
+            Some(raw) => parse(&raw.value)
+                .map(|value| Setting { value, origin: raw.origin.clone() })
+                .map_err(|e| format!("invalid {} '{}' (from {}): {}", key.name, raw.value, raw.origin, e)),
+            None => Ok(Setting { value: default, origin: Origin::Default }),
+        }
+    }
+
+    fn get_optional<T>(&self, key: &Key, parse: impl Fn(&str) -> Result<T, String>) -> Result<Setting<Option<T>>, String> {
+        self.get(key, None, |value| parse(value).map(Some))
+    }
+}
+
+fn positive(value: &str) -> Result<u64, String> {
+    match value.parse::<u64>() {
+        Ok(number) if number > 0 => Ok(number),
+        _ => Err("expected a positive whole number".to_string()),
+    }
+}
+
+fn socket_addr(value: &str) -> Result<SocketAddr, String> {
+    value
+        .parse::<SocketAddr>()
+        .map_err(|e| format!("{}, expected host:port such as 127.0.0.1:9898", e))
+}
+
+fn websocket_url(value: &str) -> Result<String, String> {
+    if value.starts_with("ws://") || value.starts_with("wss://") {
+        Ok(value.to_string())
+    } else {
+        Err("expected a ws:// or wss:// URL".to_string())
+    }
+}
+
+/// Reads the config file named by `--config` or `SIMPLE_CONFIG`, falling back
+/// to `simple.toml` in the working directory when it exists.
+fn load_file(matches: &ArgMatches, env: &dyn Fn(&str) -> Option<String>) -> Result<Option<(PathBuf, toml::Table)>, String> {
+    let explicit = matches.get_one::<String>("config").cloned().or_else(|| env(CONFIG_ENV));
+    let path = match explicit {
+        Some(path) => PathBuf::from(path),
+        None if Path::new(DEFAULT_CONFIG_FILE).exists() => PathBuf::from(DEFAULT_CONFIG_FILE),
+        None => return Ok(None),
+    };
+    let content = fs::read_to_string(&path).map_err(|e| format!("cannot read config file {}: {}", path.display(), e))?;
+    let table: toml::Table = toml::from_str(&content).map_err(|e| format!("config file {} is not valid TOML: {}", path.display(), e))?;
+    if let Some(unknown) = table.keys().find(|name| !KEYS.iter().any(|key| key.name == name.as_str())) {
+        let known: Vec<&str> = KEYS.iter().map(|key| key.name).collect();
+        return Err(format!(
+            "unknown setting '{}' in {}, expected one of: {}",
+            unknown,
+            path.display(),
+            known.join(", ")
+        ));
+    }
+    Ok(Some((path, table)))
+}
+
+pub fn load(matches: &ArgMatches) -> Result<Settings, Error> {
+    load_layers(matches, &|name| std::env::var(name).ok()).map_err(Error::Config)
+}
+
+fn load_layers(matches: &ArgMatches, env: &dyn Fn(&str) -> Option<String>) -> Result<Settings, String> {
+    let layers = Layers { matches, env, file: load_file(matches, env)? };
+    let settings = Settings {
+        config_file: layers.file.as_ref().map(|(path, _)| path.clone()),
+        websocket_url: layers.get(&WEBSOCKET_URL, "wss://stream.binance.com:443/ws/btcusdt@miniTicker".to_string(), websocket_url)?,
+        connection_timeout: layers.get(&CONNECTION_TIMEOUT, 5, positive)?,
+        times: layers.get(&TIMES, 10, positive)?,
+        clients: layers.get(&CLIENTS, 5, |value| positive(value).map(|clients| clients as usize))?,
+        output_dir: layers.get(&OUTPUT_DIR, PathBuf::from(crate::file::DEFAULT_DIR_NAME), |value| Ok(PathBuf::from(value)))?,
+        key_file: layers.get_optional(&KEY_FILE, |value| Ok(PathBuf::from(value)))?,
+        window: layers.get(&WINDOW, 60, positive)?,
+        slide: layers.get_optional(&SLIDE, positive)?,
+        metrics_addr: layers.get_optional(&METRICS_ADDR, socket_addr)?,
+        listen_addr: layers.get(&LISTEN_ADDR, SocketAddr::from(([127, 0, 0, 1], 8088)), socket_addr)?,
+        arithmetic: layers.get(&ARITHMETIC, Arithmetic::Float, Arithmetic::parse)?,
+    };
+    if let Some(slide) = settings.slide.value
+        && slide > settings.window.value
+    {
+        return Err(format!(
+            "slide ({}s, from {}) must not be longer than window ({}s, from {})",
+            slide, settings.slide.origin, settings.window.value, settings.window.origin
+        ));
+    }
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::{Arg, Command};
+
+    /// The flags `load` reads, as declared by the real command line.
+    fn matches(args: &[&str]) -> ArgMatches {
+        let command = KEYS
+            .iter()
+            .fold(Command::new("simple").arg(Arg::new("config").long("config")), |command, key| {
+                command.arg(Arg::new(key.arg).long(key.arg))
+            });
+        command.try_get_matches_from(std::iter::once("simple").chain(args.iter().copied())).unwrap()
+    }
+
+    fn config_file(name: &str, content: &str) -> String {
+        let dir = std::env::temp_dir().join(format!("simple-config-{}-{}", name, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("simple.toml");
+        fs::write(&path, content).unwrap();
+        path.display().to_string()
+    }
+
+    fn load_with(args: &[&str], env: &[(&str, &str)]) -> Result<Settings, String> {
+        let env: Vec<(String, String)> = env.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+        let lookup = move |name: &str| env.iter().find(|(key, _)| key == name).map(|(_, value)| value.clone());
+        load_layers(&matches(args), &lookup)
+    }
+
+    #[test]
+    fn test_flags_beat_env_beat_file_beat_defaults() {
+        let path = config_file("layers", "times = 3\nclients = 7\nwindow = 30\narithmetic = \"exact\"\n");
+        let settings = load_with(
+            &["--config", &path, "--times", "5"],
+            &[("SIMPLE_TIMES", "4"), ("SIMPLE_CLIENTS", "8")],
+        )
+        .unwrap();
+        assert_eq!(settings.config_file, Some(PathBuf::from(&path)));
+        assert_eq!(settings.times.value, 5);
+        assert!(matches!(settings.times.origin, Origin::Cli("times")));
+        assert_eq!(settings.clients.value, 8);
+        assert!(matches!(settings.clients.origin, Origin::Env("SIMPLE_CLIENTS")));
+        assert_eq!(settings.window.value, 30);
+        assert!(matches!(settings.window.origin, Origin::File(_)));
+        assert_eq!(settings.arithmetic.value, Arithmetic::Exact);
+        assert_eq!(settings.connection_timeout.value, 5);
+        assert!(matches!(settings.connection_timeout.origin, Origin::Default));
+        assert!(settings.slide.value.is_none());
+
+        // The config file itself can come from the environment.
+        let settings = load_with(&[], &[("SIMPLE_CONFIG", &path)]).unwrap();
+        assert_eq!(settings.times.value, 3);
+    }
+
+    #[test]
+    fn test_unknown_settings_are_rejected() {
+        let path = config_file("unknown", "times = 3\ntimez = 4\n");
+        let error = load_with(&["--config", &path], &[]).unwrap_err();
+        assert!(error.contains("unknown setting 'timez'"), "{}", error);
+        assert!(error.contains("websocket_url"), "{}", error);
+    }
+
+    #[test]
+    fn test_invalid_values_name_the_setting_and_where_it_came_from() {
+        let path = config_file("invalid", "times = 0\n");
+        let error = load_with(&["--config", &path], &[]).unwrap_err();
+        assert!(error.starts_with("invalid times '0' (from config file"), "{}", error);
+
+        let path = config_file("type", "times = true\n");
+        let error = load_with(&["--config", &path], &[]).unwrap_err();
+        assert!(error.contains("must be a string or an integer, found boolean"), "{}", error);
+
+        let error = load_with(&[], &[("SIMPLE_WEBSOCKET_URL", "https://example.com")]).unwrap_err();
+        assert!(error.contains("(from env SIMPLE_WEBSOCKET_URL)"), "{}", error);
+
+        let error = load_with(&["--listen", "localhost"], &[]).unwrap_err();
+        assert!(error.contains("(from flag --listen)"), "{}", error);
+
+        let error = load_with(&["--arithmetic", "fixed"], &[]).unwrap_err();
+        assert!(error.starts_with("invalid arithmetic 'fixed'"), "{}", error);
+
+        let error = load_with(&["--window", "10", "--slide", "20"], &[]).unwrap_err();
+        assert!(error.starts_with("slide (20s, from flag --slide) must not be longer than window (10s"), "{}", error);
+    }
+}
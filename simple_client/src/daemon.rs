@@ -0,0 +1,252 @@
+use crate::error::Error;
+use crate::file::{self, CacheRecord, PriceData, Source};
+use crate::price::Price;
+use crate::{Feed, Run};
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
+
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Buffers ticks and cuts them into windows. Tumbling windows are emptied on
+/// every emit; sliding windows keep the ticks still inside the window length.
+#[derive(Debug)]
+pub struct Windower {
+    length: Duration,
+    sliding: bool,
+    ticks: VecDeque<(Instant, Price)>,
+    fresh: bool,
+}
+
+impl Windower {
+    pub fn new(length: Duration, sliding: bool) -> Self {
+        Self {
+            length,
+            sliding,
+            ticks: VecDeque::new(),
+            fresh: false,
+        }
+    }
+
+    pub fn push(&mut self, at: Instant, price: Price) {
+        self.ticks.push_back((at, price));
+        self.fresh = true;
+    }
+
+    /// Returns the window ending at `now`, or `None` if it holds no ticks.
+    pub fn emit(&mut self, now: Instant) -> Option<PriceData> {
+        if self.sliding {
+            while self.ticks.front().is_some_and(|(at, _)| now.duration_since(*at) > self.length) {
+                self.ticks.pop_front();
+            }
+        }
+        self.fresh = false;
+        let prices: Vec<Price> = if self.sliding {
+            self.ticks.iter().map(|(_, price)| *price).collect()
+        } else {
+            self.ticks.drain(..).map(|(_, price)| price).collect()
+        };
+        let average = Price::mean(&prices)?;
+        Some(PriceData::new(prices, average))
+    }
+
+    /// Whether ticks arrived since the last emit, i.e. a flush would not just
+    /// repeat the previous window.
+    pub fn has_fresh_ticks(&self) -> bool {
+        self.fresh
+    }
+}
+
+/// Keeps the stream open and writes one aggregate per window to the history
+/// until SIGINT/SIGTERM (or the end of a replay), then flushes the partial
+/// window.
+pub async fn run_daemon(run: Arc<Run>, length: Duration, slide: Option<Duration>, shutdown: Arc<Notify>) -> Result<String, Error> {
+    let sliding = slide.is_some();
+    let mut windower = Windower::new(length, sliding);
+    let mut interval = tokio::time::interval_at(Instant::now() + slide.unwrap_or(length), slide.unwrap_or(length));
+    let (tx, mut rx) = mpsc::channel::<Price>(1024);
+    let dropped = Arc::new(AtomicUsize::new(0));
+    let producer = tokio::spawn(produce(run.clone(), tx, dropped.clone()));
+    let mut windows = 0;
+    run.telemetry.log(format!(
+        "Daemon started: {} windows of {}s{}.",
+        if sliding { "sliding" } else { "tumbling" },
+        length.as_secs(),
+        slide.map_or(String::new(), |slide| format!(" every {}s", slide.as_secs()))
+    ));
+
+    let stream_ended = loop {
+        tokio::select! {
+            price = rx.recv() => match price {
+                Some(price) => windower.push(Instant::now(), price),
+                None => break true,
+            },
+            _ = interval.tick() => {
+                report_dropped(&run, &dropped);
+                match write_window(&run, &mut windower, length, false) {
+                    Ok(true) => windows += 1,
+                    Ok(false) => {}
+                    Err(e) => {
+                        producer.abort();
+                        return Err(e);
+                    }
+                }
+            }
+            _ = shutdown.notified() => break false,
+        }
+    };
+    producer.abort();
+    report_dropped(&run, &dropped);
+    if windower.has_fresh_ticks() && write_window(&run, &mut windower, length, true)? {
+        windows += 1;
+    }
+    let reason = if stream_ended { "Stream ended" } else { "Shutdown requested" };
+    Ok(format!("{}, daemon stopped after {} window(s).", reason, windows))
+}
+
+fn report_dropped(run: &Run, dropped: &AtomicUsize) {
+    let count = dropped.swap(0, Ordering::Relaxed);
+    if count > 0 {
+        run.telemetry.log(format!("Dropped {} tick(s): the daemon fell behind the stream.", count));
+    }
+}
+
+fn write_window(run: &Run, windower: &mut Windower, length: Duration, partial: bool) -> Result<bool, Error> {
+    let Some(price_data) = windower.emit(Instant::now()) else {
+        run.telemetry.log("No ticks in this window, nothing written.".to_string());
+        return Ok(false);
+    };
+    run.telemetry.log(format!(
+        "{} window: average {:.2} over {} ticks.",
+        if partial { "Partial" } else { "Closed" },
+        price_data.average,
+        price_data.prices.len()
+    ));
+    run.telemetry.aggregate(price_data.average.to_f64());
+    let source = Source::Window { seconds: length.as_secs(), partial };
+    file::write_data_to_file(&run.output_dir, &CacheRecord::now(source, price_data), run.key.as_deref())?;
+    Ok(true)
+}
+
+/// Feeds prices to the daemon, reconnecting with backoff whenever the live
+/// stream fails. A replay simply ends when its frames run out. Reconnects
+/// are counted by `stream_live_prices`, which notices the stream dropping.
+async fn produce(run: Arc<Run>, tx: mpsc::Sender<Price>, dropped: Arc<AtomicUsize>) {
+    let mut on_price = forward(&tx, &dropped);
+    match &run.feed {
+        Feed::Replay { frames, speed } => {
+            if let Err(e) = crate::replay_prices(&run, frames, 0, *speed, &mut on_price).await {
+                run.telemetry.log(format!("Replay failed: {}", e));
+            }
+        }
+        Feed::Live { recorder } => {
+            let mut backoff = Duration::from_secs(1);
+            loop {
+                let started = Instant::now();
+                if let Err(e) = crate::stream_live_prices(&run, 0, recorder.as_deref(), None, &mut on_price).await {
+                    run.telemetry.log(format!("Stream failed: {}, retrying in {}s.", e, backoff.as_secs()));
+                }
+                if started.elapsed() > MAX_BACKOFF {
+                    backoff = Duration::from_secs(1);
+                }
+                tokio::time::sleep(backoff).await;
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+/// Hands ticks to the daemon without waiting, since the stream callbacks are
+/// synchronous. Ticks that find the buffer full are counted in `dropped`
+/// so the daemon can report them.
+fn forward<'a>(tx: &'a mpsc::Sender<Price>, dropped: &'a AtomicUsize) -> impl FnMut(Price) + 'a {
+    move |price| {
+        if tx.try_send(price).is_err() {
+            dropped.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tumbling_windows_do_not_overlap() {
+        let start = Instant::now();
+        let mut windower = Windower::new(Duration::from_secs(10), false);
+        windower.push(start, 1.0.into());
+        windower.push(start + Duration::from_secs(1), 3.0.into());
+        assert_eq!(windower.emit(start + Duration::from_secs(10)).unwrap().average, Price::Float(2.0));
+        assert!(windower.emit(start + Duration::from_secs(20)).is_none());
+    }
+
+    #[test]
+    fn test_sliding_windows_drop_expired_ticks() {
+        let start = Instant::now();
+        let mut windower = Windower::new(Duration::from_secs(10), true);
+        windower.push(start, 1.0.into());
+        windower.push(start + Duration::from_secs(8), 3.0.into());
+        assert_eq!(windower.emit(start + Duration::from_secs(9)).unwrap().average, Price::Float(2.0));
+        assert!(!windower.has_fresh_ticks());
+        let window = windower.emit(start + Duration::from_secs(15)).unwrap();
+        assert_eq!(window.prices, vec![Price::Float(3.0)]);
+    }
+
+    #[tokio::test]
+    async fn test_a_dropped_stream_counts_as_one_reconnect() {
+        use crate::price::Arithmetic;
+        use crate::telemetry::Telemetry;
+        use futures_util::SinkExt;
+        use tokio_tungstenite::tungstenite::Message;
+
+        // The exchange sends one tick, hangs up and is gone for good.
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            drop(listener);
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            socket.send(Message::text(r#"{"s":"BTCUSDT","c":"97000"}"#)).await.unwrap();
+            socket.close(None).await.unwrap();
+        });
+        let output_dir = std::env::temp_dir().join(format!("simple-daemon-reconnect-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&output_dir);
+        let run = Arc::new(Run {
+            times: 1,
+            websocket_url: format!("ws://{}", addr),
+            connection_timeout: Duration::from_secs(1),
+            arithmetic: Arithmetic::Float,
+            feed: Feed::Live { recorder: None },
+            output_dir,
+            key: None,
+            telemetry: Arc::new(Telemetry::new(false)),
+        });
+        let shutdown = Arc::new(Notify::new());
+        let daemon = tokio::spawn(run_daemon(run.clone(), Duration::from_secs(60), None, shutdown.clone()));
+
+        // Long enough for the tick, the hang-up and a retry after backoff.
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        shutdown.notify_one();
+        daemon.await.unwrap().unwrap();
+        let (_, stats) = run.telemetry.clients().remove(0);
+        assert_eq!((stats.ticks, stats.reconnects), (1, 1));
+    }
+
+    #[test]
+    fn test_ticks_that_find_the_buffer_full_are_counted() {
+        let (tx, mut rx) = mpsc::channel::<Price>(2);
+        let dropped = AtomicUsize::new(0);
+        let mut on_price = forward(&tx, &dropped);
+        for price in [1.0, 2.0, 3.0, 4.0] {
+            on_price(price.into());
+        }
+        assert_eq!(dropped.load(Ordering::Relaxed), 2);
+        assert_eq!(rx.try_recv().unwrap(), Price::Float(1.0));
+        assert_eq!(rx.try_recv().unwrap(), Price::Float(2.0));
+        assert!(rx.try_recv().is_err());
+    }
+}
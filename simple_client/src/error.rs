@@ -0,0 +1,94 @@
+use crate::integrity::IntegrityError;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::time::Duration;
+
+#[derive(Debug, thiserror::Error)]
+pub enum Error {
+    /// Bad flags, environment or config file.
+    #[error("{0}")]
+    Config(String),
+
+    #[error("cannot connect to {url}: {source}")]
+    Connection {
+        url: String,
+        source: Box<tokio_tungstenite::tungstenite::Error>,
+    },
+
+    #[error("connecting to {url} timed out after {}s", timeout.as_secs())]
+    ConnectionTimeout { url: String, timeout: Duration },
+
+    #[error("WebSocket failed: {0}")]
+    WebSocket(Box<tokio_tungstenite::tungstenite::Error>),
+
+    /// Input that could not be understood, such as a recording or a query.
+    #[error("{0}")]
+    Parse(String),
+
+    #[error("{}: {source}", path.display())]
+    Io { path: PathBuf, source: std::io::Error },
+
+    #[error("cannot serialize record: {0}")]
+    Serialize(#[from] serde_json::Error),
+
+    #[error(transparent)]
+    Integrity(#[from] IntegrityError),
+
+    /// Nothing to average or nothing to show.
+    #[error("{0}")]
+    EmptyData(String),
+
+    /// The run was stopped from the dashboard before it finished.
+    #[error("interrupted")]
+    Interrupted,
+}
+
+impl From<tokio_tungstenite::tungstenite::Error> for Error {
+    fn from(e: tokio_tungstenite::tungstenite::Error) -> Self {
+        Error::WebSocket(Box::new(e))
+    }
+}
+
+impl Error {
+    pub fn io(path: impl Into<PathBuf>, source: std::io::Error) -> Self {
+        Error::Io { path: path.into(), source }
+    }
+
+    /// Exit status for each category, so scripts can tell a missing network
+    /// from a broken cache file.
+    pub fn exit_code(&self) -> ExitCode {
+        ExitCode::from(match self {
+            Error::Config(_) => 2,
+            Error::Connection { .. } | Error::ConnectionTimeout { .. } | Error::WebSocket(_) => 3,
+            Error::Parse(_) | Error::Integrity(_) => 4,
+            Error::Io { .. } | Error::Serialize(_) => 5,
+            Error::EmptyData(_) => 6,
+            Error::Interrupted => 130,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exit_code_per_category() {
+        let io = || std::io::Error::other("disk full");
+        let serialize = serde_json::from_str::<u8>("x").unwrap_err();
+        let cases = [
+            (Error::Config("bad flag".to_string()), 2),
+            (Error::ConnectionTimeout { url: "wss://example".to_string(), timeout: Duration::from_secs(5) }, 3),
+            (Error::WebSocket(Box::new(tokio_tungstenite::tungstenite::Error::ConnectionClosed)), 3),
+            (Error::Parse("bad query".to_string()), 4),
+            (Error::Integrity(IntegrityError::MissingHmac), 4),
+            (Error::io("btc_history.jsonl", io()), 5),
+            (Error::Serialize(serialize), 5),
+            (Error::EmptyData("no records".to_string()), 6),
+            (Error::Interrupted, 130),
+        ];
+        for (error, code) in cases {
+            assert_eq!(error.exit_code(), ExitCode::from(code), "{}", error);
+        }
+    }
+}
@@ -1,17 +1,32 @@
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use std::fs;
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use crate::error::Error;
+use crate::integrity::{self, IntegrityError, Trust};
+use crate::price::Price;
 
-const DIR_NAME: &str = "simple_client";
-const FILE_NAME: &str = "btc_data.json";
+pub const DEFAULT_DIR_NAME: &str = "simple_client";
+const HISTORY_FILE_NAME: &str = "btc_history.jsonl";
+const LEGACY_FILE_NAME: &str = "btc_data.json";
+const CLIENT_FILE_PREFIX: &str = "btc_price.";
+const CLIENT_FILE_SUFFIX: &str = ".json";
 
-#[derive(Serialize, Deserialize, Debug)]
+/// Tells apart the temp files of concurrent [`write_atomic`] calls within
+/// one process; the pid tells apart those of different processes.
+static TMP_FILE_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct PriceData {
-    prices: Vec<f64>,
-    pub average: f64,
+    pub prices: Vec<Price>,
+    pub average: Price,
 }
 
 impl PriceData {
-    pub fn new(prices: Vec<f64>, average: f64) -> Self {
+    pub fn new(prices: Vec<Price>, average: Price) -> Self {
         Self {
             prices,
             average,
@@ -19,26 +34,358 @@ impl PriceData {
     }
 }
 
-pub fn write_data_to_file(price_data: &PriceData) {
-    let path_exists = fs::exists(DIR_NAME).unwrap();
-    if !path_exists {
-        fs::create_dir(DIR_NAME).expect("Error in creating directory");
+/// Which run produced a record.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum Source {
+    /// A single-client `cache` run.
+    #[default]
+    Single,
+    /// One client of a `multi` run, read from its per-client file.
+    Client(usize),
+    /// The aggregator's result of a `multi` run.
+    Aggregate { clients: usize },
+    /// One window of a `daemon` run; `partial` when flushed on shutdown.
+    Window { seconds: u64, partial: bool },
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Single => write!(f, "single"),
+            Source::Client(id) => write!(f, "client {}", id),
+            Source::Aggregate { clients } => write!(f, "aggregate/{}", clients),
+            Source::Window { seconds, partial: false } => write!(f, "window/{}s", seconds),
+            Source::Window { seconds, partial: true } => write!(f, "window/{}s*", seconds),
+        }
     }
-    let file_path = format!("{}/{}", DIR_NAME, FILE_NAME);
-    let json_string = serde_json::to_string(price_data).expect("Error in serializing");
-    fs::write(file_path, json_string).expect("Error in writing file");
 }
-pub fn get_data_from_file() {
-    let file_path = format!("{}/{}", DIR_NAME, FILE_NAME);
-    let path_exists = fs::exists(&file_path).unwrap();
-    if path_exists {
-        if let Ok(json_string) = fs::read_to_string(file_path) {
-            let price_data: PriceData = serde_json::from_str(json_string.as_str()).expect("Error in deserializing");
-            println!("Read complete: {:#?}", price_data);
-        }else {
-            println!("No data found, please run the cache mode first.");
-        }   
-    } else {
-        println!("No data found, please run the cache mode first.");
+
+/// One cache run as stored in the history file, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct CacheRecord {
+    pub timestamp: DateTime<Utc>,
+    #[serde(default)]
+    pub source: Source,
+    #[serde(flatten)]
+    pub data: PriceData,
+}
+
+impl CacheRecord {
+    pub fn now(source: Source, data: PriceData) -> Self {
+        Self {
+            timestamp: Utc::now(),
+            source,
+            data,
+        }
     }
-}
\ No newline at end of file
+}
+
+/// Filters applied to the history in read mode.
+#[derive(Debug, Default)]
+pub struct Query {
+    pub since: Option<DateTime<Utc>>,
+    pub until: Option<DateTime<Utc>>,
+    pub last: Option<usize>,
+}
+
+impl Query {
+    pub fn apply(&self, records: Vec<CacheRecord>) -> Vec<CacheRecord> {
+        let mut records: Vec<CacheRecord> = records
+            .into_iter()
+            .filter(|record| self.since.is_none_or(|since| record.timestamp >= since))
+            .filter(|record| self.until.is_none_or(|until| record.timestamp <= until))
+            .collect();
+        if let Some(last) = self.last {
+            let skip = records.len().saturating_sub(last);
+            records.drain(..skip);
+        }
+        records
+    }
+}
+
+fn ensure_dir(dir: &Path) -> Result<(), Error> {
+    fs::create_dir_all(dir).map_err(|e| Error::io(dir, e))
+}
+
+/// Replaces `path` with `content` via a temp file and rename in the same
+/// directory, so a crash leaves either the old file or the new one, never a
+/// truncated mix. Every call gets its own temp file, so concurrent writers
+/// cannot clobber each other's; the last rename wins.
+fn write_atomic(path: &Path, content: &[u8]) -> Result<(), Error> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(format!(
+        ".{}.{}.tmp",
+        std::process::id(),
+        TMP_FILE_COUNTER.fetch_add(1, Ordering::Relaxed)
+    ));
+    let tmp_path = path.with_file_name(tmp_name);
+    let result = (|| {
+        let mut file = fs::File::create(&tmp_path)?;
+        file.write_all(content)?;
+        file.sync_all()?;
+        fs::rename(&tmp_path, path)
+    })();
+    if let Err(e) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(Error::io(path, e));
+    }
+    Ok(())
+}
+
+/// Appends the run to the history file instead of overwriting previous runs.
+/// Each record goes out as a single write to a file opened in append mode,
+/// so concurrent runs keep each other's records. A crash mid-write can only
+/// leave a torn last line, which the next append terminates and
+/// `parse_history` skips.
+pub fn write_data_to_file(dir: &Path, record: &CacheRecord, key: Option<&[u8]>) -> Result<(), Error> {
+    ensure_dir(dir)?;
+    let file_path = dir.join(HISTORY_FILE_NAME);
+    let mut line = integrity::seal(record, key)?;
+    line.push('\n');
+    let result = (|| {
+        let mut file = OpenOptions::new().create(true).read(true).append(true).open(&file_path)?;
+        if ends_mid_line(&mut file)? {
+            line.insert(0, '\n');
+        }
+        file.write_all(line.as_bytes())?;
+        file.sync_data()
+    })();
+    result.map_err(|e| Error::io(&file_path, e))
+}
+
+/// Whether the last line of `file` is missing its newline.
+fn ends_mid_line(file: &mut fs::File) -> io::Result<bool> {
+    if file.metadata()?.len() == 0 {
+        return Ok(false);
+    }
+    let mut last = [0u8];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] != b'\n')
+}
+
+/// Writes one client's result of a `multi` run to `btc_price.{id}.json`,
+/// replacing the file from the previous run.
+pub fn write_client_data_to_file(dir: &Path, id: usize, record: &CacheRecord, key: Option<&[u8]>) -> Result<(), Error> {
+    ensure_dir(dir)?;
+    let file_path = dir.join(format!("{}{}{}", CLIENT_FILE_PREFIX, id, CLIENT_FILE_SUFFIX));
+    write_atomic(&file_path, integrity::seal(record, key)?.as_bytes())
+}
+
+/// Reads every run from the history file, the single-run `btc_data.json`
+/// written by older versions, and the per-client files of the last `multi`
+/// run, oldest first. Records that fail to parse or fail the integrity check
+/// are reported and skipped so one bad line does not hide the rest.
+pub fn get_data_from_file(dir: &Path, key: Option<&[u8]>) -> Result<Vec<CacheRecord>, Error> {
+    let mut legacy = 0;
+    let history_path = dir.join(HISTORY_FILE_NAME);
+    let mut records = match fs::read_to_string(&history_path) {
+        Ok(content) => parse_history(&content, key, &mut legacy),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(Error::io(history_path, e)),
+    };
+    if let Some(record) = read_record_file(&dir.join(LEGACY_FILE_NAME), key, &mut legacy) {
+        records.push(record);
+    }
+    records.extend(get_client_data_from_files(dir, key, &mut legacy));
+    if legacy > 0 {
+        eprintln!("Note: {} record(s) predate checksums and could not be verified.", legacy);
+    }
+    records.sort_by_key(|record| record.timestamp);
+    Ok(records)
+}
+
+fn get_client_data_from_files(dir: &Path, key: Option<&[u8]>, legacy: &mut usize) -> Vec<CacheRecord> {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return Vec::new();
+    };
+    let mut records = Vec::new();
+    for entry in entries.flatten() {
+        let file_name = entry.file_name();
+        let Some(id) = file_name
+            .to_str()
+            .and_then(|name| name.strip_prefix(CLIENT_FILE_PREFIX))
+            .and_then(|name| name.strip_suffix(CLIENT_FILE_SUFFIX))
+            .and_then(|id| id.parse::<usize>().ok())
+        else {
+            continue;
+        };
+        if let Some(mut record) = read_record_file(&entry.path(), key, legacy) {
+            record.source = Source::Client(id);
+            records.push(record);
+        }
+    }
+    records
+}
+
+/// Reads a single-record file. Files written before records carried a
+/// timestamp hold a bare `PriceData`; those get the file's modification time
+/// and are only accepted when no key is required.
+fn read_record_file(path: &Path, key: Option<&[u8]>, legacy: &mut usize) -> Option<CacheRecord> {
+    let json_string = fs::read_to_string(path).ok()?;
+    match integrity::open(&json_string, key, true) {
+        Ok((record, trust)) => {
+            if trust == Trust::Legacy {
+                *legacy += 1;
+            }
+            return Some(record);
+        }
+        Err(IntegrityError::Malformed(_)) => {}
+        Err(e) => {
+            eprintln!("Rejected {}: {}", path.display(), e);
+            return None;
+        }
+    }
+    match serde_json::from_str::<PriceData>(&json_string) {
+        Ok(_) if key.is_some() => {
+            eprintln!("Rejected {}: {}", path.display(), IntegrityError::MissingHmac);
+            None
+        }
+        Ok(data) => {
+            *legacy += 1;
+            let timestamp = fs::metadata(path)
+                .and_then(|metadata| metadata.modified())
+                .map(DateTime::<Utc>::from)
+                .unwrap_or_default();
+            Some(CacheRecord {
+                timestamp,
+                source: Source::Single,
+                data,
+            })
+        }
+        Err(e) => {
+            eprintln!("Skipping {}: {}", path.display(), e);
+            None
+        }
+    }
+}
+
+/// Parses the history file. Unsealed lines are only trusted in a file that
+/// has no sealed ones, see [`integrity::open`].
+fn parse_history(content: &str, key: Option<&[u8]>, legacy: &mut usize) -> Vec<CacheRecord> {
+    let allow_legacy = !content.lines().any(integrity::is_sealed);
+    let mut records = Vec::new();
+    for (line_no, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        match integrity::open(line, key, allow_legacy) {
+            Ok((record, trust)) => {
+                if trust == Trust::Legacy {
+                    *legacy += 1;
+                }
+                records.push(record);
+            }
+            Err(e) => eprintln!("Rejected line {} of history: {}", line_no + 1, e),
+        }
+    }
+    records
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn record(hour: u32, average: f64) -> CacheRecord {
+        CacheRecord {
+            timestamp: Utc.with_ymd_and_hms(2025, 1, 1, hour, 0, 0).unwrap(),
+            source: Source::Single,
+            data: PriceData::new(vec![average.into()], average.into()),
+        }
+    }
+
+    #[test]
+    fn test_query_time_range() {
+        let records = vec![record(1, 1.0), record(2, 2.0), record(3, 3.0)];
+        let query = Query {
+            since: Some(Utc.with_ymd_and_hms(2025, 1, 1, 2, 0, 0).unwrap()),
+            until: Some(Utc.with_ymd_and_hms(2025, 1, 1, 2, 30, 0).unwrap()),
+            last: None,
+        };
+        let result = query.apply(records);
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].data.average, Price::Float(2.0));
+    }
+
+    #[test]
+    fn test_query_last() {
+        let records = vec![record(1, 1.0), record(2, 2.0), record(3, 3.0)];
+        let query = Query { last: Some(2), ..Default::default() };
+        let result = query.apply(records);
+        assert_eq!(result.iter().map(|r| r.data.average.to_f64()).collect::<Vec<_>>(), vec![2.0, 3.0]);
+    }
+
+    fn temp_dir(name: &str) -> std::path::PathBuf {
+        let dir = std::env::temp_dir().join(format!("simple-file-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn test_appends_keep_concurrent_records_and_skip_a_torn_line() {
+        let dir = temp_dir("append");
+        fs::write(dir.join(HISTORY_FILE_NAME), r#"{"timestamp":"2025-01-01T00:00:00Z","pri"#).unwrap();
+        std::thread::scope(|scope| {
+            for hour in 1..=8 {
+                let dir = &dir;
+                scope.spawn(move || write_data_to_file(dir, &record(hour, hour as f64), None).unwrap());
+            }
+        });
+        let content = fs::read_to_string(dir.join(HISTORY_FILE_NAME)).unwrap();
+        assert_eq!(content.lines().count(), 9);
+        let averages: Vec<f64> = get_data_from_file(&dir, None)
+            .unwrap()
+            .iter()
+            .map(|record| record.data.average.to_f64())
+            .collect();
+        assert_eq!(averages, vec![1.0, 2.0, 3.0, 4.0, 5.0, 6.0, 7.0, 8.0]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_atomic_write_replaces_the_file_and_leaves_no_temp_files() {
+        let dir = temp_dir("atomic");
+        let path = dir.join("btc_price.1.json");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+
+        // A failed rename keeps the target as it was and cleans up.
+        let blocked = dir.join("btc_price.2.json");
+        fs::create_dir(&blocked).unwrap();
+        fs::write(blocked.join("inside"), "kept").unwrap();
+        assert!(matches!(write_atomic(&blocked, b"new"), Err(Error::Io { .. })));
+        assert_eq!(fs::read_to_string(blocked.join("inside")).unwrap(), "kept");
+        let names: Vec<_> = fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect();
+        assert_eq!(names.len(), 2, "{:?}", names);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_parse_history_skips_bad_lines() {
+        let good = integrity::seal(&record(1, 1.0), None).unwrap();
+        let content = format!("{}\nnot json\n\n{}\n", good, good);
+        assert_eq!(parse_history(&content, None, &mut 0).len(), 2);
+    }
+
+    #[test]
+    fn test_parse_history_rejects_unsealed_lines_among_sealed_ones() {
+        let sealed = integrity::seal(&record(1, 1.0), None).unwrap();
+        let stripped = serde_json::to_string(&record(2, 2.0)).unwrap();
+        let mut legacy = 0;
+        let records = parse_history(&format!("{}\n{}\n", sealed, stripped), None, &mut legacy);
+        assert_eq!((records.len(), legacy), (1, 0));
+        assert_eq!(parse_history(&stripped, None, &mut legacy).len(), 1);
+    }
+
+    #[test]
+    fn test_parse_history_defaults_missing_source() {
+        let content = r#"{"timestamp":"2025-01-01T01:00:00Z","prices":[1.0],"average":1.0}"#;
+        let mut legacy = 0;
+        assert_eq!(parse_history(content, None, &mut legacy)[0].source, Source::Single);
+        assert_eq!(legacy, 1);
+    }
+}
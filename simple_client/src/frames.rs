@@ -0,0 +1,169 @@
+use crate::error::Error;
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+
+/// One raw WebSocket message as it arrived, one JSON object per line.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Frame {
+    pub received_at: DateTime<Utc>,
+    pub client: usize,
+    pub message: String,
+}
+
+/// Appends every received frame to a JSONL file. Shared by all clients of a
+/// run, so writes go through a mutex to keep lines whole.
+pub struct Recorder {
+    file: Mutex<fs::File>,
+}
+
+impl Recorder {
+    pub fn create(path: &Path) -> Result<Self, Error> {
+        let file = fs::File::create(path).map_err(|e| Error::io(path, e))?;
+        Ok(Self {
+            file: Mutex::new(file),
+        })
+    }
+
+    pub fn record(&self, client: usize, message: &str) {
+        let frame = Frame {
+            received_at: Utc::now(),
+            client,
+            message: message.to_string(),
+        };
+        let result = serde_json::to_string(&frame)
+            .map_err(Error::from)
+            .and_then(|json_string| writeln!(self.file.lock().unwrap(), "{}", json_string).map_err(|e| Error::io("recording", e)));
+        if let Err(e) = result {
+            eprintln!("Failed to record frame: {}", e);
+        }
+    }
+}
+
+/// Loads a recording made with `--record`, keeping the original order.
+pub fn load_frames(path: &Path) -> Result<Vec<Frame>, Error> {
+    let content = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+    content
+        .lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(line_no, line)| {
+            serde_json::from_str::<Frame>(line)
+                .map_err(|e| Error::Parse(format!("{} line {}: {}", path.display(), line_no + 1, e)))
+        })
+        .collect()
+}
+
+/// Parses a replay speed such as `10x`, `0.5x` or `2`.
+pub fn parse_speed(value: &str) -> Result<f64, Error> {
+    let number = value.strip_suffix('x').unwrap_or(value);
+    match number.parse::<f64>() {
+        Ok(speed) if speed > 0.0 && speed.is_finite() => Ok(speed),
+        _ => Err(Error::Config(format!("invalid speed '{}', expected a positive factor such as 10x", value))),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::price::Arithmetic;
+    use crate::telemetry::Telemetry;
+    use crate::{Feed, Run};
+    use futures_util::SinkExt;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tokio::net::TcpListener;
+    use tokio_tungstenite::tungstenite::Message;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("simple-frames-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn ticker(price: &str) -> String {
+        format!(r#"{{"s":"BTCUSDT","c":"{}"}}"#, price)
+    }
+
+    /// A WebSocket that sends `messages` at once, then one more message
+    /// after `hold` so that a timed live read gets to check its deadline.
+    async fn exchange(messages: Vec<String>, hold: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let mut socket = tokio_tungstenite::accept_async(stream).await.unwrap();
+            for message in messages {
+                socket.send(Message::text(message)).await.unwrap();
+            }
+            tokio::time::sleep(hold).await;
+            socket.send(Message::text(r#"{"result":null,"id":1}"#)).await.unwrap();
+            tokio::time::sleep(hold).await;
+        });
+        format!("ws://{}", addr)
+    }
+
+    #[tokio::test]
+    async fn test_replaying_a_recording_gives_the_live_averages() {
+        let dir = temp_dir("replay");
+        let recording = dir.join("frames.jsonl");
+        let prices = ["0.1", "0.2", "0.3", "97000.07", "96999.93"];
+        let live = Run {
+            times: 2,
+            websocket_url: exchange(prices.iter().map(|price| ticker(price)).collect(), Duration::from_millis(2500)).await,
+            connection_timeout: Duration::from_secs(5),
+            arithmetic: Arithmetic::Float,
+            feed: Feed::Live { recorder: Some(Arc::new(Recorder::create(&recording).unwrap())) },
+            output_dir: dir.clone(),
+            key: None,
+            telemetry: Arc::new(Telemetry::new(false)),
+        };
+        let live = crate::get_btc_price(&live, 0).await.unwrap();
+        assert_eq!(live.prices.len(), prices.len());
+
+        let frames = load_frames(&recording).unwrap();
+        assert_eq!(frames.len(), prices.len() + 1);
+        let replay = Run::replay(frames, dir);
+        for _ in 0..2 {
+            let replayed = crate::get_btc_price(&replay, 0).await.unwrap();
+            assert_eq!(replayed.prices, live.prices);
+            assert_eq!(replayed.average, live.average);
+        }
+        assert_eq!(replay.telemetry.clients()[0].1.parse_failures, 2);
+    }
+
+    #[test]
+    fn test_parse_speed() {
+        assert_eq!(parse_speed("10x").unwrap(), 10.0);
+        assert_eq!(parse_speed("0.5x").unwrap(), 0.5);
+        assert_eq!(parse_speed("2").unwrap(), 2.0);
+        for invalid in ["", "x", "0x", "-1x", "fast", "infx", "NaN", "10xx"] {
+            assert!(matches!(parse_speed(invalid), Err(Error::Config(_))), "{:?} was accepted", invalid);
+        }
+    }
+
+    #[test]
+    fn test_load_frames_skips_blank_lines_and_names_the_bad_one() {
+        let dir = temp_dir("load");
+        assert!(matches!(load_frames(&dir.join("missing.jsonl")), Err(Error::Io { .. })));
+
+        let path = dir.join("frames.jsonl");
+        let frame = format!(r#"{{"received_at":"2026-10-19T10:00:00Z","client":1,"message":{:?}}}"#, ticker("97000"));
+        fs::write(&path, format!("{}\n\n{}\n", frame, frame)).unwrap();
+        let frames = load_frames(&path).unwrap();
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0].client, 1);
+        assert_eq!(frames[0].message, ticker("97000"));
+
+        fs::write(&path, format!("{}\n{{\"client\":1}}\n", frame)).unwrap();
+        match load_frames(&path) {
+            Err(Error::Parse(message)) => assert!(message.contains("line 2"), "{}", message),
+            other => panic!("expected a parse error, got {:?}", other.map(|frames| frames.len())),
+        }
+    }
+}
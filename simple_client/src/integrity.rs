@@ -0,0 +1,176 @@
+use crate::error::Error;
+use crate::file::CacheRecord;
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fs;
+use std::path::Path;
+
+/// Bumped whenever the stored record layout changes.
+pub const SCHEMA_VERSION: u32 = 1;
+
+type HmacSha256 = Hmac<Sha256>;
+
+/// A cache record as written to disk: the record itself plus a schema version,
+/// a SHA-256 checksum of the record and, when a key file is given, an
+/// HMAC-SHA256 over the same bytes.
+#[derive(Serialize, Deserialize, Debug)]
+struct SealedRecord {
+    #[serde(default)]
+    version: u32,
+    #[serde(flatten)]
+    record: CacheRecord,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    checksum: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hmac: Option<String>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum IntegrityError {
+    #[error("malformed record: {0}")]
+    Malformed(serde_json::Error),
+    #[error("schema version {0} is not supported (expected {SCHEMA_VERSION})")]
+    UnsupportedVersion(u32),
+    #[error("checksum mismatch, the record was modified")]
+    ChecksumMismatch,
+    #[error("record is not signed but a key file was given")]
+    MissingHmac,
+    #[error("record has no version or checksum but others in the same file do")]
+    Unsealed,
+    #[error("HMAC mismatch, the record was modified or signed with another key")]
+    HmacMismatch,
+}
+
+/// How much of a record could be checked.
+#[derive(Debug, PartialEq)]
+pub enum Trust {
+    /// Checksum and HMAC both match.
+    Signed,
+    /// Checksum matches; no key was given to check the HMAC.
+    Checksummed,
+    /// Written before records carried a version and checksum.
+    Legacy,
+}
+
+/// Loads the HMAC key: a hex string, surrounding whitespace ignored.
+pub fn load_key(path: &Path) -> Result<Vec<u8>, Error> {
+    let content = fs::read_to_string(path).map_err(|e| Error::io(path, e))?;
+    let key = hex::decode(content.trim()).map_err(|e| Error::Config(format!("key file {} is not hex: {}", path.display(), e)))?;
+    if key.is_empty() {
+        return Err(Error::Config(format!("key file {} is empty", path.display())));
+    }
+    Ok(key)
+}
+
+fn payload(record: &CacheRecord) -> Result<Vec<u8>, serde_json::Error> {
+    serde_json::to_vec(record)
+}
+
+fn mac(key: &[u8], payload: &[u8]) -> HmacSha256 {
+    let mut mac = HmacSha256::new_from_slice(key).expect("HMAC accepts any key length");
+    mac.update(payload);
+    mac
+}
+
+pub fn seal(record: &CacheRecord, key: Option<&[u8]>) -> Result<String, serde_json::Error> {
+    let payload = payload(record)?;
+    let sealed = SealedRecord {
+        version: SCHEMA_VERSION,
+        record: record.clone(),
+        checksum: Some(hex::encode(Sha256::digest(&payload))),
+        hmac: key.map(|key| hex::encode(mac(key, &payload).finalize().into_bytes())),
+    };
+    serde_json::to_string(&sealed)
+}
+
+/// Whether a stored record carries any of the fields [`seal`] adds, however
+/// damaged the rest of it is.
+pub fn is_sealed(json_string: &str) -> bool {
+    serde_json::from_str::<serde_json::Value>(json_string)
+        .is_ok_and(|value| ["version", "checksum", "hmac"].iter().any(|field| value.get(field).is_some()))
+}
+
+/// Parses and checks one stored record. With a key, unsigned records are
+/// rejected; without one, the HMAC is ignored and only the checksum is checked.
+/// Records written before sealing are only accepted with no key and when
+/// `allow_legacy` is set, i.e. when nothing else in their file is sealed:
+/// otherwise stripping the seal would be a way around the checks.
+pub fn open(json_string: &str, key: Option<&[u8]>, allow_legacy: bool) -> Result<(CacheRecord, Trust), IntegrityError> {
+    let sealed: SealedRecord = serde_json::from_str(json_string).map_err(IntegrityError::Malformed)?;
+    if sealed.version == 0 && sealed.checksum.is_none() && sealed.hmac.is_none() {
+        if key.is_some() {
+            return Err(IntegrityError::MissingHmac);
+        }
+        if !allow_legacy {
+            return Err(IntegrityError::Unsealed);
+        }
+        return Ok((sealed.record, Trust::Legacy));
+    }
+    if sealed.version != SCHEMA_VERSION {
+        return Err(IntegrityError::UnsupportedVersion(sealed.version));
+    }
+    let payload = payload(&sealed.record).map_err(IntegrityError::Malformed)?;
+    let checksum = hex::encode(Sha256::digest(&payload));
+    if sealed.checksum.as_deref() != Some(checksum.as_str()) {
+        return Err(IntegrityError::ChecksumMismatch);
+    }
+    let Some(key) = key else {
+        return Ok((sealed.record, Trust::Checksummed));
+    };
+    let Some(tag) = sealed.hmac else {
+        return Err(IntegrityError::MissingHmac);
+    };
+    let tag = hex::decode(tag).map_err(|_| IntegrityError::HmacMismatch)?;
+    mac(key, &payload).verify_slice(&tag).map_err(|_| IntegrityError::HmacMismatch)?;
+    Ok((sealed.record, Trust::Signed))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::{PriceData, Source};
+
+    fn record() -> CacheRecord {
+        CacheRecord::now(Source::Single, PriceData::new(vec![1.5.into(), 2.5.into()], 2.0.into()))
+    }
+
+    #[test]
+    fn test_round_trip_with_and_without_key() {
+        let key = b"secret".as_slice();
+        let (_, trust) = open(&seal(&record(), Some(key)).unwrap(), Some(key), true).unwrap();
+        assert_eq!(trust, Trust::Signed);
+        let (_, trust) = open(&seal(&record(), None).unwrap(), None, true).unwrap();
+        assert_eq!(trust, Trust::Checksummed);
+    }
+
+    #[test]
+    fn test_detects_edited_average() {
+        let sealed = seal(&record(), None).unwrap().replace("\"average\":2.0", "\"average\":3.0");
+        assert!(matches!(open(&sealed, None, true), Err(IntegrityError::ChecksumMismatch)));
+    }
+
+    #[test]
+    fn test_detects_wrong_key_and_missing_hmac() {
+        let sealed = seal(&record(), Some(b"secret")).unwrap();
+        assert!(matches!(open(&sealed, Some(b"other"), true), Err(IntegrityError::HmacMismatch)));
+        let unsigned = seal(&record(), None).unwrap();
+        assert!(matches!(open(&unsigned, Some(b"secret"), true), Err(IntegrityError::MissingHmac)));
+    }
+
+    #[test]
+    fn test_stripping_the_seal_is_not_a_downgrade() {
+        let stripped = serde_json::to_string(&record()).unwrap();
+        assert!(!is_sealed(&stripped));
+        assert!(matches!(open(&stripped, Some(b"secret"), true), Err(IntegrityError::MissingHmac)));
+        assert!(matches!(open(&stripped, None, false), Err(IntegrityError::Unsealed)));
+        assert_eq!(open(&stripped, None, true).unwrap().1, Trust::Legacy);
+        assert!(is_sealed(&seal(&record(), None).unwrap().replace("\"average\":2.0", "\"average\":\"x\"")));
+    }
+
+    #[test]
+    fn test_rejects_unknown_version() {
+        let sealed = seal(&record(), None).unwrap().replace("\"version\":1", "\"version\":9");
+        assert!(matches!(open(&sealed, None, true), Err(IntegrityError::UnsupportedVersion(9))));
+    }
+}
@@ -1,139 +1,594 @@
+mod api;
+mod config;
+mod daemon;
+mod error;
 mod file;
+mod frames;
+mod integrity;
+mod price;
+mod report;
+mod telemetry;
+mod tui;
 
-use std::fs;
-use clap::{Arg, Command};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
+use std::time::Duration;
+use chrono::{DateTime, NaiveDate, Utc};
+use clap::{Arg, ArgAction, Command};
 use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
-use tokio::sync::mpsc;
+use tokio::sync::{mpsc, Notify};
+use telemetry::Telemetry;
+use error::Error;
+use price::{Arithmetic, Price};
 
 
-const WEB_SOCKET_URL_BTC: &str = "wss://stream.binance.com:443/ws/btcusdt@miniTicker";
-const CONNECTION_TIMEOUT: u64 = 5;
+type WsStream = tokio_tungstenite::WebSocketStream<tokio_tungstenite::MaybeTlsStream<tokio::net::TcpStream>>;
 
 #[tokio::main(flavor = "multi_thread")]
-async fn main() {
+async fn main() -> ExitCode {
     let matches = Command::new("simple")
-        .about("simple client that can run the two following commands: 1) ./simple --mode=cache --times=10 2) ./simple --mode=read")
+        .about("simple client that can run the following commands: 1) ./simple --mode=cache --times=10 2) ./simple --mode=multi --clients=5 --times=10 [--record frames.jsonl | --replay frames.jsonl --speed 10x] [--tui] [--metrics-addr 127.0.0.1:9898] 3) ./simple --mode=daemon --window=60 [--slide=10] 4) ./simple --mode=serve --listen=127.0.0.1:8088 (daemon plus GET /price/latest, /price/history?since=, /price/stream) 5) ./simple --mode=read [--since T] [--until T] [--last N] [--summary] [--format table|json|csv] 6) ./simple config print; add --arithmetic exact to any fetching mode to keep exchange prices as exact decimals")
+        .subcommand_negates_reqs(true)
+        .subcommand(Command::new("config")
+            .about("Inspect the layered configuration (config file < environment < flags)")
+            .subcommand_required(true)
+            .subcommand(Command::new("print").about("Print the effective settings and where each came from"))
+        )
         .arg(Arg::new("mode")
             .short('m')
             .long("mode")
             .required(true)
-            .help("Mode should be 'cache' or 'read'")
+            .help("Mode should be 'cache', 'multi', 'daemon', 'serve' or 'read'")
+        )
+        .arg(Arg::new("config")
+            .long("config")
+            .required(false)
+            .global(true)
+            .help("TOML config file [env: SIMPLE_CONFIG, default: simple.toml if present]")
+        )
+        .arg(Arg::new("websocket-url")
+            .long("websocket-url")
+            .required(false)
+            .global(true)
+            .help("WebSocket ticker stream [env: SIMPLE_WEBSOCKET_URL, default: Binance btcusdt@miniTicker]")
+        )
+        .arg(Arg::new("connection-timeout")
+            .long("connection-timeout")
+            .required(false)
+            .global(true)
+            .help("Seconds to wait for the WebSocket to connect [env: SIMPLE_CONNECTION_TIMEOUT, default: 5]")
         )
         .arg(Arg::new("times")
             .short('t')
             .long("times")
             .required(false)
-            .help("Number of times to fetch the price")
-            .default_value("10")
+            .global(true)
+            .help("Number of times to fetch the price [env: SIMPLE_TIMES, default: 10]")
+        )
+        .arg(Arg::new("clients")
+            .short('c')
+            .long("clients")
+            .required(false)
+            .global(true)
+            .help("Multi mode: number of clients feeding the aggregator [env: SIMPLE_CLIENTS, default: 5]")
+        )
+        .arg(Arg::new("window")
+            .short('w')
+            .long("window")
+            .required(false)
+            .global(true)
+            .help("Daemon mode: window length in seconds [env: SIMPLE_WINDOW, default: 60]")
+        )
+        .arg(Arg::new("slide")
+            .long("slide")
+            .required(false)
+            .global(true)
+            .help("Daemon mode: emit a sliding window every this many seconds instead of tumbling windows [env: SIMPLE_SLIDE]")
+        )
+        .arg(Arg::new("output-dir")
+            .short('o')
+            .long("output-dir")
+            .required(false)
+            .global(true)
+            .help("Directory holding the history and per-client files [env: SIMPLE_OUTPUT_DIR, default: simple_client]")
+        )
+        .arg(Arg::new("key-file")
+            .short('k')
+            .long("key-file")
+            .required(false)
+            .global(true)
+            .help("Hex HMAC key used to sign cache records and to verify them in read mode [env: SIMPLE_KEY_FILE]")
+        )
+        .arg(Arg::new("since")
+            .long("since")
+            .required(false)
+            .help("Read mode: only show runs at or after this time (RFC 3339 or YYYY-MM-DD)")
+        )
+        .arg(Arg::new("until")
+            .long("until")
+            .required(false)
+            .help("Read mode: only show runs at or before this time (RFC 3339, or YYYY-MM-DD for up to the end of that day)")
+        )
+        .arg(Arg::new("last")
+            .long("last")
+            .required(false)
+            .help("Read mode: only show the last N runs")
+        )
+        .arg(Arg::new("summary")
+            .long("summary")
+            .action(ArgAction::SetTrue)
+            .help("Read mode: print a summary across the selected runs")
+        )
+        .arg(Arg::new("format")
+            .long("format")
+            .required(false)
+            .help("Read mode output format: table, json or csv")
+            .default_value("table")
+        )
+        .arg(Arg::new("record")
+            .long("record")
+            .required(false)
+            .conflicts_with("replay")
+            .help("Cache/multi mode: write every raw WebSocket frame to this JSONL file")
+        )
+        .arg(Arg::new("replay")
+            .long("replay")
+            .required(false)
+            .help("Cache/multi mode: feed frames from a --record file instead of the live socket")
+        )
+        .arg(Arg::new("speed")
+            .long("speed")
+            .required(false)
+            .help("Replay speed factor, e.g. 10x")
+            .default_value("1x")
+        )
+        .arg(Arg::new("tui")
+            .long("tui")
+            .action(ArgAction::SetTrue)
+            .help("Cache/multi mode: show a live dashboard instead of printing every tick")
+        )
+        .arg(Arg::new("metrics-addr")
+            .long("metrics-addr")
+            .required(false)
+            .global(true)
+            .help("Cache/multi mode: serve Prometheus metrics on this address, e.g. 127.0.0.1:9898 [env: SIMPLE_METRICS_ADDR]")
+        )
+        .arg(Arg::new("listen")
+            .long("listen")
+            .required(false)
+            .global(true)
+            .help("Serve mode: HTTP API address [env: SIMPLE_LISTEN_ADDR, default: 127.0.0.1:8088]")
+        )
+        .arg(Arg::new("arithmetic")
+            .long("arithmetic")
+            .required(false)
+            .global(true)
+            .help("'float', or 'exact' to keep exchange prices as decimals, average them exactly and store them as strings [env: SIMPLE_ARITHMETIC, default: float]")
         ).get_matches();
 
+    match run_mode(&matches).await {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            e.exit_code()
+        }
+    }
+}
+
+async fn run_mode(matches: &clap::ArgMatches) -> Result<(), Error> {
+    let settings = config::load(matches)?;
+    if let Some(("config", _)) = matches.subcommand() {
+        settings.print();
+        return Ok(());
+    }
+
     let mode = matches.get_one::<String>("mode").unwrap();
+    let output_dir = settings.output_dir.value.clone();
+    let key = settings.key_file.value.as_deref().map(integrity::load_key).transpose()?;
     match mode.as_str() {
-       "cache" => {
-           let times = matches.get_one::<String>("times").unwrap();
-           match times.parse::<usize>() {
-               Ok(times) => {
-                   // let result = get_btc_price(times).await;
-                   // if result.is_err() {
-                   //     println!("Failed to get btc price: {}", result.err().unwrap());
-                   // }
-                   client_process(times).await;
-               },
-               Err(e) => {
-                   println!("Error in times argument: {}",e);
-               }
+       "cache" | "multi" | "daemon" | "serve" => {
+           let feed = feed_from_args(matches)?;
+           let clients = if mode == "multi" { settings.clients.value } else { 1 };
+           let telemetry = Arc::new(Telemetry::new(matches.get_flag("tui")));
+           if let Some(addr) = settings.metrics_addr.value {
+               let telemetry = telemetry.clone();
+               tokio::spawn(async move {
+                   if let Err(e) = telemetry::serve_metrics(addr, telemetry).await {
+                       eprintln!("Metrics endpoint on {} failed: {}", addr, e);
+                   }
+               });
            }
+           let run = Arc::new(Run {
+               times: settings.times.value,
+               websocket_url: settings.websocket_url.value.clone(),
+               connection_timeout: settings.connection_timeout(),
+               arithmetic: settings.arithmetic.value,
+               feed,
+               output_dir,
+               key,
+               telemetry,
+           });
+           // The daemon stops gracefully on SIGINT/SIGTERM, and every mode
+           // stops when the dashboard is closed; otherwise the one-shot modes
+           // just exit on a signal.
+           let shutdown = Arc::new(Notify::new());
+           if mode == "daemon" || mode == "serve" {
+               tokio::spawn(notify_on_signal(shutdown.clone()));
+           }
+           // Bound before anything starts, so that a busy address fails the run.
+           let api_listener = if mode == "serve" {
+               let addr = settings.listen_addr.value;
+               let listener = tokio::net::TcpListener::bind(addr).await.map_err(|e| Error::io(addr.to_string(), e))?;
+               Some(listener)
+           } else {
+               None
+           };
+           let dashboard = if matches.get_flag("tui") {
+               let dashboard = tui::Dashboard::start(run.telemetry.clone(), shutdown.clone())
+                   .map_err(|e| Error::io("terminal", e))?;
+               Some(dashboard)
+           } else {
+               None
+           };
+           if let Some(listener) = api_listener {
+               let run = run.clone();
+               let addr = settings.listen_addr.value;
+               tokio::spawn(async move {
+                   run.telemetry.log(format!("Serving the price API on http://{}", addr));
+                   if let Err(e) = api::serve(listener, run.clone()).await {
+                       run.telemetry.log(format!("Price API on {} failed: {}", addr, e));
+                   }
+               });
+           }
+           let result = match mode.as_str() {
+               "daemon" | "serve" => daemon::run_daemon(run, settings.window(), settings.slide(), shutdown).await,
+               "cache" => until_shutdown(cache_process(run), &shutdown).await,
+               _ => until_shutdown(client_process(clients, run), &shutdown).await,
+           };
+           if let Some(dashboard) = dashboard {
+               dashboard.stop().await;
+           }
+           println!("{}", result?);
+           Ok(())
        },
-       "read" => {
-           file::get_data_from_file();
-       },
-       _ => {
-           println!("Error: mode should be either 'cache' or 'read'");
-       }
+       "read" => read_history(matches, &output_dir, key.as_deref()),
+       _ => Err(Error::Config("mode should be 'cache', 'multi', 'daemon', 'serve' or 'read'".to_string())),
    }
 }
 
-async fn client_process(times: usize) {
-    println!("Failed to get btc price:");
-    let (tx, rx) = mpsc::channel::<file::PriceData>(5);
+fn read_history(matches: &clap::ArgMatches, output_dir: &Path, key: Option<&[u8]>) -> Result<(), Error> {
+    let query = file::Query {
+        since: matches.get_one::<String>("since").map(|s| parse_time(s, false)).transpose().map_err(Error::Config)?,
+        until: matches.get_one::<String>("until").map(|s| parse_time(s, true)).transpose().map_err(Error::Config)?,
+        last: matches
+            .get_one::<String>("last")
+            .map(|s| s.parse::<usize>().map_err(|e| Error::Config(format!("invalid --last '{}': {}", s, e))))
+            .transpose()?,
+    };
+    let format = matches.get_one::<String>("format").unwrap().parse::<report::Format>()?;
+
+    let records = query.apply(file::get_data_from_file(output_dir, key)?);
+    if records.is_empty() {
+        return Err(Error::EmptyData("No data found, please run the cache mode first.".to_string()));
+    }
+    if matches.get_flag("summary") {
+        let summary = report::Summary::from_records(&records)
+            .ok_or_else(|| Error::EmptyData("Only per-client records selected, nothing to summarize.".to_string()))?;
+        report::print_summary(&summary, format)
+    } else {
+        report::print_records(&records, format)
+    }
+}
+
+/// Parses a `--since`/`--until` bound. A bare date is the start of that day
+/// (UTC), or its last instant with `end_of_day`, so `--until` includes it.
+fn parse_time(value: &str, end_of_day: bool) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    NaiveDate::parse_from_str(value, "%Y-%m-%d")
+        .map(|date| {
+            let time = if end_of_day { date.and_hms_nano_opt(23, 59, 59, 999_999_999) } else { date.and_hms_opt(0, 0, 0) };
+            time.unwrap().and_utc()
+        })
+        .map_err(|_| format!("invalid time '{}', expected RFC 3339 or YYYY-MM-DD", value))
+}
+
+/// Runs a one-shot mode unless the dashboard is closed first.
+async fn until_shutdown(process: impl Future<Output = Result<String, Error>>, shutdown: &Notify) -> Result<String, Error> {
+    tokio::select! {
+        result = process => result,
+        _ = shutdown.notified() => Err(Error::Interrupted),
+    }
+}
+
+async fn notify_on_signal(shutdown: Arc<Notify>) {
+    let ctrl_c = tokio::signal::ctrl_c();
+    #[cfg(unix)]
+    {
+        let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("Error in installing SIGTERM handler");
+        tokio::select! {
+            _ = ctrl_c => {}
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = ctrl_c.await;
+    shutdown.notify_one();
+}
 
-    for id in 0..5 {
+/// Where the clients get their frames from.
+#[derive(Clone)]
+enum Feed {
+    Live { recorder: Option<Arc<frames::Recorder>> },
+    Replay { frames: Arc<Vec<frames::Frame>>, speed: f64 },
+}
+
+fn feed_from_args(matches: &clap::ArgMatches) -> Result<Feed, Error> {
+    if let Some(path) = matches.get_one::<String>("replay") {
+        let speed = frames::parse_speed(matches.get_one::<String>("speed").unwrap())?;
+        let frames = frames::load_frames(Path::new(path))?;
+        return Ok(Feed::Replay { frames: Arc::new(frames), speed });
+    }
+    let recorder = matches
+        .get_one::<String>("record")
+        .map(|path| frames::Recorder::create(Path::new(path)))
+        .transpose()?;
+    Ok(Feed::Live { recorder: recorder.map(Arc::new) })
+}
+
+/// Settings shared by every client of a cache or multi run.
+struct Run {
+    times: u64,
+    websocket_url: String,
+    connection_timeout: Duration,
+    arithmetic: Arithmetic,
+    feed: Feed,
+    output_dir: PathBuf,
+    key: Option<Vec<u8>>,
+    telemetry: Arc<Telemetry>,
+}
+
+#[cfg(test)]
+impl Run {
+    /// A run that replays `frames` at full speed and keeps its records in
+    /// `output_dir`.
+    fn replay(frames: Vec<frames::Frame>, output_dir: PathBuf) -> Self {
+        Self {
+            times: 1,
+            websocket_url: String::new(),
+            connection_timeout: Duration::from_secs(1),
+            arithmetic: Arithmetic::Float,
+            feed: Feed::Replay { frames: Arc::new(frames), speed: 1000.0 },
+            output_dir,
+            key: None,
+            telemetry: Arc::new(Telemetry::new(false)),
+        }
+    }
+}
+
+async fn cache_process(run: Arc<Run>) -> Result<String, Error> {
+    let price_data = get_btc_price(&run, 0).await?;
+    let message = format!("Cache complete. The average USD price of BTC is: {}", price_data.average);
+    file::write_data_to_file(&run.output_dir, &file::CacheRecord::now(file::Source::Single, price_data), run.key.as_deref())?;
+    Ok(message)
+}
+
+async fn client_process(clients: usize, run: Arc<Run>) -> Result<String, Error> {
+    let (tx, rx) = mpsc::channel::<(usize, Result<file::PriceData, Error>)>(clients);
+
+    for id in 0..clients {
         let tx_clone = tx.clone();
+        let run = run.clone();
         tokio::spawn(async move {
-            println!("Thread id: {:?}", std::thread::current().id());
-            let result = get_btc_price(times, id, tx_clone).await;
-            if result.is_err() {
-                println!("Failed to get btc price: {}", result.err().unwrap());
+            let result = get_btc_price(&run, id).await.and_then(|price_data| {
+                run.telemetry.log(format!("Cache complete. The average USD price of BTC is: {} for id: {}", price_data.average, id));
+                let record = file::CacheRecord::now(file::Source::Client(id), price_data.clone());
+                file::write_client_data_to_file(&run.output_dir, id, &record, run.key.as_deref())?;
+                Ok(price_data)
+            });
+            if let Err(e) = &result {
+                run.telemetry.log(format!("Failed to get btc price for id {}: {}", id, e));
             }
+            let _ = tx_clone.send((id, result)).await;
         });
     }
-    aggregator(rx).await;
+    // Only the clients hold senders now, so the aggregator stops waiting once
+    // every client has either reported or failed.
+    drop(tx);
+    aggregator(rx, clients, &run).await
 }
 
-async fn aggregator(mut rx: mpsc::Receiver<file::PriceData>) {
-    let mut results = Vec::new();
-    while let Some(price_data) = rx.recv().await {
-        println!("Aggregator received avg from client: {:?}", price_data);
-        results.push(price_data.average);
-
-        if results.len() == 5 {
-            let final_avg = results.iter().sum::<f64>() / results.len() as f64;
-            println!("\n✅ Final Aggregated Average of all clients: {:.2}", final_avg);
-            break;
+async fn aggregator(
+    mut rx: mpsc::Receiver<(usize, Result<file::PriceData, Error>)>,
+    clients: usize,
+    run: &Run,
+) -> Result<String, Error> {
+    let mut received = Vec::new();
+    let mut first_error = None;
+    while let Some((id, result)) = rx.recv().await {
+        match result {
+            Ok(price_data) => {
+                run.telemetry.log(format!("Aggregator received avg from client {}: {}", id, price_data.average));
+                received.push((id, price_data));
+            }
+            Err(e) => {
+                first_error.get_or_insert(e);
+            }
         }
     }
+    // Sum in client order rather than arrival order so a replay produces the
+    // same floating point result every time.
+    received.sort_by_key(|(id, _)| *id);
+    let results: Vec<Price> = received.iter().map(|(_, price_data)| price_data.average).collect();
+    let all_prices: Vec<Price> = received.into_iter().flat_map(|(_, price_data)| price_data.prices).collect();
+    let Some(final_avg) = Price::mean(&results) else {
+        // Every client failed; their first error says more than "no data".
+        return Err(first_error.unwrap_or_else(|| Error::EmptyData("No client reported a price, nothing to aggregate.".to_string())));
+    };
+    if results.len() < clients {
+        run.telemetry.log(format!("Only {} of {} clients reported a price.", results.len(), clients));
+    }
+    run.telemetry.aggregate(final_avg.to_f64());
+    let record = file::CacheRecord::now(
+        file::Source::Aggregate { clients: results.len() },
+        file::PriceData::new(all_prices, final_avg),
+    );
+    file::write_data_to_file(&run.output_dir, &record, run.key.as_deref())?;
+    Ok(format!("\n✅ Final Aggregated Average of all clients: {:.2}", final_avg))
 }
 
-async fn get_btc_price(times: usize, id: i32, tx: mpsc::Sender<file::PriceData>) -> Result<(), Box<dyn std::error::Error>> {
-    let connection = tokio_tungstenite::connect_async(WEB_SOCKET_URL_BTC);
-    let timeout_duration = tokio::time::Duration::from_secs(CONNECTION_TIMEOUT);
-    let (stream, _response) = tokio::time::timeout(timeout_duration, connection).await??;
-    let (_write, mut read) = stream.split();
-    let fetch_times = tokio::time::Duration::from_secs(times as u64);
-    let start_time = tokio::time::Instant::now();
+async fn get_btc_price(run: &Run, id: usize) -> Result<file::PriceData, Error> {
     let mut prices = Vec::new();
-    println!("Client {id} connected to WebSocket.");
+    let mut on_price = |price: Price| prices.push(price);
+    match &run.feed {
+        Feed::Live { recorder } => {
+            let fetch_times = Duration::from_secs(run.times);
+            stream_live_prices(run, id, recorder.as_deref(), Some(fetch_times), &mut on_price).await?
+        }
+        Feed::Replay { frames, speed } => replay_prices(run, frames, id, *speed, &mut on_price).await?,
+    };
+    let Some(average) = Price::mean(&prices) else {
+        return Err(Error::EmptyData(format!("client {} received no prices to average", id)));
+    };
+    Ok(file::PriceData::new(prices, average))
+}
+
+async fn connect(run: &Run) -> Result<WsStream, Error> {
+    let connection = tokio_tungstenite::connect_async(run.websocket_url.as_str());
+    let (stream, _response) = tokio::time::timeout(run.connection_timeout, connection)
+        .await
+        .map_err(|_| Error::ConnectionTimeout { url: run.websocket_url.clone(), timeout: run.connection_timeout })?
+        .map_err(|source| Error::Connection { url: run.websocket_url.clone(), source: Box::new(source) })?;
+    Ok(stream)
+}
+
+/// Reads the live socket for `fetch_times`, or until it fails when no limit
+/// is given, passing every price to `on_price`.
+async fn stream_live_prices(
+    run: &Run,
+    id: usize,
+    recorder: Option<&frames::Recorder>,
+    fetch_times: Option<Duration>,
+    on_price: &mut (dyn FnMut(Price) + Send),
+) -> Result<(), Error> {
+    let mut stream = connect(run).await?;
+    let start_time = tokio::time::Instant::now();
+    run.telemetry.log(format!("Client {id} connected to WebSocket."));
     tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
     
-    while start_time.elapsed() < fetch_times {
-        if let Some(msg) = read.next().await{
-            let message = msg?.to_string();
-            let btc_ticker = serde_json::from_str::<BtcTicker>(message.as_str());
-            if btc_ticker.is_ok() {
-                let price = btc_ticker.unwrap().c;
-                prices.push(price);
-                println!("Received btc Price: {} for id:{}", price, id);
-            } else {
-                println!("Failed to get btc price: {}", btc_ticker.err().unwrap());
+    while fetch_times.is_none_or(|fetch_times| start_time.elapsed() < fetch_times) {
+        match stream.next().await {
+            Some(Ok(msg)) => {
+                let message = msg.to_string();
+                if let Some(recorder) = recorder {
+                    recorder.record(id, &message);
+                }
+                if let Some(price) = handle_message(run, &message, id) {
+                    on_price(price);
+                }
+            }
+            Some(Err(e)) => {
+                run.telemetry.log(format!("Client {id} lost the WebSocket: {e}, reconnecting."));
+                run.telemetry.reconnect(id);
+                stream = connect(run).await?;
+            }
+            None => {
+                run.telemetry.log(format!("Client {id} WebSocket closed, reconnecting."));
+                run.telemetry.reconnect(id);
+                stream = connect(run).await?;
             }
         }
     }
-    let average = prices.iter().sum::<f64>() / prices.len() as f64;
-    let price_data = file::PriceData::new(prices, average);
-
-    let json_string = serde_json::to_string(&price_data).expect("Error in serializing");
-    fs::write(format!("btc_price.{id}.json"), json_string).expect("Error in writing file");
-
-    // file::write_data_to_file(&price_data);
-    println!("Cache complete. The average USD price of BTC is: {} for id: {}", average, id);
+    Ok(())
+}
 
-    let _ = tx.send(price_data).await;
+/// Feeds this client's recorded frames through the same handler as the live
+/// socket, keeping the recorded gaps between frames divided by `speed`.
+async fn replay_prices(
+    run: &Run,
+    frames: &[frames::Frame],
+    id: usize,
+    speed: f64,
+    on_price: &mut (dyn FnMut(Price) + Send),
+) -> Result<(), Error> {
+    let client_frames: Vec<&frames::Frame> = frames.iter().filter(|frame| frame.client == id).collect();
+    if client_frames.is_empty() {
+        return Err(Error::EmptyData(format!("no recorded frames for client {}", id)));
+    }
+    run.telemetry.log(format!("Client {id} replaying recorded frames."));
+    let mut previous: Option<&frames::Frame> = None;
+    for frame in client_frames {
+        if let Some(previous) = previous {
+            let gap = (frame.received_at - previous.received_at).to_std().unwrap_or_default();
+            tokio::time::sleep(gap.div_f64(speed)).await;
+        }
+        if let Some(price) = handle_message(run, &frame.message, id) {
+            on_price(price);
+        }
+        previous = Some(frame);
+    }
     Ok(())
 }
 
+fn handle_message(run: &Run, message: &str, id: usize) -> Option<Price> {
+    let parsed = serde_json::from_str::<BtcTicker>(message)
+        .map_err(|e| e.to_string())
+        .and_then(|btc_ticker| Price::parse(&btc_ticker.c, run.arithmetic));
+    match parsed {
+        Ok(price) => {
+            run.telemetry.tick(id, price.to_f64());
+            run.telemetry.log(format!("Received btc Price: {} for id:{}", price, id));
+            Some(price)
+        }
+        Err(e) => {
+            run.telemetry.parse_failure(id);
+            run.telemetry.log(format!("Failed to get btc price: {}", e));
+            None
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 struct BtcTicker {
     s: String,
-    #[serde(deserialize_with = "de_string_to_f64")]
-    c: f64,
+    /// Close price exactly as the exchange sent it; parsed per `--arithmetic`.
+    c: String,
 }
 
-// Custom deserializer function
-fn de_string_to_f64<'de, D>(deserializer: D) -> Result<f64, D::Error>
-where
-    D: serde::Deserializer<'de>,
-{
-    let s: &str = Deserialize::deserialize(deserializer)?;
-    s.parse::<f64>().map_err(serde::de::Error::custom)
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_a_bare_until_date_covers_the_whole_day() {
+        let evening = DateTime::parse_from_rfc3339("2026-10-19T18:30:00Z").unwrap().with_timezone(&Utc);
+        let next_morning = DateTime::parse_from_rfc3339("2026-10-20T00:00:00Z").unwrap().with_timezone(&Utc);
+        let records: Vec<file::CacheRecord> = [evening, next_morning]
+            .into_iter()
+            .map(|timestamp| file::CacheRecord {
+                timestamp,
+                source: file::Source::Single,
+                data: file::PriceData::new(vec![1.0.into()], 1.0.into()),
+            })
+            .collect();
+        let query = file::Query {
+            since: Some(parse_time("2026-10-19", false).unwrap()),
+            until: Some(parse_time("2026-10-19", true).unwrap()),
+            last: None,
+        };
+        let selected = query.apply(records);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].timestamp, evening);
+
+        assert_eq!(parse_time("2026-10-19", false).unwrap().to_rfc3339(), "2026-10-19T00:00:00+00:00");
+        assert_eq!(
+            parse_time("2026-10-19T12:00:00+02:00", true).unwrap().to_rfc3339(),
+            "2026-10-19T10:00:00+00:00"
+        );
+        assert!(parse_time("19/10/2026", true).is_err());
+    }
 }
@@ -0,0 +1,140 @@
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::fmt;
+
+/// How prices from the exchange are parsed and averaged.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub enum Arithmetic {
+    /// Parse into `f64`; fast, but sums drift over long runs.
+    #[default]
+    Float,
+    /// Keep the exchange string as a `Decimal` and average exactly.
+    Exact,
+}
+
+impl Arithmetic {
+    pub fn parse(value: &str) -> Result<Self, String> {
+        match value {
+            "float" => Ok(Arithmetic::Float),
+            "exact" => Ok(Arithmetic::Exact),
+            _ => Err("expected float or exact".to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Arithmetic {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arithmetic::Float => write!(f, "float"),
+            Arithmetic::Exact => write!(f, "exact"),
+        }
+    }
+}
+
+/// One price. Float prices are stored as JSON numbers, exact prices as
+/// strings with the digits the exchange sent, so older cache files still load.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+#[serde(untagged)]
+pub enum Price {
+    Float(f64),
+    Exact(Decimal),
+}
+
+impl Price {
+    pub fn parse(value: &str, arithmetic: Arithmetic) -> Result<Self, String> {
+        match arithmetic {
+            Arithmetic::Float => value.parse::<f64>().map(Price::Float).map_err(|e| e.to_string()),
+            // `from_str_exact` refuses to round, so nothing the exchange sent is lost.
+            Arithmetic::Exact => Decimal::from_str_exact(value).map(Price::Exact).map_err(|e| e.to_string()),
+        }
+    }
+
+    pub fn to_f64(self) -> f64 {
+        match self {
+            Price::Float(price) => price,
+            Price::Exact(price) => f64::try_from(price).unwrap_or(f64::NAN),
+        }
+    }
+
+    /// Mean of `prices`: an exact sum divided once when every price is exact,
+    /// a float mean as soon as one of them is not.
+    pub fn mean(prices: &[Price]) -> Option<Price> {
+        if prices.is_empty() {
+            return None;
+        }
+        let exact: Option<Vec<Decimal>> = prices
+            .iter()
+            .map(|price| match price {
+                Price::Exact(price) => Some(*price),
+                Price::Float(_) => None,
+            })
+            .collect();
+        Some(match exact {
+            Some(exact) => Price::Exact(exact.iter().sum::<Decimal>() / Decimal::from(exact.len())),
+            None => Price::Float(prices.iter().map(|price| price.to_f64()).sum::<f64>() / prices.len() as f64),
+        })
+    }
+}
+
+impl From<f64> for Price {
+    fn from(price: f64) -> Self {
+        Price::Float(price)
+    }
+}
+
+impl PartialEq for Price {
+    fn eq(&self, other: &Self) -> bool {
+        self.partial_cmp(other) == Some(Ordering::Equal)
+    }
+}
+
+impl PartialOrd for Price {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        match (self, other) {
+            (Price::Exact(a), Price::Exact(b)) => Some(a.cmp(b)),
+            _ => self.to_f64().partial_cmp(&other.to_f64()),
+        }
+    }
+}
+
+/// Forwards width and precision, so `{:>12.2}` works for both kinds.
+impl fmt::Display for Price {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Price::Float(price) => fmt::Display::fmt(price, f),
+            Price::Exact(price) => fmt::Display::fmt(price, f),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_exact_mean_keeps_every_digit() {
+        let prices: Vec<Price> = ["0.1", "0.2", "0.3"]
+            .iter()
+            .map(|value| Price::parse(value, Arithmetic::Exact).unwrap())
+            .collect();
+        assert_eq!(Price::mean(&prices).unwrap().to_string(), "0.2");
+        let floats: Vec<Price> = [0.1, 0.2, 0.3].into_iter().map(Price::from).collect();
+        assert_ne!(Price::mean(&floats).unwrap().to_f64(), 0.2);
+    }
+
+    #[test]
+    fn test_exact_prices_round_trip_as_strings() {
+        let price = Price::parse("104321.10000000", Arithmetic::Exact).unwrap();
+        let json = serde_json::to_string(&price).unwrap();
+        assert_eq!(json, "\"104321.10000000\"");
+        assert!(matches!(serde_json::from_str::<Price>(&json).unwrap(), Price::Exact(_)));
+        assert!(matches!(serde_json::from_str::<Price>("104321.1").unwrap(), Price::Float(_)));
+    }
+
+    #[test]
+    fn test_mixed_prices_fall_back_to_float() {
+        let prices = [Price::parse("1.5", Arithmetic::Exact).unwrap(), Price::Float(2.5)];
+        assert_eq!(Price::mean(&prices), Some(Price::Float(2.0)));
+    }
+}
@@ -0,0 +1,169 @@
+use crate::error::Error;
+use crate::file::{CacheRecord, Source};
+use crate::price::Price;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy)]
+pub enum Format {
+    Table,
+    Json,
+    Csv,
+}
+
+impl std::str::FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Format::Table),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(Error::Config(format!("unknown format '{}', expected table, json or csv", s))),
+        }
+    }
+}
+
+/// Aggregate over every selected run. A `multi` run counts once, through its
+/// aggregate record, which already holds the prices of all its clients.
+#[derive(Serialize, Debug)]
+pub struct Summary {
+    pub runs: usize,
+    pub data_points: usize,
+    pub mean_of_averages: Price,
+    pub min_price: Price,
+    pub max_price: Price,
+    pub first_run: String,
+    pub last_run: String,
+}
+
+impl Summary {
+    pub fn from_records(records: &[CacheRecord]) -> Option<Self> {
+        let records: Vec<&CacheRecord> = records
+            .iter()
+            .filter(|record| !matches!(record.source, Source::Client(_)))
+            .collect();
+        let first = records.first()?;
+        let last = records.last()?;
+        let prices: Vec<Price> = records.iter().flat_map(|record| record.data.prices.iter().copied()).collect();
+        let averages: Vec<Price> = records.iter().map(|record| record.data.average).collect();
+        let (min_price, max_price) = min_max(&prices);
+        Some(Self {
+            runs: records.len(),
+            data_points: prices.len(),
+            mean_of_averages: Price::mean(&averages)?,
+            min_price,
+            max_price,
+            first_run: first.timestamp.to_rfc3339(),
+            last_run: last.timestamp.to_rfc3339(),
+        })
+    }
+}
+
+fn min_max(prices: &[Price]) -> (Price, Price) {
+    let Some(&first) = prices.first() else {
+        return (Price::Float(f64::NAN), Price::Float(f64::NAN));
+    };
+    prices.iter().fold((first, first), |(min, max), &price| {
+        (if price < min { price } else { min }, if price > max { price } else { max })
+    })
+}
+
+pub fn print_records(records: &[CacheRecord], format: Format) -> Result<(), Error> {
+    match format {
+        Format::Table => {
+            println!("{:<27} {:<14} {:>7} {:>12} {:>12} {:>12}", "timestamp", "source", "points", "average", "min", "max");
+            for record in records {
+                let (min, max) = min_max(&record.data.prices);
+                println!(
+                    "{:<27} {:<14} {:>7} {:>12.2} {:>12.2} {:>12.2}",
+                    record.timestamp.format("%Y-%m-%dT%H:%M:%S%.3fZ"),
+                    record.source.to_string(),
+                    record.data.prices.len(),
+                    record.data.average,
+                    min,
+                    max
+                );
+            }
+        }
+        Format::Json => {
+            println!("{}", serde_json::to_string_pretty(records)?);
+        }
+        Format::Csv => {
+            println!("timestamp,source,points,average,min,max");
+            for record in records {
+                let (min, max) = min_max(&record.data.prices);
+                println!(
+                    "{},{},{},{},{},{}",
+                    record.timestamp.to_rfc3339(),
+                    record.source,
+                    record.data.prices.len(),
+                    record.data.average,
+                    min,
+                    max
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn print_summary(summary: &Summary, format: Format) -> Result<(), Error> {
+    match format {
+        Format::Table => {
+            println!("runs:             {}", summary.runs);
+            println!("data points:      {}", summary.data_points);
+            println!("mean of averages: {:.2}", summary.mean_of_averages);
+            println!("min price:        {:.2}", summary.min_price);
+            println!("max price:        {:.2}", summary.max_price);
+            println!("first run:        {}", summary.first_run);
+            println!("last run:         {}", summary.last_run);
+        }
+        Format::Json => {
+            println!("{}", serde_json::to_string_pretty(summary)?);
+        }
+        Format::Csv => {
+            println!("runs,data_points,mean_of_averages,min_price,max_price,first_run,last_run");
+            println!(
+                "{},{},{},{},{},{},{}",
+                summary.runs,
+                summary.data_points,
+                summary.mean_of_averages,
+                summary.min_price,
+                summary.max_price,
+                summary.first_run,
+                summary.last_run
+            );
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::file::{self, PriceData};
+
+    #[test]
+    fn test_summary_counts_a_multi_run_once() {
+        let dir = std::env::temp_dir().join(format!("simple-report-multi-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let clients = [vec![100.0, 102.0], vec![98.0, 104.0]];
+        for (id, prices) in clients.iter().enumerate() {
+            let prices: Vec<Price> = prices.iter().map(|&price| price.into()).collect();
+            let average = Price::mean(&prices).unwrap();
+            let record = CacheRecord::now(Source::Client(id), PriceData::new(prices, average));
+            file::write_client_data_to_file(&dir, id, &record, None).unwrap();
+        }
+        let all_prices: Vec<Price> = clients.iter().flatten().map(|&price| price.into()).collect();
+        let aggregate = CacheRecord::now(Source::Aggregate { clients: 2 }, PriceData::new(all_prices, 101.0.into()));
+        file::write_data_to_file(&dir, &aggregate, None).unwrap();
+
+        let records = file::get_data_from_file(&dir, None).unwrap();
+        assert_eq!(records.len(), 3);
+        let summary = Summary::from_records(&records).unwrap();
+        assert_eq!((summary.runs, summary.data_points), (1, 4));
+        assert_eq!(summary.mean_of_averages, Price::Float(101.0));
+        assert_eq!((summary.min_price, summary.max_price), (Price::Float(98.0), Price::Float(104.0)));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,250 @@
+use chrono::{DateTime, Utc};
+use prometheus::{Encoder, Gauge, GaugeVec, IntCounterVec, Opts, Registry, TextEncoder};
+use serde::Serialize;
+use std::collections::{BTreeMap, VecDeque};
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+const RECENT_PRICES: usize = 120;
+const RATE_WINDOW: Duration = Duration::from_secs(10);
+const LOG_LINES: usize = 100;
+const TICK_SUBSCRIBER_BUFFER: usize = 256;
+
+/// A price as pushed to live subscribers such as the `serve` event stream.
+#[derive(Debug, Clone, Serialize)]
+pub struct Tick {
+    pub client: usize,
+    pub price: f64,
+    pub received_at: DateTime<Utc>,
+}
+
+/// Live view of one client, used by the dashboard.
+#[derive(Debug, Default, Clone)]
+pub struct ClientStats {
+    pub recent_prices: VecDeque<f64>,
+    pub ticks: u64,
+    pub parse_failures: u64,
+    pub reconnects: u64,
+    sum: f64,
+    recent_ticks: VecDeque<Instant>,
+}
+
+impl ClientStats {
+    pub fn average(&self) -> Option<f64> {
+        (self.ticks > 0).then(|| self.sum / self.ticks as f64)
+    }
+
+    pub fn last_price(&self) -> Option<f64> {
+        self.recent_prices.back().copied()
+    }
+
+    /// Ticks per second over the last few seconds.
+    pub fn tick_rate(&self) -> f64 {
+        let Some(first) = self.recent_ticks.front() else {
+            return 0.0;
+        };
+        let window = first.elapsed().clamp(Duration::from_secs(1), RATE_WINDOW);
+        self.recent_ticks.len() as f64 / window.as_secs_f64()
+    }
+}
+
+/// Collects what the clients are doing, both for the `--tui` dashboard and
+/// for the Prometheus endpoint. When the dashboard owns the terminal, log
+/// lines are kept in memory for it instead of being printed.
+pub struct Telemetry {
+    tui: bool,
+    clients: Mutex<BTreeMap<usize, ClientStats>>,
+    log: Mutex<VecDeque<String>>,
+    registry: Registry,
+    ticks: IntCounterVec,
+    parse_failures: IntCounterVec,
+    reconnects: IntCounterVec,
+    average: GaugeVec,
+    aggregate_average: Gauge,
+    ticks_tx: tokio::sync::broadcast::Sender<Tick>,
+}
+
+impl Telemetry {
+    pub fn new(tui: bool) -> Self {
+        let ticks = IntCounterVec::new(
+            Opts::new("simple_ticks_received_total", "Price ticks received from the WebSocket"),
+            &["client"],
+        )
+        .unwrap();
+        let parse_failures = IntCounterVec::new(
+            Opts::new("simple_parse_failures_total", "WebSocket messages that were not a price ticker"),
+            &["client"],
+        )
+        .unwrap();
+        let reconnects = IntCounterVec::new(
+            Opts::new("simple_reconnects_total", "WebSocket reconnects after the stream ended or failed"),
+            &["client"],
+        )
+        .unwrap();
+        let average = GaugeVec::new(
+            Opts::new("simple_average_price", "Running average BTC price of the current run"),
+            &["client"],
+        )
+        .unwrap();
+        let aggregate_average = Gauge::new(
+            "simple_aggregate_average_price",
+            "Last aggregated average across all clients",
+        )
+        .unwrap();
+
+        let registry = Registry::new();
+        registry.register(Box::new(ticks.clone())).unwrap();
+        registry.register(Box::new(parse_failures.clone())).unwrap();
+        registry.register(Box::new(reconnects.clone())).unwrap();
+        registry.register(Box::new(average.clone())).unwrap();
+        registry.register(Box::new(aggregate_average.clone())).unwrap();
+
+        Self {
+            tui,
+            clients: Mutex::new(BTreeMap::new()),
+            log: Mutex::new(VecDeque::new()),
+            registry,
+            ticks,
+            parse_failures,
+            reconnects,
+            average,
+            aggregate_average,
+            ticks_tx: tokio::sync::broadcast::channel(TICK_SUBSCRIBER_BUFFER).0,
+        }
+    }
+
+    /// Live ticks from every client; slow subscribers miss ticks rather than
+    /// holding up the clients.
+    pub fn subscribe(&self) -> tokio::sync::broadcast::Receiver<Tick> {
+        self.ticks_tx.subscribe()
+    }
+
+    pub fn log(&self, message: String) {
+        if !self.tui {
+            println!("{}", message);
+            return;
+        }
+        let mut log = self.log.lock().unwrap();
+        if log.len() == LOG_LINES {
+            log.pop_front();
+        }
+        log.push_back(message);
+    }
+
+    pub fn tick(&self, id: usize, price: f64) {
+        let label = id.to_string();
+        self.ticks.with_label_values(&[&label]).inc();
+        let _ = self.ticks_tx.send(Tick {
+            client: id,
+            price,
+            received_at: Utc::now(),
+        });
+
+        let mut clients = self.clients.lock().unwrap();
+        let stats = clients.entry(id).or_default();
+        stats.ticks += 1;
+        stats.sum += price;
+        if stats.recent_prices.len() == RECENT_PRICES {
+            stats.recent_prices.pop_front();
+        }
+        stats.recent_prices.push_back(price);
+        let now = Instant::now();
+        stats.recent_ticks.push_back(now);
+        while stats.recent_ticks.front().is_some_and(|tick| now - *tick > RATE_WINDOW) {
+            stats.recent_ticks.pop_front();
+        }
+        if let Some(average) = stats.average() {
+            self.average.with_label_values(&[&label]).set(average);
+        }
+    }
+
+    pub fn parse_failure(&self, id: usize) {
+        self.parse_failures.with_label_values(&[&id.to_string()]).inc();
+        self.clients.lock().unwrap().entry(id).or_default().parse_failures += 1;
+    }
+
+    pub fn reconnect(&self, id: usize) {
+        self.reconnects.with_label_values(&[&id.to_string()]).inc();
+        self.clients.lock().unwrap().entry(id).or_default().reconnects += 1;
+    }
+
+    pub fn aggregate(&self, average: f64) {
+        self.aggregate_average.set(average);
+    }
+
+    pub fn clients(&self) -> Vec<(usize, ClientStats)> {
+        let clients = self.clients.lock().unwrap();
+        clients.iter().map(|(id, stats)| (*id, stats.clone())).collect()
+    }
+
+    pub fn log_lines(&self) -> Vec<String> {
+        self.log.lock().unwrap().iter().cloned().collect()
+    }
+
+    pub fn encode_metrics(&self) -> String {
+        let mut buffer = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buffer)
+            .expect("Error in encoding metrics");
+        String::from_utf8(buffer).expect("Prometheus text format is UTF-8")
+    }
+}
+
+/// Serves `GET /metrics` in the Prometheus text format until the process exits.
+pub async fn serve_metrics(addr: SocketAddr, telemetry: Arc<Telemetry>) -> std::io::Result<()> {
+    let app = axum::Router::new().route(
+        "/metrics",
+        axum::routing::get(move || {
+            let telemetry = telemetry.clone();
+            async move {
+                (
+                    [(axum::http::header::CONTENT_TYPE, "text/plain; version=0.0.4")],
+                    telemetry.encode_metrics(),
+                )
+            }
+        }),
+    );
+    let listener = tokio::net::TcpListener::bind(addr).await?;
+    axum::serve(listener, app).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_metrics_are_rendered_per_client() {
+        let telemetry = Telemetry::new(false);
+        telemetry.tick(0, 100.0);
+        telemetry.tick(0, 200.0);
+        telemetry.tick(1, 50.0);
+        telemetry.parse_failure(1);
+        telemetry.reconnect(0);
+        telemetry.aggregate(125.0);
+
+        let metrics = telemetry.encode_metrics();
+        for line in [
+            "simple_ticks_received_total{client=\"0\"} 2",
+            "simple_ticks_received_total{client=\"1\"} 1",
+            "simple_parse_failures_total{client=\"1\"} 1",
+            "simple_reconnects_total{client=\"0\"} 1",
+            "simple_average_price{client=\"0\"} 150",
+            "simple_aggregate_average_price 125",
+        ] {
+            assert!(metrics.lines().any(|metric| metric == line), "missing {} in\n{}", line, metrics);
+        }
+    }
+
+    #[test]
+    fn test_dashboard_log_keeps_the_latest_lines() {
+        let telemetry = Telemetry::new(true);
+        for line in 0..LOG_LINES + 5 {
+            telemetry.log(format!("line {}", line));
+        }
+        let lines = telemetry.log_lines();
+        assert_eq!(lines.len(), LOG_LINES);
+        assert_eq!(lines[0], "line 5");
+        assert_eq!(lines[LOG_LINES - 1], format!("line {}", LOG_LINES + 4));
+    }
+}
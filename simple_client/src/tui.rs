@@ -0,0 +1,162 @@
+use crate::telemetry::{ClientStats, Telemetry};
+use crossterm::event::{self, Event, KeyCode, KeyModifiers};
+use ratatui::layout::{Constraint, Layout};
+use ratatui::style::{Color, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, Borders, Paragraph, Sparkline};
+use ratatui::Frame;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{oneshot, Notify};
+use tokio::task::JoinHandle;
+
+const REFRESH: Duration = Duration::from_millis(250);
+
+/// A running `--tui` dashboard. The terminal is restored by `stop`.
+pub struct Dashboard {
+    stop: oneshot::Sender<()>,
+    handle: JoinHandle<()>,
+}
+
+impl Dashboard {
+    /// Quitting the dashboard notifies `shutdown`, so the run stops the same
+    /// way as on SIGINT and still restores the terminal and flushes its output.
+    pub fn start(telemetry: Arc<Telemetry>, shutdown: Arc<Notify>) -> std::io::Result<Self> {
+        let mut terminal = ratatui::try_init()?;
+        let (stop, mut stopped) = oneshot::channel();
+        let handle = tokio::spawn(async move {
+            loop {
+                if let Err(e) = terminal.draw(|frame| render(frame, &telemetry)) {
+                    ratatui::restore();
+                    println!("Dashboard stopped: {}", e);
+                    return;
+                }
+                if quit_requested() {
+                    ratatui::restore();
+                    shutdown.notify_one();
+                    return;
+                }
+                tokio::select! {
+                    _ = &mut stopped => break,
+                    _ = tokio::time::sleep(REFRESH) => {}
+                }
+            }
+            ratatui::restore();
+        });
+        Ok(Self { stop, handle })
+    }
+
+    pub async fn stop(self) {
+        let _ = self.stop.send(());
+        let _ = self.handle.await;
+    }
+}
+
+/// Raw mode swallows Ctrl-C, so the dashboard watches for it along with `q`.
+fn quit_requested() -> bool {
+    while event::poll(Duration::ZERO).unwrap_or(false) {
+        if let Ok(Event::Key(key)) = event::read() {
+            let ctrl_c = key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL);
+            if ctrl_c || key.code == KeyCode::Char('q') || key.code == KeyCode::Esc {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn render(frame: &mut Frame, telemetry: &Telemetry) {
+    let clients = telemetry.clients();
+    let mut constraints: Vec<Constraint> = clients.iter().map(|_| Constraint::Length(5)).collect();
+    constraints.push(Constraint::Min(5));
+    let areas = Layout::vertical(constraints).split(frame.area());
+
+    for ((id, stats), area) in clients.iter().zip(areas.iter()) {
+        let data = sparkline_data(stats);
+        let sparkline = Sparkline::default()
+            .block(Block::default().borders(Borders::ALL).title(client_title(*id, stats)))
+            .data(&data)
+            .style(Style::default().fg(Color::Green));
+        frame.render_widget(sparkline, *area);
+    }
+
+    let log_area = areas[areas.len() - 1];
+    let visible = log_area.height.saturating_sub(2) as usize;
+    let lines = telemetry.log_lines();
+    let lines: Vec<Line> = lines[lines.len().saturating_sub(visible)..]
+        .iter()
+        .map(|line| Line::from(line.as_str()))
+        .collect();
+    let log = Paragraph::new(lines).block(Block::default().borders(Borders::ALL).title("log (q to quit)"));
+    frame.render_widget(log, log_area);
+}
+
+fn client_title(id: usize, stats: &ClientStats) -> String {
+    format!(
+        " client {}  last {}  avg {}  {:.1} ticks/s  {} parse failures  {} reconnects ",
+        id,
+        stats.last_price().map_or("-".to_string(), |price| format!("{:.2}", price)),
+        stats.average().map_or("-".to_string(), |average| format!("{:.2}", average)),
+        stats.tick_rate(),
+        stats.parse_failures,
+        stats.reconnects
+    )
+}
+
+/// Sparklines take unsigned bars, so prices are shifted to the window's
+/// minimum and scaled to cents.
+fn sparkline_data(stats: &ClientStats) -> Vec<u64> {
+    let min = stats.recent_prices.iter().copied().fold(f64::INFINITY, f64::min);
+    stats
+        .recent_prices
+        .iter()
+        .map(|price| ((price - min) * 100.0).round() as u64 + 1)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    fn screen(telemetry: &Telemetry, width: u16, height: u16) -> String {
+        let mut terminal = Terminal::new(TestBackend::new(width, height)).unwrap();
+        terminal.draw(|frame| render(frame, telemetry)).unwrap();
+        let buffer = terminal.backend().buffer();
+        (0..height)
+            .map(|y| (0..width).map(|x| buffer[(x, y)].symbol()).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn test_dashboard_shows_each_client_and_the_latest_log_lines() {
+        let telemetry = Telemetry::new(true);
+        telemetry.tick(0, 100.0);
+        telemetry.tick(0, 102.0);
+        telemetry.parse_failure(0);
+        telemetry.reconnect(1);
+        for line in 0..10 {
+            telemetry.log(format!("line {}", line));
+        }
+
+        let screen = screen(&telemetry, 140, 16);
+        assert!(screen.contains("client 0  last 102.00  avg 101.00"), "{}", screen);
+        assert!(screen.contains("1 parse failures  0 reconnects"), "{}", screen);
+        assert!(screen.contains("client 1  last -  avg -"), "{}", screen);
+        // Two sparklines of five rows leave four log lines inside the border.
+        assert!(screen.contains("line 9") && screen.contains("line 6"), "{}", screen);
+        assert!(!screen.contains("line 5"), "{}", screen);
+    }
+
+    #[test]
+    fn test_sparkline_bars_are_cents_above_the_window_minimum() {
+        let telemetry = Telemetry::new(true);
+        for price in [100.0, 100.5, 100.25] {
+            telemetry.tick(0, price);
+        }
+        let (_, stats) = telemetry.clients().remove(0);
+        assert_eq!(sparkline_data(&stats), vec![1, 51, 26]);
+    }
+}
@@ -0,0 +1,995 @@
+use crate::attestation::{self, Signer};
+use crate::audit::AuditLog;
+use crate::keys::{KeySet, Registry, SignedMessage};
+use crate::protocol::{self, Message, Proposal, RoundResult};
+use crate::robust::{self, Strategy};
+use crate::transport;
+use bytes::Bytes;
+use chrono::{DateTime, TimeDelta, Utc};
+use ed25519_dalek::SigningKey;
+use futures_util::{SinkExt, StreamExt};
+use serde::Serialize;
+use std::collections::{BTreeMap, BTreeSet, HashSet, VecDeque};
+use std::fs::OpenOptions;
+use std::io::Write;
+use std::path::Path;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::{Notify, broadcast};
+
+/// How far a message's timestamp may be from the aggregator's clock.
+pub const MAX_CLOCK_SKEW: TimeDelta = TimeDelta::seconds(30);
+/// How long a connecting client gets to complete the handshake.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// How many final results the aggregator keeps for [`Aggregator::recent_results`].
+pub const RESULT_HISTORY: usize = 100;
+
+/// What the aggregator remembers about the round it is collecting.
+#[derive(Debug)]
+struct Round {
+    id: u64,
+    /// At most one average per client.
+    averages: BTreeMap<String, f64>,
+    /// Nonces already seen this round, accepted or not.
+    nonces: HashSet<String>,
+    /// The accepted messages, handed to the contributors with the proposal.
+    inputs: Vec<SignedMessage>,
+}
+
+impl Round {
+    fn new(id: u64) -> Self {
+        Self {
+            id,
+            averages: BTreeMap::new(),
+            nonces: HashSet::new(),
+            inputs: Vec::new(),
+        }
+    }
+}
+
+/// A closed round waiting for its contributors to endorse the result.
+#[derive(Debug)]
+struct Pending {
+    result: RoundResult,
+    /// BLS signatures over the result, by signer.
+    signatures: BTreeMap<Signer, String>,
+}
+
+/// A snapshot of what the aggregator is doing, for observers.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Status {
+    /// The round being collected.
+    pub round_id: u64,
+    pub quorum: usize,
+    pub strategy: String,
+    /// Clients with a usable key, whom the open round waits for.
+    pub clients: Vec<String>,
+    /// Clients whose average the open round has.
+    pub submitted: Vec<String>,
+    pub quorum_reached: bool,
+    /// The previous round, if its result is still waiting for endorsements.
+    pub endorsing: Option<Endorsing>,
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct Endorsing {
+    pub round_id: u64,
+    pub contributors: Vec<String>,
+    pub endorsed: Vec<String>,
+}
+
+/// Verifies signed averages from the known clients and aggregates them round
+/// by round: a round closes once every client has submitted or its deadline
+/// passes, and produces an average if at least `quorum` clients took part.
+/// The averages are combined with the configured [`Strategy`], plain mean
+/// unless told otherwise. A successful round is then proposed to its
+/// contributors, and is attested if at least `quorum` of them endorse it.
+/// The client keys can be swapped with [`Aggregator::reload`] while it runs,
+/// and every frame its clients send, accepted or not, can be kept in an
+/// [`AuditLog`].
+pub struct Aggregator {
+    /// Key the aggregator authenticates itself to clients with.
+    identity: SigningKey,
+    keys: RwLock<KeySet>,
+    quorum: usize,
+    strategy: Strategy,
+    round: Mutex<Round>,
+    /// Only ever locked while `round` is held or on its own, never the
+    /// other way round, so [`Aggregator::status`] can hold both.
+    pending: Mutex<Option<Pending>>,
+    /// Woken on every accepted submission and attestation.
+    activity: Notify,
+    proposals: broadcast::Sender<Proposal>,
+    results: broadcast::Sender<RoundResult>,
+    /// The latest final results, oldest first.
+    history: Mutex<VecDeque<RoundResult>>,
+    audit: Option<Arc<AuditLog>>,
+}
+
+impl Aggregator {
+    pub fn new(identity: SigningKey, registry: &Registry, quorum: usize) -> Result<Self, String> {
+        let keys = checked_keys(&identity, registry, quorum)?;
+        Ok(Self {
+            identity,
+            keys: RwLock::new(keys),
+            quorum,
+            strategy: Strategy::Mean,
+            round: Mutex::new(Round::new(1)),
+            pending: Mutex::new(None),
+            activity: Notify::new(),
+            proposals: broadcast::channel(16).0,
+            results: broadcast::channel(16).0,
+            history: Mutex::new(VecDeque::new()),
+            audit: None,
+        })
+    }
+
+    pub fn with_strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Picks up after an earlier run: `results`, oldest first, as read by
+    /// [`load_results`], fill the history, and rounds continue after the
+    /// last of them.
+    pub fn with_history(mut self, results: Vec<RoundResult>) -> Self {
+        if let Some(last) = results.last() {
+            self.round = Mutex::new(Round::new(last.round_id + 1));
+        }
+        let skip = results.len().saturating_sub(RESULT_HISTORY);
+        self.history = Mutex::new(results.into_iter().skip(skip).collect());
+        self
+    }
+
+    pub fn with_audit_log(mut self, audit: AuditLog) -> Self {
+        self.audit = Some(Arc::new(audit));
+        self
+    }
+
+    /// Replaces the client keys with those of `registry`, e.g. after a key
+    /// was rotated or revoked. The current keys stay in place if the new
+    /// registry is unusable.
+    pub fn reload(&self, registry: &Registry) -> Result<(), String> {
+        let keys = checked_keys(&self.identity, registry, self.quorum)?;
+        *self.keys.write().unwrap() = keys;
+        Ok(())
+    }
+
+    pub fn round_id(&self) -> u64 {
+        self.round.lock().unwrap().id
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RoundResult> {
+        self.results.subscribe()
+    }
+
+    pub fn subscribe_proposals(&self) -> broadcast::Receiver<Proposal> {
+        self.proposals.subscribe()
+    }
+
+    /// The open round and the one being endorsed, taken under both locks so
+    /// they never straddle a round closing.
+    pub fn status(&self) -> Status {
+        let (round_id, submitted, endorsing) = {
+            let round = self.round.lock().unwrap();
+            let pending = self.pending.lock().unwrap();
+            let endorsing = pending.as_ref().map(|pending| Endorsing {
+                round_id: pending.result.round_id,
+                contributors: pending.result.contributors.clone(),
+                endorsed: pending.signatures.keys().map(|signer| signer.client_id.clone()).collect(),
+            });
+            (round.id, round.averages.keys().cloned().collect::<Vec<_>>(), endorsing)
+        };
+        let clients = self.keys.read().unwrap().clients(Utc::now()).into_iter().map(str::to_string).collect();
+        Status {
+            round_id,
+            quorum: self.quorum,
+            strategy: self.strategy.to_string(),
+            clients,
+            quorum_reached: submitted.len() >= self.quorum,
+            submitted,
+            endorsing,
+        }
+    }
+
+    /// The last `count` final results, newest first.
+    pub fn recent_results(&self, count: usize) -> Vec<RoundResult> {
+        self.history.lock().unwrap().iter().rev().take(count).cloned().collect()
+    }
+
+    /// Checks the signature, round, freshness and nonce, then records the
+    /// average for the open round.
+    pub fn submit(&self, signed: &SignedMessage) -> Result<(), String> {
+        self.submit_at(signed, Utc::now())
+    }
+
+    fn submit_at(&self, signed: &SignedMessage, now: DateTime<Utc>) -> Result<(), String> {
+        self.decide(signed, now).1
+    }
+
+    /// Decides on a submission, returning the round it was decided in for
+    /// the audit log along with the verdict.
+    fn decide(&self, signed: &SignedMessage, now: DateTime<Utc>) -> (u64, Result<(), String>) {
+        let mut round = self.round.lock().unwrap();
+        let verdict = self.accept(&mut round, signed, now);
+        (round.id, verdict)
+    }
+
+    /// Records a frame from `peer` and its verdict in the audit log, if
+    /// there is one. The write and its sync run off the async workers and
+    /// outside every lock, so a slow disk holds up only this connection.
+    async fn audit(&self, received_at: DateTime<Utc>, round_id: u64, peer: &str, frame: Bytes, verdict: Result<(), String>) {
+        let Some(audit) = self.audit.clone() else {
+            return;
+        };
+        let peer = peer.to_string();
+        let appended = tokio::task::spawn_blocking(move || audit.append(received_at, round_id, &peer, &frame, &verdict))
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|appended| appended);
+        if let Err(e) = appended {
+            println!("Error:: cannot write to the audit log: {}", e);
+        }
+    }
+
+    fn accept(&self, round: &mut Round, signed: &SignedMessage, now: DateTime<Utc>) -> Result<(), String> {
+        let verifying_key = self.keys.read().unwrap().key(&signed.key_id, &signed.client_id, now)?.verifying_key;
+        signed.verify(&verifying_key)?;
+        let average = signed.average_value()?;
+        let signed_at = DateTime::from_timestamp_millis(signed.timestamp).ok_or("timestamp out of range")?;
+        if (now - signed_at).abs() > MAX_CLOCK_SKEW {
+            return Err(format!("stale message signed at {}", signed_at.to_rfc3339()));
+        }
+
+        if signed.round_id != round.id {
+            return Err(format!("message is for round {} but round {} is open", signed.round_id, round.id));
+        }
+        if !round.nonces.insert(signed.nonce.clone()) {
+            return Err(format!("replayed message from {}", signed.client_id));
+        }
+        if round.averages.contains_key(&signed.client_id) {
+            return Err(format!("{} already submitted in round {}", signed.client_id, round.id));
+        }
+        println!("Received avg price of BTC: {} from {} for round {}", signed.average, signed.client_id, round.id);
+        round.averages.insert(signed.client_id.clone(), average);
+        round.inputs.push(signed.clone());
+        self.activity.notify_one();
+        Ok(())
+    }
+
+    /// Records a contributor's endorsement of the result awaiting attestation,
+    /// made with a key that was valid when the round closed.
+    pub fn attest(&self, round_id: u64, client_id: &str, key_id: &str, signature: &str) -> Result<(), String> {
+        let mut pending = self.pending.lock().unwrap();
+        let pending = pending
+            .as_mut()
+            .filter(|pending| pending.result.round_id == round_id)
+            .ok_or_else(|| format!("round {} is not awaiting attestation", round_id))?;
+        if !pending.result.contributors.iter().any(|contributor| contributor == client_id) {
+            return Err(format!("{} did not contribute to round {}", client_id, round_id));
+        }
+        if pending.signatures.keys().any(|signer| signer.client_id == client_id) {
+            return Err(format!("{} already attested round {}", client_id, round_id));
+        }
+        let public_key = self.keys.read().unwrap().key(key_id, client_id, pending.result.closed_at)?.bls_key;
+        attestation::check_signature(&pending.result, signature, &public_key)?;
+        println!("Received attestation of round {} from {}", round_id, client_id);
+        let signer = Signer {
+            client_id: client_id.to_string(),
+            key_id: key_id.to_string(),
+        };
+        pending.signatures.insert(signer, signature.to_string());
+        self.activity.notify_one();
+        Ok(())
+    }
+
+    /// Closes the open round and opens the next one. A result with an
+    /// average starts waiting for endorsements before the next round is
+    /// visible.
+    fn close_round(&self) -> Proposal {
+        let mut round = self.round.lock().unwrap();
+        let next = Round::new(round.id + 1);
+        let closed = std::mem::replace(&mut *round, next);
+        let aggregate = (closed.averages.len() >= self.quorum)
+            .then(|| robust::aggregate(self.strategy, &closed.averages))
+            .flatten();
+        let (average, excluded) = match aggregate {
+            Some(aggregate) => (Some(aggregate.value), aggregate.excluded),
+            None => (None, Vec::new()),
+        };
+        let result = RoundResult {
+            round_id: closed.id,
+            closed_at: Utc::now(),
+            contributors: closed.averages.into_keys().collect(),
+            average,
+            strategy: self.strategy.to_string(),
+            excluded,
+            audit_head: self.audit.as_ref().map(|audit| audit.head()),
+            attestation: None,
+        };
+        if result.average.is_some() {
+            *self.pending.lock().unwrap() = Some(Pending {
+                result: result.clone(),
+                signatures: BTreeMap::new(),
+            });
+        }
+        Proposal { result, inputs: closed.inputs }
+    }
+
+    fn submissions(&self) -> usize {
+        self.round.lock().unwrap().averages.len()
+    }
+
+    fn active_clients(&self) -> usize {
+        self.keys.read().unwrap().clients(Utc::now()).len()
+    }
+
+    fn attestations(&self) -> usize {
+        self.pending.lock().unwrap().as_ref().map_or(0, |pending| pending.signatures.len())
+    }
+
+    /// Proposes the result, already pending since [`Aggregator::close_round`],
+    /// to the contributors and waits until all of them endorsed it or
+    /// `deadline` passes. The result is attested if at least the quorum did.
+    async fn endorse(&self, proposal: Proposal, deadline: Duration) -> RoundResult {
+        let contributors = proposal.result.contributors.len();
+        let _ = self.proposals.send(proposal);
+        let closes_at = tokio::time::Instant::now() + deadline;
+        while self.attestations() < contributors {
+            if tokio::time::timeout_at(closes_at, self.activity.notified()).await.is_err() {
+                break;
+            }
+        }
+        let Pending { mut result, signatures } = self.pending.lock().unwrap().take().expect("pending round");
+        if signatures.len() >= self.quorum {
+            match attestation::combine(&signatures) {
+                Ok(attestation) => result.attestation = Some(attestation),
+                Err(e) => println!("Error:: cannot attest round {}: {}", result.round_id, e),
+            }
+        }
+        result
+    }
+
+    /// Drives the rounds forever. A round's deadline starts with its first
+    /// submission, so an idle aggregator does not burn through empty rounds;
+    /// endorsing its result gets the same deadline again. Each result is
+    /// appended to `results_path` as a JSON line, kept for
+    /// [`Aggregator::recent_results`] and broadcast to every connected client.
+    pub async fn run_rounds(&self, deadline: Duration, results_path: Option<&Path>) {
+        loop {
+            while self.submissions() == 0 {
+                self.activity.notified().await;
+            }
+            let closes_at = tokio::time::Instant::now() + deadline;
+            while self.submissions() < self.active_clients() {
+                if tokio::time::timeout_at(closes_at, self.activity.notified()).await.is_err() {
+                    break;
+                }
+            }
+            let proposal = self.close_round();
+            let result = match proposal.result.average {
+                Some(_) => self.endorse(proposal, deadline).await,
+                None => proposal.result,
+            };
+            match result.average {
+                Some(average) => {
+                    println!(
+                        "Final Aggregated Average for round {} from {} clients ({}): {}",
+                        result.round_id,
+                        result.contributors.len() - result.excluded.len(),
+                        result.strategy,
+                        average
+                    );
+                    for exclusion in &result.excluded {
+                        println!("  excluded {} ({}): {}", exclusion.client_id, exclusion.average, exclusion.reason);
+                    }
+                    match &result.attestation {
+                        Some(attestation) => println!("  attested by {}", attestation.signer_ids().join(", ")),
+                        None => println!("  ❌ not attested: fewer than {} contributors endorsed it", self.quorum),
+                    }
+                }
+                None => println!(
+                    "❌ Round {} failed: {} of the {} required clients submitted",
+                    result.round_id,
+                    result.contributors.len(),
+                    self.quorum
+                ),
+            }
+            if let Some(path) = results_path
+                && let Err(e) = persist(path, &result)
+            {
+                println!("Error:: cannot write round result to {}: {}", path.display(), e);
+            }
+            {
+                let mut history = self.history.lock().unwrap();
+                if history.len() == RESULT_HISTORY {
+                    history.pop_front();
+                }
+                history.push_back(result.clone());
+            }
+            let _ = self.results.send(result);
+        }
+    }
+
+    /// Serves one client connection: the handshake, which establishes who
+    /// the client is, a `Hello` from that client answered with the open
+    /// round, then signed averages and attestations, each answered with
+    /// `Ack` or `Error`, plus every proposal and round result as it is
+    /// decided. A framing error is reported to the client and ends the
+    /// connection. Every frame is written to the audit log, if there is one,
+    /// before it is answered.
+    pub async fn handle_connection<S>(&self, stream: S)
+    where
+        S: AsyncRead + AsyncWrite + Unpin,
+    {
+        let handshake = transport::accept(stream, &self.identity, |static_public| {
+            let keys = self.keys.read().unwrap();
+            keys.by_static(static_public, Utc::now()).map(|record| record.client_id.clone())
+        });
+        let (mut framed, peer) = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(e)) => {
+                println!("❌ Dropping connection: {}", e);
+                return;
+            }
+            Err(_) => {
+                println!("❌ Dropping connection: handshake timed out");
+                return;
+            }
+        };
+        framed.codec_mut().set_max_frame_length(protocol::max_frame_length(self.active_clients()));
+        let mut results = self.subscribe();
+        let mut proposals = self.subscribe_proposals();
+        let mut client_id: Option<String> = None;
+        loop {
+            let frame = tokio::select! {
+                frame = framed.next() => frame,
+                proposal = proposals.recv() => {
+                    match proposal {
+                        Ok(proposal) => {
+                            if framed.send(Message::Proposal(proposal)).await.is_err() {
+                                return;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    }
+                    continue;
+                }
+                result = results.recv() => {
+                    match result {
+                        Ok(result) => {
+                            if framed.send(Message::RoundResult(result)).await.is_err() {
+                                return;
+                            }
+                        }
+                        Err(broadcast::error::RecvError::Lagged(_)) => {}
+                        Err(broadcast::error::RecvError::Closed) => return,
+                    }
+                    continue;
+                }
+            };
+            let received_at = Utc::now();
+            let raw = framed.codec_mut().take_last_frame().unwrap_or_default();
+            let message = match frame {
+                Some(Ok(message)) => message,
+                Some(Err(e)) => {
+                    println!("❌ Dropping connection: {}", e);
+                    self.audit(received_at, self.round_id(), &peer, raw, Err(e.to_string())).await;
+                    let _ = framed.send(Message::Error { reason: e.to_string() }).await;
+                    return;
+                }
+                None => return,
+            };
+            let mut round_id = self.round_id();
+            let reply = match message {
+                Message::Hello { client_id: id } if id == peer => {
+                    client_id = Some(id);
+                    Ok(Message::Welcome { round_id: self.round_id() })
+                }
+                Message::Hello { client_id: id } => Err(format!("connection is authenticated as {}, not {}", peer, id)),
+                Message::SignedAverage(signed) if client_id.as_ref() != Some(&signed.client_id) => {
+                    Err(format!("{} did not say hello on this connection", signed.client_id))
+                }
+                Message::SignedAverage(signed) => {
+                    let (decided_in, verdict) = self.decide(&signed, received_at);
+                    round_id = decided_in;
+                    verdict.map(|_| Message::Ack)
+                }
+                Message::Attest { client_id: id, .. } if client_id.as_ref() != Some(&id) => {
+                    Err(format!("{} did not say hello on this connection", id))
+                }
+                Message::Attest { round_id, client_id: id, key_id, signature } => {
+                    self.attest(round_id, &id, &key_id, &signature).map(|_| Message::Ack)
+                }
+                other => Err(format!("unexpected {:?} from a client", other)),
+            };
+            let verdict = reply.as_ref().map(|_| ()).map_err(String::clone);
+            self.audit(received_at, round_id, &peer, raw, verdict).await;
+            let reply = match reply {
+                Ok(reply) => reply,
+                Err(reason) => {
+                    println!("❌ Rejected message: {}", reason);
+                    Message::Error { reason }
+                }
+            };
+            if framed.send(reply).await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+/// The client keys of `registry`, provided it names `identity` as the
+/// aggregator and has enough clients for `quorum`.
+fn checked_keys(identity: &SigningKey, registry: &Registry, quorum: usize) -> Result<KeySet, String> {
+    if identity.verifying_key() != registry.aggregator_key()? {
+        return Err("the aggregator key does not match the one in the registry".to_string());
+    }
+    let keys = KeySet::new(registry)?;
+    let clients: BTreeSet<&str> = registry.clients.iter().map(|entry| entry.client_id.as_str()).collect();
+    if quorum == 0 || quorum > clients.len() {
+        return Err(format!("quorum must be between 1 and {}, got {}", clients.len(), quorum));
+    }
+    Ok(keys)
+}
+
+/// The results [`Aggregator::run_rounds`] appended to `path`, oldest first;
+/// none if there is no such file yet. A last line cut short by a crash is
+/// left out.
+pub fn load_results(path: &Path) -> Result<Vec<RoundResult>, String> {
+    let data = match std::fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(format!("cannot read {}: {}", path.display(), e)),
+    };
+    let lines: Vec<&str> = data.lines().filter(|line| !line.trim().is_empty()).collect();
+    let mut results = Vec::new();
+    for (i, line) in lines.iter().enumerate() {
+        match serde_json::from_str(line) {
+            Ok(result) => results.push(result),
+            Err(_) if i + 1 == lines.len() && !data.ends_with('\n') => {}
+            Err(e) => return Err(format!("invalid round result in {}: {}", path.display(), e)),
+        }
+    }
+    Ok(results)
+}
+
+fn persist(path: &Path, result: &RoundResult) -> std::io::Result<()> {
+    let mut file = OpenOptions::new().create(true).append(true).open(path)?;
+    writeln!(file, "{}", serde_json::to_string(result)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::audit;
+    use crate::keys::{self, RegistryEntry, Revocation};
+    use crate::protocol::{self, MAX_FRAME_LENGTH, MessageCodec, ProtocolError};
+    use std::sync::Arc;
+    use tokio::io::{AsyncWriteExt, DuplexStream};
+    use tokio_util::codec::Framed;
+
+    fn aggregator_key() -> SigningKey {
+        SigningKey::from_bytes(&[0xa0; 32])
+    }
+
+    fn key_data(id: usize) -> (RegistryEntry, SigningKey) {
+        let signing_key = SigningKey::from_bytes(&[id as u8; 32]);
+        let entry = RegistryEntry::new(&format!("client{}", id), &signing_key, Utc::now() - TimeDelta::hours(1));
+        (entry, signing_key)
+    }
+
+    fn registry(clients: Vec<RegistryEntry>) -> Registry {
+        Registry {
+            aggregator: hex::encode(aggregator_key().verifying_key().to_bytes()),
+            clients,
+            revoked: vec![],
+        }
+    }
+
+    fn signer_key_id(signing_key: &SigningKey) -> String {
+        keys::key_id(&signing_key.verifying_key())
+    }
+
+    fn cluster(clients: usize, quorum: usize) -> (Arc<Aggregator>, Vec<SigningKey>) {
+        cluster_with(clients, quorum, Strategy::Mean)
+    }
+
+    fn cluster_with(clients: usize, quorum: usize, strategy: Strategy) -> (Arc<Aggregator>, Vec<SigningKey>) {
+        let (keys, signing_keys): (Vec<_>, Vec<_>) = (1..=clients).map(key_data).unzip();
+        let aggregator = Aggregator::new(aggregator_key(), &registry(keys), quorum).unwrap();
+        (Arc::new(aggregator.with_strategy(strategy)), signing_keys)
+    }
+
+    /// Endorses every proposal on behalf of the contributors among the given
+    /// clients, as honest clients would.
+    fn endorse_proposals(aggregator: &Arc<Aggregator>, signing_keys: Vec<SigningKey>) {
+        let mut proposals = aggregator.subscribe_proposals();
+        let aggregator = aggregator.clone();
+        tokio::spawn(async move {
+            while let Ok(proposal) = proposals.recv().await {
+                for (i, signing_key) in signing_keys.iter().enumerate() {
+                    let client_id = format!("client{}", i + 1);
+                    if proposal.result.contributors.contains(&client_id) {
+                        let signature = attestation::sign(&proposal.result, &attestation::secret_key(signing_key));
+                        let key_id = signer_key_id(signing_key);
+                        aggregator.attest(proposal.result.round_id, &client_id, &key_id, &signature).unwrap();
+                    }
+                }
+            }
+        });
+    }
+
+    async fn connect(aggregator: &Arc<Aggregator>, identity: &SigningKey) -> Result<Framed<DuplexStream, MessageCodec>, ProtocolError> {
+        let (client, server) = tokio::io::duplex(1024);
+        let aggregator = aggregator.clone();
+        tokio::spawn(async move { aggregator.handle_connection(server).await });
+        transport::connect(client, identity, &aggregator_key().verifying_key()).await
+    }
+
+    #[tokio::test]
+    async fn test_accepts_signed_averages_and_rejects_forgeries() {
+        let (aggregator, signing_keys) = cluster(2, 2);
+        let mut framed = connect(&aggregator, &signing_keys[0]).await.unwrap();
+
+        let round_id = protocol::hello(&mut framed, "client1").await.unwrap();
+        assert_eq!(round_id, 1);
+        let signed = SignedMessage::sign("client1", 100.5, round_id, &signing_keys[0]);
+        protocol::request(&mut framed, Message::SignedAverage(signed.clone())).await.unwrap();
+
+        let mut forged = signed;
+        forged.average = "1.0".to_string();
+        let result = protocol::request(&mut framed, Message::SignedAverage(forged)).await;
+        assert!(matches!(result, Err(ProtocolError::Rejected(reason)) if reason == "invalid signature"));
+        assert_eq!(aggregator.round.lock().unwrap().averages.values().collect::<Vec<_>>(), vec![&100.5]);
+    }
+
+    #[tokio::test]
+    async fn test_requires_hello_from_the_submitting_client() {
+        let (aggregator, signing_keys) = cluster(1, 1);
+        let mut framed = connect(&aggregator, &signing_keys[0]).await.unwrap();
+        let signed = SignedMessage::sign("client1", 100.5, 1, &signing_keys[0]);
+        let result = protocol::request(&mut framed, Message::SignedAverage(signed)).await;
+        assert!(matches!(result, Err(ProtocolError::Rejected(_))));
+
+        let result = protocol::hello(&mut framed, "client9").await;
+        assert!(matches!(
+            result,
+            Err(ProtocolError::Rejected(reason)) if reason == "connection is authenticated as client1, not client9"
+        ));
+    }
+
+    #[tokio::test]
+    async fn test_only_registered_clients_get_through_the_handshake() {
+        let (aggregator, _) = cluster(1, 1);
+        let stranger = SigningKey::from_bytes(&[9; 32]);
+        assert!(connect(&aggregator, &stranger).await.is_err());
+
+        let registry = Registry {
+            aggregator: hex::encode(stranger.verifying_key().to_bytes()),
+            ..registry(vec![key_data(1).0])
+        };
+        let result = Aggregator::new(aggregator_key(), &registry, 1);
+        assert_eq!(result.err(), Some("the aggregator key does not match the one in the registry".to_string()));
+    }
+
+    #[test]
+    fn test_rejects_replays_and_second_submissions() {
+        let (aggregator, signing_keys) = cluster(2, 2);
+        let signed = SignedMessage::sign("client1", 100.0, 1, &signing_keys[0]);
+        assert_eq!(aggregator.submit(&signed), Ok(()));
+        assert_eq!(aggregator.submit(&signed), Err("replayed message from client1".to_string()));
+
+        let again = SignedMessage::sign("client1", 101.0, 1, &signing_keys[0]);
+        assert_eq!(aggregator.submit(&again), Err("client1 already submitted in round 1".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_every_frame_is_audited() {
+        let path = std::env::temp_dir().join(format!("audit-aggregator-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (keys, signing_keys): (Vec<_>, Vec<_>) = (1..=2).map(key_data).unzip();
+        let aggregator = Aggregator::new(aggregator_key(), &registry(keys), 2)
+            .unwrap()
+            .with_audit_log(AuditLog::open(&path, aggregator_key()).unwrap());
+        let aggregator = Arc::new(aggregator);
+
+        let mut framed = connect(&aggregator, &signing_keys[0]).await.unwrap();
+        let signed = SignedMessage::sign("client1", 100.0, 1, &signing_keys[0]);
+        protocol::request(&mut framed, Message::SignedAverage(signed.clone())).await.unwrap_err();
+        protocol::hello(&mut framed, "client1").await.unwrap();
+        protocol::request(&mut framed, Message::SignedAverage(signed.clone())).await.unwrap();
+        protocol::request(&mut framed, Message::SignedAverage(signed)).await.unwrap_err();
+
+        let mut framed = connect(&aggregator, &signing_keys[1]).await.unwrap();
+        protocol::hello(&mut framed, "client2").await.unwrap();
+        let mut forged = SignedMessage::sign("client2", 100.0, 1, &signing_keys[1]);
+        forged.average = "1".to_string();
+        protocol::request(&mut framed, Message::SignedAverage(forged)).await.unwrap_err();
+        // A frame that does not decrypt is logged as it came in.
+        framed.get_mut().write_all(&[0, 0, 0, 4, 1, 2, 3, 4]).await.unwrap();
+        assert!(matches!(framed.next().await, Some(Ok(Message::Error { .. }))));
+        assert!(framed.next().await.is_none());
+
+        let report = audit::verify(&path, &aggregator.keys.read().unwrap(), &aggregator_key().verifying_key()).unwrap();
+        assert_eq!(report.problems, Vec::<String>::new());
+        assert_eq!((report.entries, report.accepted, report.rejected, report.rejected_but_signed), (7, 3, 4, 2));
+        let last: audit::AuditEntry = serde_json::from_str(std::fs::read_to_string(&path).unwrap().lines().last().unwrap()).unwrap();
+        assert_eq!((last.peer.as_str(), last.frame.as_str()), ("client2", "01020304"));
+        // The round publishes the log as it stood when the round closed.
+        let head = aggregator.close_round().result.audit_head.unwrap();
+        assert!(report.hashes.contains(&head));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_stale_and_wrong_round_messages() {
+        let (aggregator, signing_keys) = cluster(1, 1);
+        let now = Utc::now();
+        let stale = SignedMessage::sign_at("client1", 100.0, 1, now - TimeDelta::minutes(5), &signing_keys[0]);
+        assert!(aggregator.submit_at(&stale, now).unwrap_err().starts_with("stale message"));
+        let future = SignedMessage::sign_at("client1", 100.0, 1, now + TimeDelta::minutes(5), &signing_keys[0]);
+        assert!(aggregator.submit_at(&future, now).unwrap_err().starts_with("stale message"));
+
+        let wrong_round = SignedMessage::sign_at("client1", 100.0, 7, now, &signing_keys[0]);
+        assert_eq!(
+            aggregator.submit_at(&wrong_round, now),
+            Err("message is for round 7 but round 1 is open".to_string())
+        );
+        let fresh = SignedMessage::sign_at("client1", 100.0, 1, now - TimeDelta::seconds(5), &signing_keys[0]);
+        assert_eq!(aggregator.submit_at(&fresh, now), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_round_closes_early_when_everyone_submitted() {
+        let (aggregator, signing_keys) = cluster(2, 2);
+        let mut results = aggregator.subscribe();
+        endorse_proposals(&aggregator, signing_keys.clone());
+        let driver = aggregator.clone();
+        tokio::spawn(async move { driver.run_rounds(Duration::from_secs(3600), None).await });
+        aggregator.submit(&SignedMessage::sign("client1", 100.0, 1, &signing_keys[0])).unwrap();
+        aggregator.submit(&SignedMessage::sign("client2", 102.0, 1, &signing_keys[1])).unwrap();
+
+        let result = results.recv().await.unwrap();
+        assert_eq!(result.round_id, 1);
+        assert_eq!(result.average, Some(101.0));
+        assert_eq!(result.contributors, vec!["client1", "client2"]);
+        assert_eq!(result.attestation.unwrap().signer_ids(), vec!["client1", "client2"]);
+        assert_eq!(aggregator.round_id(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_deadline_applies_quorum_and_moves_on() {
+        let (aggregator, signing_keys) = cluster(3, 2);
+        let path = std::env::temp_dir().join(format!("rounds-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut results = aggregator.subscribe();
+        let driver = aggregator.clone();
+        let driver_path = path.clone();
+        tokio::spawn(async move { driver.run_rounds(Duration::from_millis(50), Some(&driver_path)).await });
+
+        // Round 1: two of three is enough.
+        aggregator.submit(&SignedMessage::sign("client1", 100.0, 1, &signing_keys[0])).unwrap();
+        aggregator.submit(&SignedMessage::sign("client3", 104.0, 1, &signing_keys[2])).unwrap();
+        let first = results.recv().await.unwrap();
+        assert_eq!(first.average, Some(102.0));
+        // Nobody endorsed it before the deadline.
+        assert_eq!(first.attestation, None);
+
+        // Round 2: one of three is not, and a late round 1 message is refused.
+        let late = SignedMessage::sign("client2", 99.0, 1, &signing_keys[1]);
+        assert!(aggregator.submit(&late).is_err());
+        aggregator.submit(&SignedMessage::sign("client2", 99.0, 2, &signing_keys[1])).unwrap();
+        let second = results.recv().await.unwrap();
+        assert_eq!((second.round_id, second.average), (2, None));
+
+        let persisted: Vec<RoundResult> = std::fs::read_to_string(&path)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(persisted, vec![first, second]);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_malicious_clients_are_excluded() {
+        // Clients 5 and 6 hold valid keys but report a $1 and a $1M bitcoin.
+        let reports = [100_010.0, 99_990.0, 100_000.0, 100_020.0, 1.0, 1_000_000.0];
+        for (strategy, expected, excluded) in [
+            (Strategy::Median, 100_005.0, vec![]),
+            (Strategy::TrimmedMean(0.2), 100_005.0, vec!["client5", "client6"]),
+            (Strategy::Mad(3.5), 100_005.0, vec!["client5", "client6"]),
+        ] {
+            let (aggregator, signing_keys) = cluster_with(reports.len(), reports.len(), strategy);
+            for (i, (report, signing_key)) in reports.iter().zip(&signing_keys).enumerate() {
+                let signed = SignedMessage::sign(&format!("client{}", i + 1), *report, 1, signing_key);
+                aggregator.submit(&signed).unwrap();
+            }
+            let result = aggregator.close_round().result;
+            assert_eq!(result.average, Some(expected), "{}", strategy);
+            assert_eq!(result.strategy, strategy.to_string());
+            assert_eq!(result.contributors.len(), reports.len());
+            let mut ids: Vec<&str> = result.excluded.iter().map(|exclusion| exclusion.client_id.as_str()).collect();
+            ids.sort();
+            assert_eq!(ids, excluded, "{}", strategy);
+            assert!(result.excluded.iter().all(|exclusion| !exclusion.reason.is_empty()));
+        }
+    }
+
+    #[test]
+    fn test_malicious_non_finite_averages_are_rejected() {
+        // A validly signed "NaN" or "inf" would turn every strategy's result
+        // into NaN, which no honest client could endorse.
+        for strategy in [Strategy::Mean, Strategy::TrimmedMean(0.2), Strategy::Mad(3.5)] {
+            let (aggregator, signing_keys) = cluster_with(5, 3, strategy);
+            for (i, report) in [100_010.0, 99_990.0, 100_000.0].into_iter().enumerate() {
+                let signed = SignedMessage::sign(&format!("client{}", i + 1), report, 1, &signing_keys[i]);
+                aggregator.submit(&signed).unwrap();
+            }
+            let nan = SignedMessage::sign("client4", f64::NAN, 1, &signing_keys[3]);
+            assert_eq!(nan.average, "NaN");
+            assert_eq!(aggregator.submit(&nan), Err("average 'NaN' is not a finite number".to_string()));
+            let infinite = SignedMessage::sign("client5", f64::INFINITY, 1, &signing_keys[4]);
+            assert_eq!(aggregator.submit(&infinite), Err("average 'inf' is not a finite number".to_string()));
+
+            let result = aggregator.close_round().result;
+            assert_eq!(result.average, Some(100_000.0), "{}", strategy);
+            assert_eq!(result.contributors, vec!["client1", "client2", "client3"]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_results_are_endorsed_and_broadcast_to_connected_clients() {
+        let (aggregator, signing_keys) = cluster(1, 1);
+        let driver = aggregator.clone();
+        tokio::spawn(async move { driver.run_rounds(Duration::from_secs(3600), None).await });
+        let mut framed = connect(&aggregator, &signing_keys[0]).await.unwrap();
+        let round_id = protocol::hello(&mut framed, "client1").await.unwrap();
+        let signed = SignedMessage::sign("client1", 100.0, round_id, &signing_keys[0]);
+        protocol::request(&mut framed, Message::SignedAverage(signed.clone())).await.unwrap();
+
+        let proposal = match protocol::round_update(&mut framed, round_id).await.unwrap() {
+            protocol::RoundUpdate::Proposal(proposal) => proposal,
+            other => panic!("expected a proposal, got {:?}", other),
+        };
+        let keys = aggregator.keys.read().unwrap().clone();
+        attestation::check_proposal(&proposal, &signed, &keys).unwrap();
+        let signature = attestation::sign(&proposal.result, &attestation::secret_key(&signing_keys[0]));
+        let attest = Message::Attest {
+            round_id,
+            client_id: "client1".to_string(),
+            key_id: signer_key_id(&signing_keys[0]),
+            signature,
+        };
+        protocol::request(&mut framed, attest).await.unwrap();
+
+        let result = protocol::round_result(&mut framed, round_id).await.unwrap();
+        assert_eq!(result.average, Some(100.0));
+        assert_eq!(attestation::verify(&result, &keys, 1), Ok(()));
+    }
+
+    #[test]
+    fn test_status_never_straddles_a_closing_round() {
+        let (aggregator, signing_keys) = cluster(3, 2);
+        aggregator.submit(&SignedMessage::sign("client1", 100.0, 1, &signing_keys[0])).unwrap();
+        aggregator.submit(&SignedMessage::sign("client2", 101.0, 1, &signing_keys[1])).unwrap();
+        assert_eq!(aggregator.status().endorsing, None);
+        aggregator.close_round();
+        // Round 2 is open and round 1 is already awaiting endorsements,
+        // before anyone has been sent the proposal.
+        let status = aggregator.status();
+        assert_eq!((status.round_id, status.submitted.len()), (2, 0));
+        assert_eq!(
+            status.endorsing,
+            Some(Endorsing { round_id: 1, contributors: vec!["client1".to_string(), "client2".to_string()], endorsed: vec![] })
+        );
+
+        // Once round 1 is done, a round that failed its quorum has nothing
+        // to endorse.
+        aggregator.pending.lock().unwrap().take();
+        aggregator.submit(&SignedMessage::sign("client3", 102.0, 2, &signing_keys[2])).unwrap();
+        assert_eq!(aggregator.close_round().result.average, None);
+        assert_eq!(aggregator.status().endorsing, None);
+    }
+
+    #[test]
+    fn test_history_and_round_numbers_carry_over_from_the_results_file() {
+        let path = std::env::temp_dir().join(format!("rounds-history-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let (aggregator, signing_keys) = cluster(1, 1);
+        for round_id in 1..=RESULT_HISTORY as u64 + 2 {
+            aggregator.submit(&SignedMessage::sign("client1", round_id as f64, round_id, &signing_keys[0])).unwrap();
+            persist(&path, &aggregator.close_round().result).unwrap();
+        }
+
+        let (entries, _) = key_data(1);
+        let restarted = Aggregator::new(aggregator_key(), &registry(vec![entries]), 1)
+            .unwrap()
+            .with_history(load_results(&path).unwrap());
+        let last = RESULT_HISTORY as u64 + 2;
+        assert_eq!(restarted.round_id(), last + 1);
+        let recent = restarted.recent_results(RESULT_HISTORY + 10);
+        assert_eq!(recent.len(), RESULT_HISTORY);
+        assert_eq!((recent[0].round_id, recent[RESULT_HISTORY - 1].round_id), (last, 3));
+        assert_eq!(restarted.status().round_id, last + 1);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_rejects_bad_attestations() {
+        let (aggregator, signing_keys) = cluster(3, 2);
+        aggregator.submit(&SignedMessage::sign("client1", 100.0, 1, &signing_keys[0])).unwrap();
+        aggregator.submit(&SignedMessage::sign("client2", 101.0, 1, &signing_keys[1])).unwrap();
+        let result = aggregator.close_round().result;
+        let signature = |i: usize| attestation::sign(&result, &attestation::secret_key(&signing_keys[i]));
+        let key_id = |i: usize| signer_key_id(&signing_keys[i]);
+
+        assert_eq!(
+            aggregator.attest(2, "client1", &key_id(0), &signature(0)),
+            Err("round 2 is not awaiting attestation".to_string())
+        );
+        assert_eq!(
+            aggregator.attest(1, "client3", &key_id(2), &signature(2)),
+            Err("client3 did not contribute to round 1".to_string())
+        );
+        assert_eq!(
+            aggregator.attest(1, "client1", &key_id(0), &signature(1)),
+            Err("invalid attestation signature".to_string())
+        );
+        assert_eq!(
+            aggregator.attest(1, "client1", &key_id(1), &signature(1)),
+            Err(format!("key {} belongs to client2, not client1", key_id(1)))
+        );
+        assert_eq!(aggregator.attest(1, "client1", &key_id(0), &signature(0)), Ok(()));
+        assert_eq!(
+            aggregator.attest(1, "client1", &key_id(0), &signature(0)),
+            Err("client1 already attested round 1".to_string())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_reload_rotates_and_revokes_keys_without_restarting() {
+        let (aggregator, signing_keys) = cluster(2, 1);
+        let (mut entries, _): (Vec<_>, Vec<_>) = (1..=2).map(key_data).unzip();
+        let rotated = SigningKey::from_bytes(&[11; 32]);
+        entries[0].not_after = Some(Utc::now());
+        entries.push(RegistryEntry::new("client1", &rotated, Utc::now() - TimeDelta::seconds(1)));
+        let mut rotation = registry(entries);
+        rotation.revoked.push(Revocation {
+            key_id: signer_key_id(&signing_keys[1]),
+            revoked_at: Utc::now(),
+            reason: "compromised".to_string(),
+        });
+        aggregator.reload(&rotation).unwrap();
+
+        let old = SignedMessage::sign("client1", 100.0, 1, &signing_keys[0]);
+        assert!(aggregator.submit(&old).unwrap_err().contains("expired"));
+        assert!(connect(&aggregator, &signing_keys[1]).await.is_err());
+        let mut framed = connect(&aggregator, &rotated).await.unwrap();
+        let round_id = protocol::hello(&mut framed, "client1").await.unwrap();
+        let signed = SignedMessage::sign("client1", 100.0, round_id, &rotated);
+        protocol::request(&mut framed, Message::SignedAverage(signed)).await.unwrap();
+
+        // A registry the aggregator cannot run with leaves the keys alone.
+        let unusable = Registry { clients: vec![], ..rotation };
+        assert!(aggregator.reload(&unusable).is_err());
+        assert_eq!(aggregator.active_clients(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_oversized_write_gets_an_error_reply() {
+        let (aggregator, signing_keys) = cluster(1, 1);
+        let mut framed = connect(&aggregator, &signing_keys[0]).await.unwrap();
+        let client = framed.get_mut();
+        client.write_all(&((MAX_FRAME_LENGTH as u32 * 4).to_be_bytes())).await.unwrap();
+        client.write_all(&vec![b'x'; 512]).await.unwrap();
+        match framed.next().await {
+            Some(Ok(Message::Error { reason })) => assert!(reason.contains("byte limit")),
+            other => panic!("expected an error reply, got {:?}", other),
+        }
+        assert!(framed.next().await.is_none());
+    }
+}
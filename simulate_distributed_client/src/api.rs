@@ -0,0 +1,147 @@
+use crate::aggregator::{Aggregator, Status};
+use crate::protocol::RoundResult;
+use axum::extract::ws::{self, WebSocket, WebSocketUpgrade};
+use axum::extract::{Query, State};
+use axum::response::Response;
+use axum::routing::get;
+use axum::{Json, Router};
+use serde::Deserialize;
+use std::sync::Arc;
+use tokio::net::TcpListener;
+use tokio::sync::broadcast;
+
+/// Results `GET /results` returns when not asked for a number.
+const DEFAULT_RESULTS: usize = 10;
+
+#[derive(Debug, Deserialize)]
+struct ResultsParams {
+    last: Option<usize>,
+}
+
+/// `GET /status`: the open round, who submitted to it, whether it has a
+/// quorum yet and the round being endorsed, if any.
+async fn status(State(aggregator): State<Arc<Aggregator>>) -> Json<Status> {
+    Json(aggregator.status())
+}
+
+/// `GET /results?last=N`: the latest final results with their attestations,
+/// newest first.
+async fn results(State(aggregator): State<Arc<Aggregator>>, Query(params): Query<ResultsParams>) -> Json<Vec<RoundResult>> {
+    Json(aggregator.recent_results(params.last.unwrap_or(DEFAULT_RESULTS)))
+}
+
+/// `GET /results/ws`: a websocket that gets every final result as a JSON
+/// text message as soon as its round completes.
+async fn results_ws(State(aggregator): State<Arc<Aggregator>>, upgrade: WebSocketUpgrade) -> Response {
+    let results = aggregator.subscribe();
+    upgrade.on_upgrade(move |socket| push_results(socket, results))
+}
+
+async fn push_results(mut socket: WebSocket, mut results: broadcast::Receiver<RoundResult>) {
+    loop {
+        tokio::select! {
+            result = results.recv() => {
+                let result = match result {
+                    Ok(result) => result,
+                    // A lagging subscriber just skips the results it missed.
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    Err(broadcast::error::RecvError::Closed) => return,
+                };
+                let json = serde_json::to_string(&result).expect("round results serialize");
+                if socket.send(ws::Message::Text(json.into())).await.is_err() {
+                    return;
+                }
+            }
+            // Nothing is expected from the dashboard; this only notices it leaving.
+            message = socket.recv() => {
+                if !matches!(message, Some(Ok(_))) {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Serves the aggregator's status API on `listener` until it fails.
+pub async fn serve(listener: TcpListener, aggregator: Arc<Aggregator>) -> std::io::Result<()> {
+    let app = Router::new()
+        .route("/status", get(status))
+        .route("/results", get(results))
+        .route("/results/ws", get(results_ws))
+        .with_state(aggregator);
+    axum::serve(listener, app).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::attestation;
+    use crate::keys::{self, Registry, RegistryEntry, SignedMessage};
+    use chrono::{TimeDelta, Utc};
+    use ed25519_dalek::SigningKey;
+    use futures_util::StreamExt;
+    use std::time::Duration;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    async fn get(address: std::net::SocketAddr, path: &str) -> serde_json::Value {
+        let mut stream = tokio::net::TcpStream::connect(address).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nHost: {}\r\nConnection: close\r\n\r\n", path, address);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{}", response);
+        let (_, body) = response.split_once("\r\n\r\n").unwrap();
+        serde_json::from_str(body).unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_status_results_and_pushed_results() {
+        let signing_keys: Vec<SigningKey> = (1..=3).map(|i| SigningKey::from_bytes(&[i; 32])).collect();
+        let aggregator_key = SigningKey::from_bytes(&[0xa0; 32]);
+        let registry = Registry {
+            aggregator: hex::encode(aggregator_key.verifying_key().to_bytes()),
+            clients: signing_keys
+                .iter()
+                .enumerate()
+                .map(|(i, signing_key)| RegistryEntry::new(&format!("client{}", i + 1), signing_key, Utc::now() - TimeDelta::hours(1)))
+                .collect(),
+            revoked: vec![],
+        };
+        let aggregator = Arc::new(Aggregator::new(aggregator_key, &registry, 2).unwrap());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, aggregator.clone()));
+
+        aggregator.submit(&SignedMessage::sign("client2", 100.0, 1, &signing_keys[1])).unwrap();
+        let status = get(address, "/status").await;
+        assert_eq!(status["round_id"], 1);
+        assert_eq!(status["clients"], serde_json::json!(["client1", "client2", "client3"]));
+        assert_eq!(status["submitted"], serde_json::json!(["client2"]));
+        assert_eq!(status["quorum_reached"], false);
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{}/results/ws", address)).await.unwrap();
+        let mut proposals = aggregator.subscribe_proposals();
+        let driver = aggregator.clone();
+        tokio::spawn(async move { driver.run_rounds(Duration::from_secs(3600), None).await });
+        aggregator.submit(&SignedMessage::sign("client1", 102.0, 1, &signing_keys[0])).unwrap();
+        aggregator.submit(&SignedMessage::sign("client3", 104.0, 1, &signing_keys[2])).unwrap();
+
+        let proposal = proposals.recv().await.unwrap();
+        let status = get(address, "/status").await;
+        assert_eq!((status["round_id"].as_u64(), status["endorsing"]["round_id"].as_u64()), (Some(2), Some(1)));
+        for (i, signing_key) in signing_keys.iter().enumerate() {
+            let signature = attestation::sign(&proposal.result, &attestation::secret_key(signing_key));
+            let key_id = keys::key_id(&signing_key.verifying_key());
+            aggregator.attest(1, &format!("client{}", i + 1), &key_id, &signature).unwrap();
+        }
+
+        let pushed = match socket.next().await.unwrap().unwrap() {
+            tokio_tungstenite::tungstenite::Message::Text(text) => serde_json::from_str::<RoundResult>(&text).unwrap(),
+            other => panic!("expected a text message, got {:?}", other),
+        };
+        assert_eq!(pushed.average, Some(102.0));
+        assert_eq!(pushed.attestation.as_ref().unwrap().signer_ids(), vec!["client1", "client2", "client3"]);
+        let results: Vec<RoundResult> = serde_json::from_value(get(address, "/results?last=5").await).unwrap();
+        assert_eq!(results, vec![pushed]);
+    }
+}
@@ -0,0 +1,333 @@
+use crate::keys::{KeySet, SignedMessage};
+use crate::protocol::{Proposal, RoundResult};
+use crate::robust::{self, Strategy};
+use blst::BLST_ERROR;
+use blst::min_pk::{AggregateSignature, PublicKey, SecretKey, Signature};
+use chrono::{DateTime, SecondsFormat};
+use ed25519_dalek::SigningKey;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{BTreeMap, HashSet};
+
+/// BLS signatures over G2 with the proof-of-possession ciphersuite, so that
+/// signatures over the same message can be checked with one pairing. The
+/// rogue-key attack this suite guards against with proofs of possession is
+/// ruled out here by only accepting public keys from the key registry.
+const DST: &[u8] = b"BLS_SIG_BLS12381G2_XMD:SHA-256_SSWU_RO_POP_";
+/// Separates the BLS key derived from a client's seed from any other use of it.
+const KEY_INFO: &[u8] = b"simulate_distributed_client round attestation";
+
+/// Proof that at least a threshold of registered clients endorsed a round
+/// result: one BLS signature aggregated from each signer's signature over
+/// [`round_message`].
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Attestation {
+    pub signers: Vec<Signer>,
+    pub signature: String,
+}
+
+impl Attestation {
+    pub fn signer_ids(&self) -> Vec<&str> {
+        self.signers.iter().map(|signer| signer.client_id.as_str()).collect()
+    }
+}
+
+/// A client and the registry key it attested with.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Signer {
+    pub client_id: String,
+    pub key_id: String,
+}
+
+/// The BLS key a client attests with, derived from its ed25519 seed so that
+/// a client still has a single secret to keep.
+pub fn secret_key(signing_key: &SigningKey) -> SecretKey {
+    SecretKey::key_gen(&signing_key.to_bytes(), KEY_INFO).expect("an ed25519 seed is 32 bytes")
+}
+
+pub fn public_key_hex(signing_key: &SigningKey) -> String {
+    hex::encode(secret_key(signing_key).sk_to_pk().to_bytes())
+}
+
+pub fn parse_public_key(value: &str) -> Result<PublicKey, String> {
+    let bytes = hex::decode(value).map_err(|e| format!("BLS public key is not hex: {}", e))?;
+    PublicKey::key_validate(&bytes).map_err(|e| format!("invalid BLS public key: {:?}", e))
+}
+
+fn parse_signature(value: &str) -> Result<Signature, String> {
+    let bytes = hex::decode(value).map_err(|e| format!("BLS signature is not hex: {}", e))?;
+    Signature::from_bytes(&bytes).map_err(|e| format!("invalid BLS signature: {:?}", e))
+}
+
+/// What the signers endorse: everything that determines the outcome of the
+/// round, the closing time, since the signers' keys are checked against it
+/// and it must not be moved into another key's validity window, and the
+/// audit log head the aggregator published with it.
+pub fn round_message(result: &RoundResult) -> Vec<u8> {
+    let average = result.average.map_or("none".to_string(), |average| average.to_string());
+    let excluded: Vec<&str> = result.excluded.iter().map(|exclusion| exclusion.client_id.as_str()).collect();
+    format!(
+        "round={};closed_at={};average={};strategy={};contributors={};excluded={};audit_head={}",
+        result.round_id,
+        result.closed_at.to_rfc3339_opts(SecondsFormat::Nanos, true),
+        average,
+        result.strategy,
+        result.contributors.join(","),
+        excluded.join(","),
+        result.audit_head.as_deref().unwrap_or("none")
+    )
+    .into_bytes()
+}
+
+/// Identifies a result by what its signers endorse, see [`round_message`].
+pub fn digest(result: &RoundResult) -> String {
+    hex::encode(Sha256::digest(round_message(result)))
+}
+
+pub fn sign(result: &RoundResult, secret_key: &SecretKey) -> String {
+    hex::encode(secret_key.sign(&round_message(result), DST, &[]).to_bytes())
+}
+
+/// Checks one client's signature over `result`.
+pub fn check_signature(result: &RoundResult, signature: &str, public_key: &PublicKey) -> Result<(), String> {
+    match parse_signature(signature)?.verify(true, &round_message(result), DST, &[], public_key, false) {
+        BLST_ERROR::BLST_SUCCESS => Ok(()),
+        _ => Err("invalid attestation signature".to_string()),
+    }
+}
+
+/// Aggregates already checked signatures, keyed by signer.
+pub fn combine(signatures: &BTreeMap<Signer, String>) -> Result<Attestation, String> {
+    let parsed = signatures.values().map(|signature| parse_signature(signature)).collect::<Result<Vec<_>, _>>()?;
+    let parsed: Vec<&Signature> = parsed.iter().collect();
+    let aggregate = AggregateSignature::aggregate(&parsed, false).map_err(|e| format!("cannot aggregate: {:?}", e))?;
+    Ok(Attestation {
+        signers: signatures.keys().cloned().collect(),
+        signature: hex::encode(aggregate.to_signature().to_bytes()),
+    })
+}
+
+/// Checks that `result` carries an attestation by at least `threshold`
+/// distinct clients, each with a key of `keys` that was valid when the
+/// round closed. Needs nothing from the aggregator but the result itself.
+pub fn verify(result: &RoundResult, keys: &KeySet, threshold: usize) -> Result<(), String> {
+    let attestation = result
+        .attestation
+        .as_ref()
+        .ok_or_else(|| format!("round {} is not attested", result.round_id))?;
+    let mut seen = HashSet::new();
+    let mut public_keys = Vec::new();
+    for signer in &attestation.signers {
+        if !seen.insert(&signer.client_id) {
+            return Err(format!("{} is listed twice as a signer", signer.client_id));
+        }
+        let record = keys
+            .key(&signer.key_id, &signer.client_id, result.closed_at)
+            .map_err(|e| format!("signer {}: {}", signer.client_id, e))?;
+        public_keys.push(&record.bls_key);
+    }
+    if public_keys.len() < threshold {
+        return Err(format!("{} signers, {} required", public_keys.len(), threshold));
+    }
+    match parse_signature(&attestation.signature)?.fast_aggregate_verify(true, &round_message(result), DST, &public_keys) {
+        BLST_ERROR::BLST_SUCCESS => Ok(()),
+        _ => Err(format!("invalid attestation for round {}", result.round_id)),
+    }
+}
+
+/// What a client checks before endorsing a proposal: its `own` input is
+/// among the inputs as it sent it, every input is a correctly signed message
+/// for the round, and applying the stated strategy to them gives exactly the
+/// proposed result. Each input must be signed with a key that was valid when
+/// it was signed.
+pub fn check_proposal(proposal: &Proposal, own: &SignedMessage, keys: &KeySet) -> Result<(), String> {
+    let result = &proposal.result;
+    match proposal.inputs.iter().find(|input| input.client_id == own.client_id) {
+        Some(input) if input == own => {}
+        Some(_) => return Err(format!("the input of {} is not the one it sent", own.client_id)),
+        None => return Err(format!("the input of {} is missing", own.client_id)),
+    }
+    let mut averages = BTreeMap::new();
+    for input in &proposal.inputs {
+        let signed_at = DateTime::from_timestamp_millis(input.timestamp)
+            .ok_or_else(|| format!("input from {}: timestamp out of range", input.client_id))?;
+        let record = keys
+            .key(&input.key_id, &input.client_id, signed_at)
+            .map_err(|e| format!("input from {}: {}", input.client_id, e))?;
+        input
+            .verify(&record.verifying_key)
+            .map_err(|e| format!("input from {}: {}", input.client_id, e))?;
+        if input.round_id != result.round_id {
+            return Err(format!("input from {} is for round {}", input.client_id, input.round_id));
+        }
+        let average = input
+            .average_value()
+            .map_err(|_| format!("input from {} is not a finite number", input.client_id))?;
+        if averages.insert(input.client_id.clone(), average).is_some() {
+            return Err(format!("two inputs from {}", input.client_id));
+        }
+    }
+    if !averages.keys().eq(result.contributors.iter()) {
+        return Err("contributors do not match the inputs".to_string());
+    }
+    let strategy: Strategy = result.strategy.parse()?;
+    let aggregate = robust::aggregate(strategy, &averages).ok_or("no inputs")?;
+    if result.average != Some(aggregate.value) || result.excluded != aggregate.excluded {
+        return Err(format!(
+            "proposed average {:?} does not follow from the inputs, expected {}",
+            result.average, aggregate.value
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{self, Registry, RegistryEntry};
+    use chrono::{TimeDelta, Utc};
+
+    fn client(id: u8) -> (String, SigningKey) {
+        (format!("client{}", id), SigningKey::from_bytes(&[id; 32]))
+    }
+
+    fn proposal(reports: &[(u8, f64)]) -> (Proposal, Vec<(String, SigningKey)>) {
+        let clients: Vec<_> = reports.iter().map(|(id, _)| client(*id)).collect();
+        let inputs: Vec<SignedMessage> = clients
+            .iter()
+            .zip(reports)
+            .map(|((client_id, signing_key), (_, average))| SignedMessage::sign(client_id, *average, 4, signing_key))
+            .collect();
+        let averages = inputs
+            .iter()
+            .map(|input| (input.client_id.clone(), input.average.parse().unwrap()))
+            .collect();
+        let aggregate = robust::aggregate(Strategy::Mad(3.5), &averages).unwrap();
+        let result = RoundResult {
+            round_id: 4,
+            closed_at: Utc::now(),
+            contributors: averages.into_keys().collect(),
+            average: Some(aggregate.value),
+            strategy: Strategy::Mad(3.5).to_string(),
+            excluded: aggregate.excluded,
+            audit_head: None,
+            attestation: None,
+        };
+        (Proposal { result, inputs }, clients)
+    }
+
+    fn registry(clients: &[(String, SigningKey)]) -> KeySet {
+        let registry = Registry {
+            aggregator: hex::encode([0; 32]),
+            clients: clients
+                .iter()
+                .map(|(client_id, signing_key)| RegistryEntry::new(client_id, signing_key, Utc::now() - TimeDelta::hours(1)))
+                .collect(),
+            revoked: vec![],
+        };
+        KeySet::new(&registry).unwrap()
+    }
+
+    fn signer(client_id: &str, signing_key: &SigningKey) -> Signer {
+        Signer {
+            client_id: client_id.to_string(),
+            key_id: keys::key_id(&signing_key.verifying_key()),
+        }
+    }
+
+    fn attest(result: &mut RoundResult, signers: &[(String, SigningKey)]) {
+        let signatures = signers
+            .iter()
+            .map(|(client_id, signing_key)| (signer(client_id, signing_key), sign(result, &secret_key(signing_key))))
+            .collect();
+        result.attestation = Some(combine(&signatures).unwrap());
+    }
+
+    #[test]
+    fn test_threshold_attestation_verifies() {
+        let (proposal, clients) = proposal(&[(1, 100.0), (2, 101.0), (3, 102.0)]);
+        let mut result = proposal.result;
+        attest(&mut result, &clients[..2]);
+        let public_keys = registry(&clients);
+        assert_eq!(verify(&result, &public_keys, 2), Ok(()));
+        assert_eq!(verify(&result, &public_keys, 3), Err("2 signers, 3 required".to_string()));
+    }
+
+    #[test]
+    fn test_tampered_results_and_signer_lists_fail() {
+        let (proposal, clients) = proposal(&[(1, 100.0), (2, 101.0), (3, 102.0)]);
+        let mut result = proposal.result;
+        attest(&mut result, &clients);
+        let public_keys = registry(&clients);
+
+        let mut tampered = result.clone();
+        tampered.average = Some(1.0);
+        assert!(verify(&tampered, &public_keys, 2).is_err());
+
+        let mut backdated = result.clone();
+        backdated.closed_at -= TimeDelta::minutes(30);
+        assert_eq!(verify(&backdated, &public_keys, 2), Err("invalid attestation for round 4".to_string()));
+
+        let mut padded = result.clone();
+        padded.attestation.as_mut().unwrap().signers.push(signer("client1", &clients[0].1));
+        assert_eq!(verify(&padded, &public_keys, 2), Err("client1 is listed twice as a signer".to_string()));
+
+        // A signer outside the registry cannot stand in for a registered one.
+        let (outsider, outsider_key) = client(9);
+        let mut forged = result.clone();
+        attest(&mut forged, &[clients[0].clone(), (outsider.clone(), outsider_key)]);
+        forged.attestation.as_mut().unwrap().signers[1] = signer("client2", &clients[1].1);
+        assert!(verify(&forged, &public_keys, 2).is_err());
+    }
+
+    #[test]
+    fn test_clients_only_endorse_what_follows_from_the_inputs() {
+        let (proposal, clients) = proposal(&[(1, 100.0), (2, 101.0), (3, 102.0), (4, 1.0)]);
+        let verifying_keys = registry(&clients);
+        let own = proposal.inputs[1].clone();
+        assert_eq!(check_proposal(&proposal, &own, &verifying_keys), Ok(()));
+
+        let mut inflated = proposal.clone();
+        inflated.result.average = Some(1_000.0);
+        assert!(check_proposal(&inflated, &own, &verifying_keys).unwrap_err().starts_with("proposed average"));
+
+        let mut hidden = proposal.clone();
+        hidden.result.excluded.clear();
+        assert!(check_proposal(&hidden, &own, &verifying_keys).is_err());
+
+        let mut forged = proposal.clone();
+        forged.inputs[0].average = "50".to_string();
+        assert!(check_proposal(&forged, &own, &verifying_keys).unwrap_err().contains("invalid signature"));
+    }
+
+    #[test]
+    fn test_clients_refuse_non_finite_inputs() {
+        let (proposal, clients) = proposal(&[(1, 100.0), (2, 101.0), (3, 102.0), (4, f64::NAN)]);
+        let own = proposal.inputs[1].clone();
+        assert_eq!(
+            check_proposal(&proposal, &own, &registry(&clients)),
+            Err("input from client4 is not a finite number".to_string())
+        );
+    }
+
+    #[test]
+    fn test_clients_refuse_proposals_without_their_input() {
+        let (sent, clients) = proposal(&[(1, 100.0), (2, 101.0), (3, 102.0)]);
+        let verifying_keys = registry(&clients);
+        let own = sent.inputs[1].clone();
+
+        // Left out, with a result that follows from the remaining inputs.
+        let (without, _) = proposal(&[(1, 100.0), (3, 102.0)]);
+        assert_eq!(
+            check_proposal(&without, &own, &verifying_keys),
+            Err("the input of client2 is missing".to_string())
+        );
+
+        // Swapped for another validly signed average of the same client.
+        let (replaced, _) = proposal(&[(1, 100.0), (2, 101.0), (3, 102.0)]);
+        assert_eq!(
+            check_proposal(&replaced, &own, &verifying_keys),
+            Err("the input of client2 is not the one it sent".to_string())
+        );
+    }
+}
@@ -0,0 +1,496 @@
+use crate::keys::KeySet;
+use crate::protocol::{self, Message, RoundResult};
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashSet;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Seek, SeekFrom, Write};
+use std::path::Path;
+use std::sync::Mutex;
+
+/// Where the server keeps its audit log unless told otherwise.
+pub const AUDIT_LOG_PATH: &str = "audit.jsonl";
+/// What the first entry of a log chains to.
+const GENESIS_HASH: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// One frame as the aggregator received it: who sent it, its raw bytes, when
+/// it arrived, which round was open and whether it was accepted.
+/// Each entry commits to the one before through `prev_hash` and is signed by
+/// the aggregator, so without the aggregator's key an entry cannot be
+/// changed, dropped or reordered without breaking the chain. The aggregator
+/// itself is held to the log by the heads it publishes in every
+/// [`RoundResult::audit_head`], which its clients endorse.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct AuditEntry {
+    /// Position in the log, from 1.
+    pub seq: u64,
+    pub received_at: DateTime<Utc>,
+    /// The round open when the frame arrived.
+    pub round_id: u64,
+    /// The client the connection was authenticated as.
+    pub peer: String,
+    /// The frame after decryption, hex-encoded, see [`protocol::frame`]; its
+    /// ciphertext if it did not decrypt, or empty if it was never read
+    /// whole, e.g. because it was too long.
+    pub frame: String,
+    /// Why the frame was refused; `None` if it was accepted.
+    pub rejection: Option<String>,
+    /// `hash` of the previous entry.
+    pub prev_hash: String,
+    /// SHA-256 over the entry with this field and `signature` empty,
+    /// hex-encoded.
+    pub hash: String,
+    /// The aggregator's ed25519 signature over `hash`, hex-encoded.
+    pub signature: String,
+}
+
+impl AuditEntry {
+    fn digest(&self) -> String {
+        let unhashed = Self {
+            hash: String::new(),
+            signature: String::new(),
+            ..self.clone()
+        };
+        let json = serde_json::to_vec(&unhashed).expect("audit entries serialize");
+        hex::encode(Sha256::digest(json))
+    }
+
+    /// The message in the frame, if it is one.
+    pub fn message(&self) -> Option<Message> {
+        hex::decode(&self.frame).ok().and_then(|frame| protocol::parse_frame(&frame).ok())
+    }
+
+    fn check_signature(&self, aggregator: &VerifyingKey) -> Result<(), String> {
+        let bytes: [u8; 64] = hex::decode(&self.signature)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or("signature is not 64 hex-encoded bytes")?;
+        aggregator
+            .verify(self.hash.as_bytes(), &Signature::from_bytes(&bytes))
+            .map_err(|_| "invalid signature".to_string())
+    }
+}
+
+/// The end of the chain new entries are appended to.
+#[derive(Debug)]
+struct Tail {
+    file: File,
+    seq: u64,
+    hash: String,
+}
+
+/// An append-only, hash-chained log of every frame the aggregator received
+/// from its clients, signed with the aggregator's `identity`, see
+/// [`AuditEntry`]. Opening an existing log continues its chain.
+///
+/// Entries are written one line at a time, so a crash can only cut the last
+/// one short. Opening a log whose last line is unreadable and unterminated
+/// drops that line with a warning and continues from the entry before it:
+/// an entry is only written once its frame was decided on, and the reply is
+/// only sent once it is on disk, so the lost entry was never acted upon. Any
+/// other unreadable or altered entry keeps the log from opening.
+#[derive(Debug)]
+pub struct AuditLog {
+    identity: SigningKey,
+    tail: Mutex<Tail>,
+}
+
+impl AuditLog {
+    pub fn open(path: &Path, identity: SigningKey) -> Result<Self, String> {
+        let data = match fs::read_to_string(path) {
+            Ok(data) => recover_tail(path, data)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(format!("cannot read {}: {}", path.display(), e)),
+        };
+        let (seq, hash) = match data.lines().rfind(|line| !line.trim().is_empty()) {
+            Some(line) => {
+                let last: AuditEntry = serde_json::from_str(line)
+                    .map_err(|e| format!("cannot continue {}, its last entry is unreadable: {}", path.display(), e))?;
+                if last.digest() != last.hash || last.check_signature(&identity.verifying_key()).is_err() {
+                    return Err(format!("cannot continue {}, its last entry was altered", path.display()));
+                }
+                (last.seq, last.hash)
+            }
+            None => (0, GENESIS_HASH.to_string()),
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+        Ok(Self {
+            identity,
+            tail: Mutex::new(Tail { file, seq, hash }),
+        })
+    }
+
+    /// The hash of the latest entry, which pins everything logged so far.
+    pub fn head(&self) -> String {
+        self.tail.lock().unwrap().hash.clone()
+    }
+
+    /// Appends a frame received from `peer` and its verdict, and syncs it to
+    /// disk.
+    pub fn append(
+        &self,
+        received_at: DateTime<Utc>,
+        round_id: u64,
+        peer: &str,
+        frame: &[u8],
+        verdict: &Result<(), String>,
+    ) -> Result<(), String> {
+        let mut tail = self.tail.lock().unwrap();
+        let mut entry = AuditEntry {
+            seq: tail.seq + 1,
+            received_at,
+            round_id,
+            peer: peer.to_string(),
+            frame: hex::encode(frame),
+            rejection: verdict.clone().err(),
+            prev_hash: tail.hash.clone(),
+            hash: String::new(),
+            signature: String::new(),
+        };
+        entry.hash = entry.digest();
+        entry.signature = hex::encode(self.identity.sign(entry.hash.as_bytes()).to_bytes());
+        let line = serde_json::to_string(&entry).map_err(|e| e.to_string())?;
+        writeln!(tail.file, "{}", line)
+            .and_then(|_| tail.file.sync_data())
+            .map_err(|e| e.to_string())?;
+        tail.seq = entry.seq;
+        tail.hash = entry.hash;
+        Ok(())
+    }
+}
+
+/// Cuts an unreadable, unterminated last line off the log at `path`, see
+/// [`AuditLog`], and makes sure the log ends with a newline. Returns what is
+/// left.
+fn recover_tail(path: &Path, mut data: String) -> Result<String, String> {
+    if data.is_empty() || data.ends_with('\n') {
+        return Ok(data);
+    }
+    let complete = data.rfind('\n').map_or(0, |end| end + 1);
+    let mut file = OpenOptions::new()
+        .write(true)
+        .open(path)
+        .map_err(|e| format!("cannot open {}: {}", path.display(), e))?;
+    let repaired = match serde_json::from_str::<AuditEntry>(&data[complete..]) {
+        Ok(_) => {
+            data.push('\n');
+            file.seek(SeekFrom::End(0)).and_then(|_| file.write_all(b"\n"))
+        }
+        Err(e) => {
+            println!("⚠️ {} ends in an entry cut short ({}), dropping it", path.display(), e);
+            data.truncate(complete);
+            file.set_len(complete as u64)
+        }
+    };
+    repaired
+        .and_then(|_| file.sync_data())
+        .map_err(|e| format!("cannot repair {}: {}", path.display(), e))?;
+    Ok(data)
+}
+
+/// What [`verify`] found in a log.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AuditReport {
+    pub entries: usize,
+    pub accepted: usize,
+    pub rejected: usize,
+    /// Rejected signed averages whose signature is nonetheless valid, e.g.
+    /// replays and late submissions.
+    pub rejected_but_signed: usize,
+    /// Everything wrong with the log, one line per finding.
+    pub problems: Vec<String>,
+    /// The hash of every entry, to look published heads up in.
+    pub hashes: HashSet<String>,
+}
+
+impl AuditReport {
+    /// Reports every head published in `results` that the log does not
+    /// contain: the log was rewritten after the round closed.
+    pub fn check_heads(&mut self, results: &[RoundResult]) {
+        for result in results {
+            if let Some(head) = &result.audit_head
+                && head != GENESIS_HASH
+                && !self.hashes.contains(head)
+            {
+                self.problems.push(format!(
+                    "round {} was published with audit head {}, which is not in the log",
+                    result.round_id, head
+                ));
+            }
+        }
+    }
+}
+
+/// Re-checks the chain of the log at `path`, the aggregator's signature on
+/// every entry and the signature of every signed average in it against the
+/// registered key it names. Keys are checked as registered, whatever their
+/// validity now: the log records whether a key was usable when its message
+/// arrived.
+pub fn verify(path: &Path, keys: &KeySet, aggregator: &VerifyingKey) -> Result<AuditReport, String> {
+    let data = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let mut report = AuditReport::default();
+    let mut prev_hash = GENESIS_HASH.to_string();
+    let mut prev_seq = 0;
+    for (number, line) in data.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let line_number = number + 1;
+        let entry: AuditEntry = match serde_json::from_str(line) {
+            Ok(entry) => entry,
+            Err(e) => {
+                report.problems.push(format!("line {}: {}", line_number, e));
+                continue;
+            }
+        };
+        report.entries += 1;
+        if entry.seq != prev_seq + 1 {
+            report
+                .problems
+                .push(format!("line {}: entry {} follows entry {}", line_number, entry.seq, prev_seq));
+        }
+        if entry.prev_hash != prev_hash {
+            report
+                .problems
+                .push(format!("line {}: entry {} does not chain to the entry before it", line_number, entry.seq));
+        }
+        if entry.digest() != entry.hash {
+            report
+                .problems
+                .push(format!("line {}: entry {} was altered after it was written", line_number, entry.seq));
+        }
+        if let Err(e) = entry.check_signature(aggregator) {
+            report
+                .problems
+                .push(format!("line {}: entry {} is not signed by the aggregator: {}", line_number, entry.seq, e));
+        }
+        report.hashes.insert(entry.hash.clone());
+        match &entry.rejection {
+            None => report.accepted += 1,
+            Some(_) => report.rejected += 1,
+        }
+        let message = entry.message();
+        if let Some(Message::SignedAverage(signed)) = &message {
+            let signature = keys
+                .registered(&signed.key_id, &signed.client_id)
+                .and_then(|record| signed.verify(&record.verifying_key));
+            match (&entry.rejection, signature) {
+                (None, Err(e)) => report.problems.push(format!(
+                    "line {}: entry {} from {} was accepted but {}",
+                    line_number, entry.seq, signed.client_id, e
+                )),
+                (Some(_), Ok(())) => report.rejected_but_signed += 1,
+                _ => {}
+            }
+            if entry.rejection.is_none() && signed.client_id != entry.peer {
+                report.problems.push(format!(
+                    "line {}: entry {} was accepted from {} for {}",
+                    line_number, entry.seq, entry.peer, signed.client_id
+                ));
+            }
+            if entry.rejection.is_none() && signed.round_id != entry.round_id {
+                report.problems.push(format!(
+                    "line {}: entry {} was accepted for round {} while round {} was open",
+                    line_number, entry.seq, signed.round_id, entry.round_id
+                ));
+            }
+        } else if entry.rejection.is_none() && message.is_none() {
+            report
+                .problems
+                .push(format!("line {}: entry {} was accepted but holds no message", line_number, entry.seq));
+        }
+        prev_seq = entry.seq;
+        prev_hash = entry.hash;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::{Registry, RegistryEntry, SignedMessage};
+    use chrono::TimeDelta;
+    use std::path::PathBuf;
+
+    fn aggregator() -> SigningKey {
+        SigningKey::from_bytes(&[0xa0; 32])
+    }
+
+    fn setup(name: &str) -> (PathBuf, SigningKey, KeySet) {
+        let path = std::env::temp_dir().join(format!("audit-{}-{}.jsonl", name, std::process::id()));
+        let _ = fs::remove_file(&path);
+        let signing_key = SigningKey::from_bytes(&[1; 32]);
+        let registry = Registry {
+            aggregator: hex::encode(aggregator().verifying_key().to_bytes()),
+            clients: vec![RegistryEntry::new("client1", &signing_key, Utc::now() - TimeDelta::hours(1))],
+            revoked: vec![],
+        };
+        (path, signing_key, KeySet::new(&registry).unwrap())
+    }
+
+    fn average(signed: &SignedMessage) -> Vec<u8> {
+        protocol::frame(&Message::SignedAverage(signed.clone())).unwrap()
+    }
+
+    fn published(round_id: u64, head: String) -> RoundResult {
+        RoundResult {
+            round_id,
+            closed_at: Utc::now(),
+            contributors: vec!["client1".to_string()],
+            average: Some(100.0),
+            strategy: "mean".to_string(),
+            excluded: vec![],
+            audit_head: Some(head),
+            attestation: None,
+        }
+    }
+
+    #[test]
+    fn test_chain_continues_across_reopening_and_verifies() {
+        let (path, signing_key, keys) = setup("reopen");
+        let log = AuditLog::open(&path, aggregator()).unwrap();
+        let signed = SignedMessage::sign("client1", 100.0, 1, &signing_key);
+        log.append(Utc::now(), 1, "client1", &average(&signed), &Ok(())).unwrap();
+        log.append(Utc::now(), 1, "client1", &average(&signed), &Err("replayed message from client1".to_string()))
+            .unwrap();
+        let head = log.head();
+        drop(log);
+
+        let log = AuditLog::open(&path, aggregator()).unwrap();
+        assert_eq!(log.head(), head);
+        let mut forged = SignedMessage::sign("client1", 100.0, 2, &signing_key);
+        forged.average = "1".to_string();
+        log.append(Utc::now(), 2, "client1", &average(&forged), &Err("invalid signature".to_string())).unwrap();
+        log.append(Utc::now(), 2, "client1", b"\x01not json", &Err("malformed message".to_string())).unwrap();
+
+        let mut report = verify(&path, &keys, &aggregator().verifying_key()).unwrap();
+        report.check_heads(&[published(1, head)]);
+        assert_eq!(report.problems, Vec::<String>::new());
+        assert_eq!((report.entries, report.accepted, report.rejected, report.rejected_but_signed), (4, 1, 3, 1));
+        let last: AuditEntry = serde_json::from_str(fs::read_to_string(&path).unwrap().lines().last().unwrap()).unwrap();
+        assert_eq!((last.seq, last.message()), (4, None));
+        assert!(AuditLog::open(&path, SigningKey::from_bytes(&[0xbb; 32])).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_detects_altered_dropped_and_rechained_entries() {
+        let (path, signing_key, keys) = setup("tamper");
+        let aggregator_key = aggregator().verifying_key();
+        let log = AuditLog::open(&path, aggregator()).unwrap();
+        for round_id in 1..=3 {
+            let signed = SignedMessage::sign("client1", 100.0, round_id, &signing_key);
+            log.append(Utc::now(), round_id, "client1", &average(&signed), &Ok(())).unwrap();
+        }
+        let original = fs::read_to_string(&path).unwrap();
+        let lines: Vec<&str> = original.lines().collect();
+
+        // An edited average breaks both the entry's hash and its signature.
+        let edited: Vec<String> = lines
+            .iter()
+            .map(|line| {
+                let mut entry: AuditEntry = serde_json::from_str(line).unwrap();
+                let Some(Message::SignedAverage(mut signed)) = entry.message() else {
+                    panic!("expected a signed average in {}", line);
+                };
+                signed.average = "90".to_string();
+                entry.frame = hex::encode(average(&signed));
+                serde_json::to_string(&entry).unwrap()
+            })
+            .collect();
+        fs::write(&path, edited.join("\n") + "\n").unwrap();
+        let report = verify(&path, &keys, &aggregator_key).unwrap();
+        assert_eq!(report.problems.len(), 6);
+        assert!(report.problems[0].ends_with("entry 1 was altered after it was written"));
+        assert!(report.problems[1].ends_with("entry 1 from client1 was accepted but invalid signature"));
+        assert!(AuditLog::open(&path, aggregator()).is_err());
+
+        // A dropped entry leaves a gap in the chain.
+        fs::write(&path, format!("{}\n{}\n", lines[0], lines[2])).unwrap();
+        let report = verify(&path, &keys, &aggregator_key).unwrap();
+        assert_eq!(
+            report.problems,
+            vec![
+                "line 2: entry 3 follows entry 1".to_string(),
+                "line 2: entry 3 does not chain to the entry before it".to_string(),
+            ]
+        );
+
+        // Rehashing an edited entry, and everything after it, needs the
+        // aggregator's key to sign the new hashes.
+        let mut entry: AuditEntry = serde_json::from_str(lines[1]).unwrap();
+        entry.round_id = 7;
+        entry.hash = entry.digest();
+        let mut next: AuditEntry = serde_json::from_str(lines[2]).unwrap();
+        next.prev_hash = entry.hash.clone();
+        next.hash = next.digest();
+        let rehashed = [lines[0].to_string(), serde_json::to_string(&entry).unwrap(), serde_json::to_string(&next).unwrap()];
+        fs::write(&path, rehashed.join("\n") + "\n").unwrap();
+        let report = verify(&path, &keys, &aggregator_key).unwrap();
+        assert_eq!(
+            report.problems,
+            vec![
+                "line 2: entry 2 is not signed by the aggregator: invalid signature".to_string(),
+                "line 2: entry 2 was accepted for round 2 while round 7 was open".to_string(),
+                "line 3: entry 3 is not signed by the aggregator: invalid signature".to_string(),
+            ]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// The aggregator can re-sign a rewritten log, but not change the heads
+    /// it already published.
+    #[test]
+    fn test_published_heads_pin_the_log() {
+        let (path, signing_key, keys) = setup("heads");
+        let log = AuditLog::open(&path, aggregator()).unwrap();
+        let signed = SignedMessage::sign("client1", 100.0, 1, &signing_key);
+        log.append(Utc::now(), 1, "client1", &average(&signed), &Ok(())).unwrap();
+        let head = log.head();
+        drop(log);
+
+        fs::remove_file(&path).unwrap();
+        let rewritten = AuditLog::open(&path, aggregator()).unwrap();
+        let signed = SignedMessage::sign("client1", 90.0, 1, &signing_key);
+        rewritten.append(Utc::now(), 1, "client1", &average(&signed), &Ok(())).unwrap();
+        let mut report = verify(&path, &keys, &aggregator().verifying_key()).unwrap();
+        assert!(report.problems.is_empty());
+        report.check_heads(&[published(1, head.clone())]);
+        assert_eq!(
+            report.problems,
+            vec![format!("round 1 was published with audit head {}, which is not in the log", head)]
+        );
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_an_entry_cut_short_by_a_crash_is_dropped_on_reopening() {
+        let (path, signing_key, keys) = setup("torn");
+        let log = AuditLog::open(&path, aggregator()).unwrap();
+        for round_id in 1..=2 {
+            let signed = SignedMessage::sign("client1", 100.0, round_id, &signing_key);
+            log.append(Utc::now(), round_id, "client1", &average(&signed), &Ok(())).unwrap();
+        }
+        let head = log.head();
+        drop(log);
+        let complete = fs::read_to_string(&path).unwrap();
+        let torn = complete.clone() + &complete.lines().last().unwrap()[..40];
+        fs::write(&path, &torn).unwrap();
+
+        let log = AuditLog::open(&path, aggregator()).unwrap();
+        assert_eq!(log.head(), head);
+        assert_eq!(fs::read_to_string(&path).unwrap(), complete);
+        let signed = SignedMessage::sign("client1", 100.0, 3, &signing_key);
+        log.append(Utc::now(), 3, "client1", &average(&signed), &Ok(())).unwrap();
+        let report = verify(&path, &keys, &aggregator().verifying_key()).unwrap();
+        assert_eq!((report.problems.len(), report.entries), (0, 3));
+
+        // Only the last line can be cut short; anything else is refused.
+        fs::write(&path, torn + "\n").unwrap();
+        assert!(AuditLog::open(&path, aggregator()).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
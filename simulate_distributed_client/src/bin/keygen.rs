@@ -1,29 +1,289 @@
+use chrono::{TimeDelta, Utc};
+use clap::{Args, Parser, Subcommand};
 use ed25519_dalek::SigningKey;
 use rand::rngs::OsRng;
-use simulate_distributed_client::keys::KeyData;
-use std::fs::File;
+use simulate_distributed_client::config::ClusterConfig;
+use simulate_distributed_client::keys::{self, EncryptedKey, KdfParams, Registry, RegistryEntry, Revocation};
+use simulate_distributed_client::schedule;
+use std::fs::{self, OpenOptions};
 use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
 
-fn main() {
-    let mut clients = vec![];
-    for i in 1..=5 {
-        let client_id = format!("client{}", i);
-        let signing_key = SigningKey::generate(&mut OsRng);
-        let verifying_key = signing_key.verifying_key();
+#[derive(Debug, Parser)]
+#[command(
+    about = "Generates encrypted key files for the aggregator and each configured client, and the public-key registry",
+    args_conflicts_with_subcommands = true
+)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    init: InitArgs,
+    #[arg(long, global = true, help = "Cluster config [default: cluster.toml if present, else five local clients]")]
+    config: Option<PathBuf>,
+    #[arg(long, global = true, help = "Directory of the encrypted key files [default: from the cluster config]")]
+    key_dir: Option<PathBuf>,
+    #[arg(long, global = true, help = "Public-key registry for the server and clients [default: from the cluster config]")]
+    registry: Option<PathBuf>,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Generates a fresh set of keys and a new registry (the default)
+    Init(InitArgs),
+    /// Gives a client a new key, keeping the old one valid for a while
+    Rotate {
+        #[arg(long, help = "Client whose key is replaced, e.g. client3")]
+        client: String,
+        #[arg(long, default_value = "5m", help = "How long the old key stays valid next to the new one")]
+        overlap: String,
+    },
+    /// Stops a key from being accepted, effective immediately
+    Revoke {
+        #[arg(long, help = "Key id as listed in the registry")]
+        key_id: String,
+        #[arg(long, default_value = "", help = "Why the key is revoked, recorded in the registry")]
+        reason: String,
+    },
+}
+
+#[derive(Debug, Args)]
+struct InitArgs {
+    #[arg(long, help = "Overwrite existing key files")]
+    force: bool,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let result = ClusterConfig::load(cli.config.as_deref()).and_then(|mut config| {
+        config.override_paths(cli.key_dir.as_deref(), cli.registry.as_deref());
+        match &cli.command {
+            None => init(&config, &cli.init),
+            Some(Command::Init(args)) => init(&config, args),
+            Some(Command::Rotate { client, overlap }) => rotate(&config, client, overlap),
+            Some(Command::Revoke { key_id, reason }) => revoke(&config, key_id, reason),
+        }
+    });
+    match result {
+        Ok(done) => {
+            println!("✅ {}", done);
+            ExitCode::SUCCESS
+        }
+        Err(e) => {
+            println!("Error:: {}", e);
+            ExitCode::FAILURE
+        }
+    }
+}
+
+/// Writes every key file and the registry into a staging directory first and
+/// moves them into place only once all of them were written, so a failed init
+/// leaves the keys and registry in use as they were.
+fn init(config: &ClusterConfig, args: &InitArgs) -> Result<String, String> {
+    let owners: Vec<&str> = std::iter::once(keys::AGGREGATOR_ID).chain(config.client_ids()).collect();
+    // The registry counts too: the server and any revocations depend on it
+    // even when the key files are gone.
+    if !args.force
+        && let Some(path) = owners
+            .iter()
+            .map(|owner| config.key_path(owner))
+            .chain(std::iter::once(config.registry.clone()))
+            .find(|path| path.exists())
+    {
+        return Err(format!("{} already exists, pass --force to overwrite it", path.display()));
+    }
+    // Every password is asked for before anything is written.
+    let passwords = owners
+        .iter()
+        .map(|owner| new_password(owner, config.password_file(owner)))
+        .collect::<Result<Vec<_>, String>>()?;
 
-        let private_hex = hex::encode(signing_key.to_bytes());
-        let public_hex = hex::encode(verifying_key.to_bytes());
+    let mut staging = Staging::new(&config.key_dir)?;
+    let mut registry = Registry {
+        aggregator: String::new(),
+        clients: vec![],
+        revoked: vec![],
+    };
+    for (owner, password) in owners.iter().zip(&passwords) {
+        let (signing_key, json) = seal_new_key(owner, password)?;
+        staging.add(&config.key_path(owner), &json, true)?;
+        if *owner == keys::AGGREGATOR_ID {
+            registry.aggregator = hex::encode(signing_key.verifying_key().to_bytes());
+        } else {
+            registry.clients.push(RegistryEntry::new(owner, &signing_key, Utc::now()));
+        }
+    }
+    let json = serde_json::to_string_pretty(&registry).map_err(|e| e.to_string())?;
+    staging.add(&config.registry, &json, false)?;
+    staging.commit()?;
+    Ok(format!(
+        "{} client key files written, registry written to {}",
+        config.clients.len(),
+        config.registry.display()
+    ))
+}
+
+/// Files written under a scratch directory next to the key directory, and
+/// renamed to their places by [`Staging::commit`]. Whatever was not committed
+/// is removed with the directory.
+struct Staging {
+    dir: PathBuf,
+    /// Staged file and where it goes, in the order they were added.
+    files: Vec<(PathBuf, PathBuf)>,
+}
+
+impl Staging {
+    fn new(key_dir: &Path) -> Result<Self, String> {
+        let name = key_dir.file_name().map_or("keys".into(), |name| name.to_string_lossy());
+        let dir = key_dir.with_file_name(format!(".{}.init-{}", name, std::process::id()));
+        let mut builder = fs::DirBuilder::new();
+        builder.recursive(true);
+        #[cfg(unix)]
+        std::os::unix::fs::DirBuilderExt::mode(&mut builder, 0o700);
+        builder
+            .create(&dir)
+            .map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+        Ok(Self { dir, files: Vec::new() })
+    }
 
-        let key_data = KeyData {
-            client_id,
-            public: public_hex,
-            private: private_hex,
-        };
-        clients.push(key_data);
+    fn add(&mut self, target: &Path, contents: &str, private: bool) -> Result<(), String> {
+        let staged = self.dir.join(self.files.len().to_string());
+        if private {
+            write_private(&staged, contents, false)?;
+        } else {
+            fs::write(&staged, contents).map_err(|e| format!("cannot write {}: {}", staged.display(), e))?;
+        }
+        self.files.push((staged, target.to_path_buf()));
+        Ok(())
     }
 
-    let json = serde_json::to_string_pretty(&clients).unwrap();
-    let mut file = File::create("keys.json").unwrap();
-    file.write_all(json.as_bytes()).unwrap();
-    println!("✅ keys.json generated");
+    /// Moves the staged files into place, the registry last so it never
+    /// names keys whose files are not there yet.
+    fn commit(self) -> Result<(), String> {
+        for (staged, target) in &self.files {
+            if let Some(dir) = target.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+                fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+            }
+            fs::rename(staged, target).map_err(|e| format!("cannot move {} into place: {}", target.display(), e))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Staging {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+/// Writes a new key for `client_id` in place of its current one, which is
+/// moved aside to `<client>.<key id>.key` and expires after `overlap`, so
+/// rounds already under way can finish with it.
+fn rotate(config: &ClusterConfig, client_id: &str, overlap: &str) -> Result<String, String> {
+    let overlap = TimeDelta::from_std(schedule::parse_interval(overlap)?).map_err(|e| e.to_string())?;
+    config.client(client_id)?;
+    let mut registry = keys::load_registry(&config.registry)?;
+    let now = Utc::now();
+    let current = registry
+        .clients
+        .iter_mut()
+        .filter(|entry| entry.client_id == client_id && entry.not_after.is_none())
+        .max_by_key(|entry| entry.not_before)
+        .ok_or_else(|| format!("{} has no current key in {}", client_id, config.registry.display()))?;
+    current.not_after = Some(now + overlap);
+    let old_key_id = current.key_id.clone();
+
+    let password = new_password(client_id, config.password_file(client_id))?;
+    let path = config.key_path(client_id);
+    let retired = path.with_file_name(format!("{}.{}.key", client_id, old_key_id));
+    if path.exists() {
+        fs::rename(&path, &retired).map_err(|e| format!("cannot move {} aside: {}", path.display(), e))?;
+    }
+    let signing_key = new_key(&path, client_id, &password, false)?;
+    let entry = RegistryEntry::new(client_id, &signing_key, now);
+    let new_key_id = entry.key_id.clone();
+    registry.clients.push(entry);
+    keys::save_registry(&config.registry, &registry)?;
+    Ok(format!(
+        "{} now signs with key {}, key {} expires at {}",
+        client_id,
+        new_key_id,
+        old_key_id,
+        (now + overlap).to_rfc3339()
+    ))
+}
+
+fn revoke(config: &ClusterConfig, key_id: &str, reason: &str) -> Result<String, String> {
+    let mut registry = keys::load_registry(&config.registry)?;
+    let entry = registry
+        .clients
+        .iter()
+        .find(|entry| entry.key_id == key_id)
+        .ok_or_else(|| format!("no key {} in {}", key_id, config.registry.display()))?;
+    let client_id = entry.client_id.clone();
+    if registry.revoked.iter().any(|revocation| revocation.key_id == key_id) {
+        return Err(format!("key {} is already revoked", key_id));
+    }
+    registry.revoked.push(Revocation {
+        key_id: key_id.to_string(),
+        revoked_at: Utc::now(),
+        reason: reason.to_string(),
+    });
+    keys::save_registry(&config.registry, &registry)?;
+    Ok(format!("key {} of {} revoked", key_id, client_id))
+}
+
+/// Asks for the password `owner`'s new key file is encrypted with, twice,
+/// unless it comes from `password_file` or, for the aggregator, from the
+/// environment.
+fn new_password(owner: &str, password_file: Option<&Path>) -> Result<zeroize::Zeroizing<String>, String> {
+    if owner == keys::AGGREGATOR_ID {
+        let password = keys::password("Password for the aggregator key: ")?;
+        if std::env::var(keys::PASSWORD_ENV).is_err() && *keys::password("Repeat the password: ")? != *password {
+            return Err("passwords do not match".to_string());
+        }
+        return Ok(password);
+    }
+    let password = keys::key_password(owner, password_file)?;
+    if password_file.is_none() && *keys::key_password(owner, None)? != *password {
+        return Err(format!("passwords for {} do not match", owner));
+    }
+    Ok(password)
+}
+
+/// A new key and its key file contents, encrypted with `password`.
+fn seal_new_key(id: &str, password: &str) -> Result<(SigningKey, String), String> {
+    let signing_key = SigningKey::generate(&mut OsRng);
+    let encrypted = EncryptedKey::seal(id, &signing_key, password, KdfParams::default())?;
+    let json = serde_json::to_string_pretty(&encrypted).map_err(|e| e.to_string())?;
+    Ok((signing_key, json))
+}
+
+fn new_key(path: &Path, id: &str, password: &str, force: bool) -> Result<SigningKey, String> {
+    let (signing_key, json) = seal_new_key(id, password)?;
+    if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(dir).map_err(|e| format!("cannot create {}: {}", dir.display(), e))?;
+    }
+    write_private(path, &json, force)?;
+    Ok(signing_key)
+}
+
+/// Writes a file only its owner can read.
+fn write_private(path: &Path, contents: &str, force: bool) -> Result<(), String> {
+    let mut options = OpenOptions::new();
+    options.write(true);
+    if force {
+        options.create(true).truncate(true);
+    } else {
+        options.create_new(true);
+    }
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    let mut file = options.open(path).map_err(|e| match e.kind() {
+        std::io::ErrorKind::AlreadyExists => format!("{} already exists, pass --force to overwrite it", path.display()),
+        _ => format!("cannot write {}: {}", path.display(), e),
+    })?;
+    file.write_all(contents.as_bytes())
+        .map_err(|e| format!("cannot write {}: {}", path.display(), e))
 }
@@ -0,0 +1,145 @@
+use chrono::{Local, Utc};
+use clap::Parser;
+use simulate_distributed_client::config::ClusterConfig;
+use simulate_distributed_client::{keys, schedule};
+use std::collections::HashMap;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process::{Child, Command, ExitCode, Stdio};
+use std::time::Duration;
+
+/// Time the processes get to start up before the first tick they may use.
+const STARTUP_LEAD: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Parser)]
+#[command(about = "Spawns one client process per configured client, all starting on the same tick")]
+struct Cli {
+    #[arg(long, help = "Cluster config [default: cluster.toml if present, else five local clients]")]
+    config: Option<PathBuf>,
+    #[arg(short, long, default_value = "10", help = "Number of seconds each client fetches the price for")]
+    times: u64,
+    #[arg(long, conflicts_with = "start_at_next", help = "Start all clients at this wall-clock time, e.g. 10:01:01")]
+    start_at: Option<String>,
+    #[arg(long, default_value = "5s", help = "Start all clients at the next multiple of this interval")]
+    start_at_next: String,
+    #[arg(long, help = "Address of the aggregator [default: from the cluster config]")]
+    server: Option<String>,
+    #[arg(long, help = "Have the clients aggregate among themselves at their configured peer addresses")]
+    gossip: bool,
+    #[arg(long, requires = "gossip", help = "Have the peers elect a leader to aggregate each round")]
+    elect_leader: bool,
+    #[arg(long, help = "Path to the client binary [default: next to this launcher]")]
+    client_bin: Option<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let config = match ClusterConfig::load(cli.config.as_deref()) {
+        Ok(config) => config,
+        Err(e) => {
+            println!("Error:: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    if cli.gossip
+        && let Err(e) = config.peers()
+    {
+        println!("Error:: {}", e);
+        return ExitCode::FAILURE;
+    }
+    // Asked for here rather than by every client on a shared terminal, and
+    // before the start is picked so typing them cannot make anyone late.
+    // Clients with a password file read it themselves.
+    let passwords = config
+        .client_ids()
+        .filter(|id| config.password_file(id).is_none())
+        .map(|id| Ok((id, keys::key_password(id, None)?)))
+        .collect::<Result<HashMap<_, _>, String>>();
+    let passwords = match passwords {
+        Ok(passwords) => passwords,
+        Err(e) => {
+            println!("Error:: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    // The launcher picks one absolute start and hands it to every client, so
+    // a process that starts late cannot round to a different tick.
+    let start = match &cli.start_at {
+        Some(start_at) => schedule::parse_start_at(start_at, Local::now()),
+        None => schedule::parse_interval(&cli.start_at_next)
+            .map(|every| schedule::next_tick(Utc::now() + STARTUP_LEAD, every)),
+    };
+    let start = match start {
+        Ok(start) => start,
+        Err(e) => {
+            println!("Error:: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let client_bin = match cli.client_bin.clone().map_or_else(default_client_bin, Ok) {
+        Ok(client_bin) => client_bin,
+        Err(e) => {
+            println!("Error:: cannot locate the client binary: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    println!(
+        "Launching {} clients, starting at {}",
+        config.clients.len(),
+        start.with_timezone(&Local).format("%H:%M:%S%.3f")
+    );
+    let start_at = start.to_rfc3339_opts(chrono::SecondsFormat::Millis, true);
+    let mut children: Vec<(&str, Child)> = Vec::new();
+    for id in config.client_ids() {
+        // A password goes down the child's stdin, where no other process can
+        // read it, unlike its environment or arguments.
+        let password = passwords.get(id);
+        let spawned = Command::new(&client_bin)
+            .args(["--mode", "cache", "--times", &cli.times.to_string(), "--id", id, "--start-at", &start_at])
+            .args(cli.config.iter().flat_map(|config| ["--config".as_ref(), config.as_os_str()]))
+            .args(cli.server.iter().flat_map(|server| ["--server", server.as_str()]))
+            .args(cli.gossip.then_some("--gossip"))
+            .args(cli.elect_leader.then_some("--elect-leader"))
+            .args(password.map(|_| "--password-stdin"))
+            .env_remove(keys::PASSWORD_ENV)
+            .stdin(if password.is_some() { Stdio::piped() } else { Stdio::inherit() })
+            .spawn()
+            .and_then(|mut child| {
+                if let (Some(password), Some(mut stdin)) = (password, child.stdin.take()) {
+                    writeln!(stdin, "{}", password.as_str())?;
+                }
+                Ok(child)
+            });
+        match spawned {
+            Ok(child) => children.push((id, child)),
+            Err(e) => println!("Error:: failed to spawn {} from {}: {}", id, client_bin.display(), e),
+        }
+    }
+
+    let mut failed = config.clients.len() - children.len();
+    for (id, mut child) in children {
+        match child.wait() {
+            Ok(status) if status.success() => {}
+            Ok(status) => {
+                println!("{} exited with {}", id, status);
+                failed += 1;
+            }
+            Err(e) => {
+                println!("Error:: waiting for {}: {}", id, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        println!("❌ {} of {} client processes failed", failed, config.clients.len());
+        return ExitCode::FAILURE;
+    }
+    println!("✅ All {} client processes finished", config.clients.len());
+    ExitCode::SUCCESS
+}
+
+fn default_client_bin() -> std::io::Result<PathBuf> {
+    let launcher = std::env::current_exe()?;
+    Ok(launcher.with_file_name(format!("client{}", std::env::consts::EXE_SUFFIX)))
+}
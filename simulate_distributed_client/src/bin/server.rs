@@ -1,49 +1,209 @@
-use ed25519_dalek::{Signature, Verifier, VerifyingKey};
-use simulate_distributed_client::keys;
-use std::collections::HashMap;
-use std::sync::{Arc, Mutex};
-use tokio::io::AsyncReadExt;
+use clap::{Args, Parser, Subcommand};
+use simulate_distributed_client::aggregator::{self, Aggregator};
+use simulate_distributed_client::api;
+use simulate_distributed_client::audit::{self, AuditLog};
+use simulate_distributed_client::config::ClusterConfig;
+use simulate_distributed_client::keys::{self, KeySet};
+use simulate_distributed_client::robust::Strategy;
+use simulate_distributed_client::schedule;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::Arc;
 use tokio::net::TcpListener;
 
+#[derive(Debug, Parser)]
+#[command(about = "Aggregates signed client averages round by round", args_conflicts_with_subcommands = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+    #[command(flatten)]
+    run: RunArgs,
+    #[arg(long, global = true, help = "Cluster config [default: cluster.toml if present, else five local clients]")]
+    config: Option<PathBuf>,
+    #[arg(long, global = true, help = "Public keys of the aggregator and the clients [default: from the cluster config]")]
+    registry: Option<PathBuf>,
+    #[arg(long, global = true, default_value = audit::AUDIT_LOG_PATH, help = "Hash-chained log of every frame received from clients")]
+    audit_log: PathBuf,
+}
+
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Works with the audit log
+    Audit {
+        #[command(subcommand)]
+        command: AuditCommand,
+    },
+}
+
+#[derive(Debug, Subcommand)]
+enum AuditCommand {
+    /// Re-checks the hash chain, the signatures and the heads published in the round results
+    Verify {
+        #[arg(long, default_value = "rounds.jsonl", help = "Round results whose published audit heads must be in the log")]
+        results: PathBuf,
+    },
+}
+
+#[derive(Debug, Args)]
+struct RunArgs {
+    #[arg(short, long, help = "Averages needed for a round to count [default: every known client]")]
+    quorum: Option<usize>,
+    #[arg(long, default_value = "30s", help = "How long a round stays open after its first submission")]
+    round_timeout: String,
+    #[arg(long, default_value = "rounds.jsonl", help = "File the round results are appended to, and picked up from on restart")]
+    results: PathBuf,
+    #[arg(long, default_value = "2s", help = "How often the registry file is checked for rotated or revoked keys")]
+    reload_interval: String,
+    #[arg(long, help = "Directory holding the aggregator's encrypted key file [default: from the cluster config]")]
+    key_dir: Option<PathBuf>,
+    #[arg(long, help = "Address to accept client connections on [default: the aggregator address in the cluster config]")]
+    listen: Option<String>,
+    #[arg(
+        long,
+        help = "Serve the status API on this address, e.g. 127.0.0.1:8081: GET /status, GET /results?last=N and a websocket at /results/ws"
+    )]
+    http: Option<String>,
+    #[arg(
+        long,
+        default_value = "mean",
+        help = "How averages are combined: mean, median, trimmed-mean[:FRACTION] or mad[:THRESHOLD]"
+    )]
+    strategy: Strategy,
+}
+
 #[tokio::main]
-async fn main() {
-    let keys = keys::load_keys();
-    let mut pubkeys = HashMap::new();
-    for key in keys {
-        pubkeys.insert(key.client_id.clone(), key.public);
+async fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let config = match ClusterConfig::load(cli.config.as_deref()) {
+        Ok(mut config) => {
+            config.override_paths(cli.run.key_dir.as_deref(), cli.registry.as_deref());
+            config
+        }
+        Err(e) => {
+            println!("Error:: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    match cli.command {
+        Some(Command::Audit { command: AuditCommand::Verify { results } }) => verify_audit_log(&config, &cli.audit_log, &results),
+        None => serve(cli.run, config, &cli.audit_log).await,
     }
-    let listener = TcpListener::bind("127.0.0.1:8080").await.expect("failed to bind");
+}
+
+async fn serve(cli: RunArgs, config: ClusterConfig, audit_log: &Path) -> ExitCode {
+    let intervals = schedule::parse_interval(&cli.round_timeout)
+        .and_then(|deadline| Ok((deadline, schedule::parse_interval(&cli.reload_interval)?)));
+    let (deadline, reload_interval) = match intervals {
+        Ok(intervals) => intervals,
+        Err(e) => {
+            println!("Error:: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let registry = match keys::load_registry(&config.registry) {
+        Ok(registry) => registry,
+        Err(e) => {
+            println!("Error:: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let identity = match keys::password("Password for the aggregator key: ").and_then(|password| {
+        keys::load_signing_key(&config.key_path(keys::AGGREGATOR_ID), keys::AGGREGATOR_ID, &password)
+    }) {
+        Ok(identity) => identity,
+        Err(e) => {
+            println!("Error:: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let clients: BTreeSet<&str> = registry.clients.iter().map(|entry| entry.client_id.as_str()).collect();
+    let quorum = cli.quorum.unwrap_or(clients.len());
+    // Earlier results keep /results and the round numbers going across restarts.
+    let aggregator = aggregator::load_results(&cli.results).and_then(|history| {
+        let audit = AuditLog::open(audit_log, identity.clone())?;
+        Ok(Aggregator::new(identity, &registry, quorum)?
+            .with_strategy(cli.strategy)
+            .with_history(history)
+            .with_audit_log(audit))
+    });
+    let aggregator = match aggregator {
+        Ok(aggregator) => Arc::new(aggregator),
+        Err(e) => {
+            println!("Error:: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let listen = cli.listen.unwrap_or(config.aggregator);
+    let listener = match TcpListener::bind(&listen).await {
+        Ok(listener) => listener,
+        Err(e) => {
+            println!("Error:: cannot listen on {}: {}", listen, e);
+            return ExitCode::FAILURE;
+        }
+    };
     println!("Listening on {}", listener.local_addr().unwrap());
-    let prices = Arc::new(Mutex::new(Vec::new()));
-    loop {
-        let (mut stream, _address) = listener.accept().await.expect("failed to accept");
-        let prices = prices.clone();
-        let pubkeys = pubkeys.clone();
+    if let Some(http) = &cli.http {
+        let http_listener = match TcpListener::bind(http).await {
+            Ok(listener) => listener,
+            Err(e) => {
+                println!("Error:: cannot serve the status API on {}: {}", http, e);
+                return ExitCode::FAILURE;
+            }
+        };
+        println!("Status API on http://{}", http_listener.local_addr().unwrap());
+        let observed = aggregator.clone();
         tokio::spawn(async move {
-            let mut buffer = [0; 1024];
-            let len = stream.read(&mut buffer).await.expect("failed to read");
-            let signed: keys::SignedMessage = serde_json::from_slice(&buffer[..len]).unwrap();
-            if let Some(pubkey_hex) = pubkeys.get(&signed.client_id) {
-                let pubkey_bytes = hex::decode(pubkey_hex).unwrap();
-                let verifying_key = VerifyingKey::from_bytes(&pubkey_bytes.try_into().unwrap()).unwrap();
-
-                let message = format!("{}:{}", signed.client_id, signed.average);
-                let sig_bytes = hex::decode(&signed.signature).unwrap();
-                let signature = Signature::from_bytes(&sig_bytes.try_into().unwrap());
-
-                if verifying_key.verify(message.as_bytes(), &signature).is_ok() {
-                    println!("Received avg price of BTC: {} from {}", signed.average, signed.client_id);
-                    let mut guard = prices.lock().unwrap();
-                    guard.push(signed.average.parse::<f64>().unwrap());
-                    if guard.len() == 5 {
-                        let avg = guard.iter().sum::<f64>() / guard.len() as f64;
-                        println!("Final Aggregated Average from 5 clients: {}", avg);
-                        return;
-                    }
-                } else {
-                    println!("❌ Invalid signature from {:?}", signed);
-                }
+            if let Err(e) = api::serve(http_listener, observed).await {
+                println!("Error:: the status API stopped: {}", e);
             }
         });
     }
-}
\ No newline at end of file
+    let watcher = aggregator.clone();
+    let registry_path = config.registry.clone();
+    tokio::spawn(async move {
+        keys::watch_registry(&registry_path, reload_interval, |registry| watcher.reload(registry)).await;
+    });
+    let driver = aggregator.clone();
+    tokio::spawn(async move {
+        driver.run_rounds(deadline, Some(&cli.results)).await;
+    });
+    loop {
+        let (stream, _address) = listener.accept().await.expect("failed to accept");
+        let aggregator = aggregator.clone();
+        tokio::spawn(async move {
+            aggregator.handle_connection(stream).await;
+        });
+    }
+}
+
+/// Re-checks the audit log against the registry and the published round
+/// results, reporting every broken link, altered entry, bad signature and
+/// head missing from the log.
+fn verify_audit_log(config: &ClusterConfig, path: &Path, results: &Path) -> ExitCode {
+    let report = keys::load_registry(&config.registry).and_then(|registry| {
+        let mut report = audit::verify(path, &KeySet::new(&registry)?, &registry.aggregator_key()?)?;
+        report.check_heads(&aggregator::load_results(results)?);
+        Ok(report)
+    });
+    let report = match report {
+        Ok(report) => report,
+        Err(e) => {
+            println!("Error:: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    for problem in &report.problems {
+        println!("❌ {}", problem);
+    }
+    println!(
+        "{} entries: {} accepted, {} rejected ({} of them validly signed)",
+        report.entries, report.accepted, report.rejected, report.rejected_but_signed
+    );
+    if !report.problems.is_empty() {
+        println!("❌ {} problems in {}", report.problems.len(), path.display());
+        return ExitCode::FAILURE;
+    }
+    println!("✅ {}: chain intact, every accepted submission validly signed", path.display());
+    ExitCode::SUCCESS
+}
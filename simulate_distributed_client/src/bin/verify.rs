@@ -0,0 +1,75 @@
+use clap::Parser;
+use simulate_distributed_client::attestation;
+use simulate_distributed_client::config::ClusterConfig;
+use simulate_distributed_client::keys::{self, KeySet};
+use simulate_distributed_client::protocol::RoundResult;
+use std::collections::BTreeSet;
+use std::fs;
+use std::path::PathBuf;
+use std::process::ExitCode;
+
+#[derive(Debug, Parser)]
+#[command(about = "Checks that persisted round results are attested by a quorum of keys valid when each round closed")]
+struct Cli {
+    #[arg(long, default_value = "rounds.jsonl", help = "File of round results written by the server")]
+    results: PathBuf,
+    #[arg(short, long, help = "Signers required per round [default: every known client]")]
+    threshold: Option<usize>,
+    #[arg(long, help = "Cluster config [default: cluster.toml if present, else five local clients]")]
+    config: Option<PathBuf>,
+    #[arg(long, help = "Public keys of the clients [default: from the cluster config]")]
+    registry: Option<PathBuf>,
+}
+
+fn main() -> ExitCode {
+    let cli = Cli::parse();
+    let registry = ClusterConfig::load(cli.config.as_deref()).and_then(|mut config| {
+        config.override_paths(None, cli.registry.as_deref());
+        keys::load_registry(&config.registry)
+    });
+    let (keys, clients) = match registry.and_then(|registry| {
+        let clients: BTreeSet<String> = registry.clients.iter().map(|entry| entry.client_id.clone()).collect();
+        Ok((KeySet::new(&registry)?, clients.len()))
+    }) {
+        Ok(loaded) => loaded,
+        Err(e) => {
+            println!("Error:: {}", e);
+            return ExitCode::FAILURE;
+        }
+    };
+    let threshold = cli.threshold.unwrap_or(clients);
+    let data = match fs::read_to_string(&cli.results) {
+        Ok(data) => data,
+        Err(e) => {
+            println!("Error:: cannot read {}: {}", cli.results.display(), e);
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let mut failed = 0;
+    for (number, line) in data.lines().enumerate().filter(|(_, line)| !line.trim().is_empty()) {
+        let result: RoundResult = match serde_json::from_str(line) {
+            Ok(result) => result,
+            Err(e) => {
+                println!("❌ line {}: {}", number + 1, e);
+                failed += 1;
+                continue;
+            }
+        };
+        if result.average.is_none() {
+            println!("Round {} failed, nothing to verify", result.round_id);
+            continue;
+        }
+        match attestation::verify(&result, &keys, threshold) {
+            Ok(()) => println!("✅ Round {}: {:?} attested", result.round_id, result.average),
+            Err(e) => {
+                println!("❌ Round {}: {}", result.round_id, e);
+                failed += 1;
+            }
+        }
+    }
+    if failed > 0 {
+        return ExitCode::FAILURE;
+    }
+    ExitCode::SUCCESS
+}
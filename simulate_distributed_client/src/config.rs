@@ -0,0 +1,219 @@
+use crate::keys;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// Where every binary looks for the cluster config unless told otherwise.
+pub const CONFIG_PATH: &str = "cluster.toml";
+/// Cluster used when there is no config file: five clients and an
+/// aggregator on the local machine.
+const DEFAULT_CLIENTS: usize = 5;
+const DEFAULT_AGGREGATOR: &str = "127.0.0.1:8080";
+
+/// The shape of the cluster, shared by keygen, the client, the server and
+/// the launcher:
+///
+/// ```toml
+/// aggregator = "127.0.0.1:8080"
+/// registry = "registry.json"
+/// key_dir = "keys"
+///
+/// [[clients]]
+/// id = "client1"
+/// peer = "127.0.0.1:9001"
+///
+/// [[clients]]
+/// id = "client2"
+/// peer = "127.0.0.1:9002"
+/// key_file = "/secure/client2.key"
+/// password_file = "/secure/client2.password"
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClusterConfig {
+    /// Address the aggregator listens on and the clients connect to.
+    #[serde(default = "default_aggregator")]
+    pub aggregator: String,
+    #[serde(default = "default_registry")]
+    pub registry: PathBuf,
+    /// Where key files go unless a client names its own.
+    #[serde(default = "default_key_dir")]
+    pub key_dir: PathBuf,
+    pub clients: Vec<ClientConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ClientConfig {
+    pub id: String,
+    /// The client's encrypted key file [default: `<key_dir>/<id>.key`].
+    #[serde(default)]
+    pub key_file: Option<PathBuf>,
+    /// Address the client listens on for the other clients in gossip mode.
+    #[serde(default)]
+    pub peer: Option<String>,
+    /// File holding the password of the client's key file, readable by its
+    /// owner only [default: asked for].
+    #[serde(default)]
+    pub password_file: Option<PathBuf>,
+}
+
+fn default_aggregator() -> String {
+    DEFAULT_AGGREGATOR.to_string()
+}
+
+fn default_registry() -> PathBuf {
+    PathBuf::from(keys::REGISTRY_PATH)
+}
+
+fn default_key_dir() -> PathBuf {
+    PathBuf::from(keys::KEY_DIR)
+}
+
+impl Default for ClusterConfig {
+    fn default() -> Self {
+        Self {
+            aggregator: default_aggregator(),
+            registry: default_registry(),
+            key_dir: default_key_dir(),
+            clients: (1..=DEFAULT_CLIENTS)
+                .map(|i| ClientConfig {
+                    id: format!("client{}", i),
+                    key_file: None,
+                    peer: None,
+                    password_file: None,
+                })
+                .collect(),
+        }
+    }
+}
+
+impl ClusterConfig {
+    /// Reads the config at `path`, or at [`CONFIG_PATH`] if there is one,
+    /// falling back to the default cluster.
+    pub fn load(path: Option<&Path>) -> Result<Self, String> {
+        let path = match path {
+            Some(path) => path,
+            None if Path::new(CONFIG_PATH).exists() => Path::new(CONFIG_PATH),
+            None => return Ok(Self::default()),
+        };
+        let data = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+        Self::parse(&data).map_err(|e| format!("invalid cluster config {}: {}", path.display(), e))
+    }
+
+    pub fn parse(data: &str) -> Result<Self, String> {
+        let config: Self = toml::from_str(data).map_err(|e| e.message().to_string())?;
+        config.validate()?;
+        Ok(config)
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        if self.clients.is_empty() {
+            return Err("no clients listed".to_string());
+        }
+        let mut ids = HashSet::new();
+        let mut peers = HashSet::new();
+        for client in &self.clients {
+            if client.id.is_empty() || client.id == keys::AGGREGATOR_ID {
+                return Err(format!("'{}' cannot be used as a client id", client.id));
+            }
+            if !ids.insert(client.id.as_str()) {
+                return Err(format!("client {} is listed twice", client.id));
+            }
+            if let Some(peer) = &client.peer
+                && !peers.insert(peer.as_str())
+            {
+                return Err(format!("peer address {} is used twice", peer));
+            }
+        }
+        Ok(())
+    }
+
+    /// Replaces the paths from the file with those given on the command line.
+    pub fn override_paths(&mut self, key_dir: Option<&Path>, registry: Option<&Path>) {
+        if let Some(key_dir) = key_dir {
+            self.key_dir = key_dir.to_path_buf();
+        }
+        if let Some(registry) = registry {
+            self.registry = registry.to_path_buf();
+        }
+    }
+
+    pub fn client(&self, client_id: &str) -> Result<&ClientConfig, String> {
+        self.clients
+            .iter()
+            .find(|client| client.id == client_id)
+            .ok_or_else(|| format!("{} is not in the cluster config", client_id))
+    }
+
+    pub fn client_ids(&self) -> impl Iterator<Item = &str> {
+        self.clients.iter().map(|client| client.id.as_str())
+    }
+
+    /// The key file of `client_id`, or of the aggregator for
+    /// [`keys::AGGREGATOR_ID`].
+    pub fn key_path(&self, client_id: &str) -> PathBuf {
+        let key_file = self
+            .clients
+            .iter()
+            .find(|client| client.id == client_id)
+            .and_then(|client| client.key_file.clone());
+        key_file.unwrap_or_else(|| keys::key_path(&self.key_dir, client_id))
+    }
+
+    /// Where the password of `client_id`'s key file is kept, if anywhere.
+    pub fn password_file(&self, client_id: &str) -> Option<&Path> {
+        self.clients
+            .iter()
+            .find(|client| client.id == client_id)
+            .and_then(|client| client.password_file.as_deref())
+    }
+
+    /// Every client's gossip address, which gossip mode needs for all of them.
+    pub fn peers(&self) -> Result<BTreeMap<String, String>, String> {
+        self.clients
+            .iter()
+            .map(|client| {
+                let peer = client
+                    .peer
+                    .clone()
+                    .ok_or_else(|| format!("{} has no peer address in the cluster config", client.id))?;
+                Ok((client.id.clone(), peer))
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parses_clusters_of_any_size_with_defaults() {
+        let clients: String = (1..=7).map(|i| format!("[[clients]]\nid = \"node{}\"\npeer = \"127.0.0.1:{}\"\n", i, 9000 + i)).collect();
+        let config = ClusterConfig::parse(&format!("aggregator = \"10.0.0.1:7000\"\n{}", clients)).unwrap();
+        assert_eq!(config.clients.len(), 7);
+        assert_eq!(config.aggregator, "10.0.0.1:7000");
+        assert_eq!(config.registry, PathBuf::from(keys::REGISTRY_PATH));
+        assert_eq!(config.key_path("node3"), PathBuf::from("keys/node3.key"));
+        assert_eq!(config.peers().unwrap()["node7"], "127.0.0.1:9007");
+
+        let config = ClusterConfig::parse("[[clients]]\nid = \"a\"\nkey_file = \"/secure/a.key\"\npassword_file = \"/secure/a.pw\"\n").unwrap();
+        assert_eq!(config.key_path("a"), PathBuf::from("/secure/a.key"));
+        assert_eq!(config.password_file("a"), Some(Path::new("/secure/a.pw")));
+        assert_eq!(config.peers(), Err("a has no peer address in the cluster config".to_string()));
+        assert_eq!(ClusterConfig::default().client_ids().count(), 5);
+    }
+
+    #[test]
+    fn test_rejects_inconsistent_configs() {
+        let twice = "[[clients]]\nid = \"a\"\n[[clients]]\nid = \"a\"\n";
+        assert_eq!(ClusterConfig::parse(twice), Err("client a is listed twice".to_string()));
+        let shared = "[[clients]]\nid = \"a\"\npeer = \"x:1\"\n[[clients]]\nid = \"b\"\npeer = \"x:1\"\n";
+        assert_eq!(ClusterConfig::parse(shared), Err("peer address x:1 is used twice".to_string()));
+        assert!(ClusterConfig::parse("clients = []").is_err());
+        assert!(ClusterConfig::parse("[[clients]]\nid = \"aggregator\"\n").is_err());
+        assert!(ClusterConfig::parse("[[clients]]\nid = \"a\"\nport = 1\n").is_err());
+    }
+}
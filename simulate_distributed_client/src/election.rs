@@ -0,0 +1,84 @@
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
+use tokio::time::Instant;
+
+/// How often a peer tells the others it is alive.
+pub const HEARTBEAT_INTERVAL: Duration = Duration::from_millis(250);
+/// How long a peer counts as alive after it was last heard from.
+pub const LEASE: Duration = Duration::from_secs(1);
+
+/// A bully election driven by heartbeats instead of election messages: the
+/// leader is the highest client id among the peers heard from within the
+/// [`LEASE`], this one included. Peers agree on the leader once their
+/// heartbeats have spread, and a leader that dies is replaced by the next
+/// highest as soon as its lease runs out.
+#[derive(Debug)]
+pub struct Liveness {
+    client_id: String,
+    started: Instant,
+    last_seen: HashMap<String, Instant>,
+}
+
+impl Liveness {
+    pub fn new(client_id: &str, now: Instant) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            started: now,
+            last_seen: HashMap::new(),
+        }
+    }
+
+    pub fn seen(&mut self, peer_id: &str, at: Instant) {
+        self.last_seen.insert(peer_id.to_string(), at);
+    }
+
+    /// The peers heard from within the lease, this one included.
+    pub fn alive(&self, now: Instant) -> BTreeSet<&str> {
+        self.last_seen
+            .iter()
+            .filter(|(_, seen)| now.saturating_duration_since(**seen) < LEASE)
+            .map(|(peer_id, _)| peer_id.as_str())
+            .chain([self.client_id.as_str()])
+            .collect()
+    }
+
+    /// `None` for the first lease after starting, when a quiet peer may just
+    /// not have been heard from yet.
+    pub fn leader(&self, now: Instant) -> Option<&str> {
+        if now.saturating_duration_since(self.started) < LEASE {
+            return None;
+        }
+        self.alive(now).last().copied()
+    }
+
+    /// When [`Liveness::leader`] first has an answer.
+    pub fn settled_at(&self) -> Instant {
+        self.started + LEASE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_highest_live_peer_leads_and_a_dead_leader_is_replaced() {
+        let start = Instant::now();
+        let mut liveness = Liveness::new("client2", start);
+        liveness.seen("client1", start);
+        liveness.seen("client3", start);
+        assert_eq!(liveness.leader(start), None);
+
+        let settled = liveness.settled_at();
+        liveness.seen("client1", settled);
+        liveness.seen("client3", settled - Duration::from_millis(1));
+        assert_eq!(liveness.leader(settled), Some("client3"));
+        assert_eq!(liveness.alive(settled).len(), 3);
+
+        // client3 goes quiet; client2 outranks the remaining client1.
+        let later = settled + LEASE - Duration::from_millis(1);
+        liveness.seen("client1", later);
+        assert_eq!(liveness.leader(later), Some("client2"));
+        assert_eq!(liveness.alive(later), BTreeSet::from(["client1", "client2"]));
+    }
+}
@@ -0,0 +1,927 @@
+use crate::aggregator::{HANDSHAKE_TIMEOUT, MAX_CLOCK_SKEW};
+use crate::attestation::{self, Signer};
+use crate::election::{self, Liveness};
+use crate::keys::{self, KeySet, Registry, SignedMessage};
+use crate::protocol::{self, Message, Proposal, RoundResult};
+use crate::robust::{self, Strategy};
+use crate::transport;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use futures_util::{SinkExt, StreamExt};
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Notify, broadcast};
+use tokio::task::{AbortHandle, JoinSet};
+use tokio::time::Instant;
+
+/// How long a peer waits before dialling a peer that was not reachable again.
+const REDIAL_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Another peer, as this one dials it.
+#[derive(Debug, Clone)]
+struct Remote {
+    address: String,
+    key: VerifyingKey,
+}
+
+/// What a peer has heard about one round.
+#[derive(Debug, Default)]
+struct GossipRound {
+    inputs: BTreeMap<String, SignedMessage>,
+    /// Checked BLS signatures by signer and [`attestation::digest`] of the
+    /// result they are over, which is one this peer signed or was proposed.
+    /// A peer may sign more than one result in a led round, when a new leader
+    /// proposes from a different set of inputs.
+    attestations: BTreeMap<(Signer, String), String>,
+    /// The results the other peers computed, or proposed for endorsement in a
+    /// led round, by proposer.
+    proposals: BTreeMap<String, Proposal>,
+    /// The results this peer signed.
+    endorsed: Vec<RoundResult>,
+    /// The final result of a led round, as announced by its leader.
+    result: Option<RoundResult>,
+}
+
+impl GossipRound {
+    /// The results signatures are checked against.
+    fn known_results(&self) -> impl Iterator<Item = &RoundResult> {
+        self.endorsed.iter().chain(self.proposals.values().map(|proposal| &proposal.result))
+    }
+
+    fn signers(&self) -> BTreeSet<&Signer> {
+        self.attestations.keys().map(|(signer, _)| signer).collect()
+    }
+
+    /// Drops the signatures over results no longer proposed, once a peer
+    /// replaced its proposal.
+    fn prune_attestations(&mut self) {
+        let digests: HashSet<String> = self.known_results().map(attestation::digest).collect();
+        self.attestations.retain(|(_, digest), _| digests.contains(digest));
+    }
+}
+
+/// What a peer concluded for a round.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Outcome {
+    /// This peer's result, attested by the peers that computed the same one,
+    /// or in a led round the leader's result.
+    pub result: RoundResult,
+    /// Peers whose signed result differs from this peer's.
+    pub disagreeing: Vec<String>,
+    /// The peer that decided a led round.
+    pub leader: Option<String>,
+}
+
+/// One client in gossip mode, where the clients aggregate among themselves
+/// instead of relying on an aggregator. Every peer sends its signed average
+/// to all others and relays what it hears, computes the round result from
+/// the averages it collected, and signs it as in [`attestation`]. Peers that
+/// computed the same result end up with the same attested [`RoundResult`],
+/// so any of them can stand in for the aggregator.
+///
+/// Alternatively a round can be led: the peers elect a leader (see
+/// [`election`]) that acts as the aggregator for the round, proposing the
+/// result and collecting the endorsements. As every peer already holds the
+/// gossiped averages, the next leader can complete the round if the current
+/// one dies halfway.
+pub struct Peer {
+    client_id: String,
+    identity: SigningKey,
+    keys: RwLock<KeySet>,
+    /// The other peers by client id.
+    remotes: BTreeMap<String, Remote>,
+    quorum: usize,
+    strategy: Strategy,
+    rounds: Mutex<HashMap<u64, GossipRound>>,
+    /// The round this peer takes part in or is about to, see
+    /// [`Peer::join_round`].
+    current: Mutex<u64>,
+    /// Highest round this peer has finished; later messages for it are dropped.
+    finished: Mutex<u64>,
+    /// Woken on every new input and attestation.
+    activity: Notify,
+    /// Everything to send to the other peers.
+    outbound: broadcast::Sender<Message>,
+    /// Heartbeats, apart from the rest so that piling up for a peer that is
+    /// down they cannot push gossip out of `outbound`.
+    heartbeats: broadcast::Sender<Message>,
+    liveness: Mutex<Liveness>,
+    /// The tasks started by [`Peer::start`].
+    tasks: Mutex<Vec<AbortHandle>>,
+}
+
+impl Peer {
+    /// A peer for `client_id`, reaching the others at `peers`, keyed by client
+    /// id. A result counts once `quorum` peers, this one included, took part.
+    pub fn new(
+        client_id: &str,
+        identity: SigningKey,
+        registry: &Registry,
+        peers: &BTreeMap<String, String>,
+        quorum: usize,
+    ) -> Result<Self, String> {
+        let keys = KeySet::new(registry)?;
+        keys.key(&keys::key_id(&identity.verifying_key()), client_id, Utc::now())?;
+        let remotes = peers
+            .iter()
+            .filter(|(peer_id, _)| *peer_id != client_id)
+            .map(|(peer_id, address)| {
+                let entry = registry
+                    .current_key(peer_id)
+                    .ok_or_else(|| format!("peer {} has no current key in the registry", peer_id))?;
+                let remote = Remote {
+                    address: address.clone(),
+                    key: entry.verifying_key()?,
+                };
+                Ok((peer_id.clone(), remote))
+            })
+            .collect::<Result<BTreeMap<_, _>, String>>()?;
+        if quorum == 0 || quorum > remotes.len() + 1 {
+            return Err(format!("quorum must be between 1 and {}, got {}", remotes.len() + 1, quorum));
+        }
+        Ok(Self {
+            client_id: client_id.to_string(),
+            identity,
+            keys: RwLock::new(keys),
+            remotes,
+            quorum,
+            strategy: Strategy::Mean,
+            rounds: Mutex::new(HashMap::new()),
+            current: Mutex::new(1),
+            finished: Mutex::new(0),
+            activity: Notify::new(),
+            outbound: broadcast::channel(256).0,
+            heartbeats: broadcast::channel(1).0,
+            liveness: Mutex::new(Liveness::new(client_id, Instant::now())),
+            tasks: Mutex::new(Vec::new()),
+        })
+    }
+
+    pub fn with_strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// Replaces the keys gossip is checked with, e.g. after a key was
+    /// revoked. The peers' addresses and transport keys stay as they are.
+    pub fn reload(&self, registry: &Registry) -> Result<(), String> {
+        *self.keys.write().unwrap() = KeySet::new(registry)?;
+        Ok(())
+    }
+
+    /// Moves this peer on to `round_id`: from now on it takes in gossip about
+    /// that round and the next only, and forgets what it heard about earlier
+    /// ones. A peer starts out in round 1; one joining a later round should
+    /// be told before it is started, so that it does not drop early gossip.
+    pub fn join_round(&self, round_id: u64) {
+        *self.current.lock().unwrap() = round_id;
+        self.rounds.lock().unwrap().retain(|known, _| *known >= round_id);
+    }
+
+    /// Accepts the other peers on `listener`, starts dialling each of them,
+    /// retrying until they are up, and sends heartbeats.
+    pub fn start(self: &Arc<Self>, listener: TcpListener) {
+        let mut tasks = self.tasks.lock().unwrap();
+        for (peer_id, remote) in &self.remotes {
+            let dialler = dial(
+                self.identity.clone(),
+                peer_id.clone(),
+                remote.clone(),
+                self.max_frame_length(),
+                self.outbound.subscribe(),
+                self.heartbeats.subscribe(),
+            );
+            tasks.push(tokio::spawn(dialler).abort_handle());
+        }
+        let (client_id, heartbeats) = (self.client_id.clone(), self.heartbeats.clone());
+        let heartbeat = tokio::spawn(async move {
+            let mut interval = tokio::time::interval(election::HEARTBEAT_INTERVAL);
+            loop {
+                interval.tick().await;
+                let _ = heartbeats.send(Message::Heartbeat { client_id: client_id.clone() });
+            }
+        });
+        tasks.push(heartbeat.abort_handle());
+        let peer = self.clone();
+        let acceptor = tokio::spawn(async move {
+            // Owned here so that stopping the peer also ends its connections.
+            let mut connections = JoinSet::new();
+            loop {
+                while connections.try_join_next().is_some() {}
+                let Ok((stream, _address)) = listener.accept().await else {
+                    continue;
+                };
+                let peer = peer.clone();
+                connections.spawn(async move { peer.handle_connection(stream).await });
+            }
+        });
+        tasks.push(acceptor.abort_handle());
+    }
+
+    /// Stops everything [`Peer::start`] started, as if the peer's process
+    /// died: the others stop hearing from it and cannot reach it any more.
+    pub fn stop(&self) {
+        for task in self.tasks.lock().unwrap().drain(..) {
+            task.abort();
+        }
+    }
+
+    /// Frames sized for this peer and all the others.
+    fn max_frame_length(&self) -> usize {
+        protocol::max_frame_length(self.remotes.len() + 1)
+    }
+
+    fn leader(&self) -> Option<String> {
+        self.liveness.lock().unwrap().leader(Instant::now()).map(str::to_string)
+    }
+
+    /// Takes part in `round_id` with `average`: gossips it, waits until every
+    /// peer's average arrived or `deadline` passes, computes, gossips and
+    /// signs the result, then waits the same way for the other peers'
+    /// signatures.
+    pub async fn run_round(&self, round_id: u64, average: f64, deadline: Duration) -> Result<Outcome, String> {
+        let everyone = self.remotes.len() + 1;
+        self.join_round(round_id);
+        self.receive_input(SignedMessage::sign(&self.client_id, average, round_id, &self.identity), Utc::now())?;
+        self.wait(deadline, || self.with_round(round_id, |round| round.inputs.len()) >= everyone).await;
+
+        let mut result = self.aggregate(round_id);
+        if result.average.is_none() {
+            self.finish(round_id);
+            return Ok(Outcome { result, disagreeing: Vec::new(), leader: None });
+        }
+        // The others need the result to check this peer's signature against.
+        self.propose(&result);
+        self.endorse(&result)?;
+        self.wait(deadline, || self.with_round(round_id, |round| round.signers().len()) >= everyone).await;
+
+        let agreeing = self.endorsements(&result);
+        let disagreeing = self
+            .finish(round_id)
+            .signers()
+            .into_iter()
+            .filter(|signer| !agreeing.contains_key(*signer))
+            .map(|signer| signer.client_id.clone())
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect();
+        if agreeing.len() >= self.quorum {
+            result.attestation = Some(attestation::combine(&agreeing)?);
+        }
+        Ok(Outcome { result, disagreeing, leader: None })
+    }
+
+    /// Takes part in `round_id` with `average` under an elected leader: the
+    /// average is gossiped, then this peer endorses what its leader proposes
+    /// until the leader announces the attested result, or leads the round
+    /// itself once it is the highest peer alive. Gives up after four
+    /// `deadline`s.
+    pub async fn run_led_round(&self, round_id: u64, average: f64, deadline: Duration) -> Result<Outcome, String> {
+        let own = SignedMessage::sign(&self.client_id, average, round_id, &self.identity);
+        self.join_round(round_id);
+        self.receive_input(own.clone(), Utc::now())?;
+        let settled_at = self.liveness.lock().unwrap().settled_at();
+        tokio::time::sleep_until(settled_at).await;
+        let gives_up = Instant::now() + deadline * 4;
+        let mut endorsed = HashSet::new();
+        loop {
+            if let Some(result) = self.with_round(round_id, |round| round.result.clone()) {
+                self.finish(round_id);
+                let leader = self.leader();
+                return Ok(Outcome { result, disagreeing: Vec::new(), leader });
+            }
+            let Some(leader) = self.leader() else {
+                return Err("no leader".to_string());
+            };
+            if leader == self.client_id {
+                return self.lead(round_id, deadline).await;
+            }
+            let proposal = self.with_round(round_id, |round| round.proposals.get(&leader).cloned());
+            if let Some(proposal) = proposal
+                && endorsed.insert(attestation::round_message(&proposal.result))
+            {
+                let checked = attestation::check_proposal(&proposal, &own, &self.keys.read().unwrap());
+                match checked {
+                    Ok(()) => self.endorse(&proposal.result)?,
+                    Err(e) => println!("❌ {} refuses to endorse round {} as proposed by {}: {}", self.client_id, round_id, leader, e),
+                }
+            }
+            if Instant::now() >= gives_up {
+                self.finish(round_id);
+                return Err(format!("round {} was not decided in time", round_id));
+            }
+            // Woken by gossip, and at least every heartbeat to notice a leader
+            // that went quiet.
+            let _ = tokio::time::timeout(election::HEARTBEAT_INTERVAL, self.activity.notified()).await;
+        }
+    }
+
+    /// Acts as the aggregator for `round_id`: waits for the averages of every
+    /// peer alive, proposes the result, waits for the live contributors to
+    /// endorse it and announces it, attested if at least the quorum did.
+    async fn lead(&self, round_id: u64, deadline: Duration) -> Result<Outcome, String> {
+        println!("{} leads round {}", self.client_id, round_id);
+        let submitted = |round: &mut GossipRound| {
+            let liveness = self.liveness.lock().unwrap();
+            liveness.alive(Instant::now()).iter().all(|peer_id| round.inputs.contains_key(*peer_id))
+        };
+        self.wait(deadline, || self.with_round(round_id, submitted)).await;
+
+        let mut result = self.aggregate(round_id);
+        if result.average.is_some() {
+            self.propose(&result);
+            self.endorse(&result)?;
+            let endorsers = || {
+                let liveness = self.liveness.lock().unwrap();
+                let alive = liveness.alive(Instant::now());
+                result.contributors.iter().filter(|client_id| alive.contains(client_id.as_str())).count()
+            };
+            self.wait(deadline, || self.endorsements(&result).len() >= endorsers()).await;
+            let endorsements = self.endorsements(&result);
+            if endorsements.len() >= self.quorum {
+                result.attestation = Some(attestation::combine(&endorsements)?);
+            }
+        }
+        self.finish(round_id);
+        let _ = self.outbound.send(Message::RoundResult(result.clone()));
+        Ok(Outcome { result, disagreeing: Vec::new(), leader: Some(self.client_id.clone()) })
+    }
+
+    /// Gossips `result` with the inputs it was computed from.
+    fn propose(&self, result: &RoundResult) {
+        let inputs = self.with_round(result.round_id, |round| round.inputs.values().cloned().collect());
+        let _ = self.outbound.send(Message::Proposal(Proposal { result: result.clone(), inputs }));
+    }
+
+    /// Signs `result` and gossips the signature.
+    fn endorse(&self, result: &RoundResult) -> Result<(), String> {
+        self.with_round(result.round_id, |round| round.endorsed.push(result.clone()));
+        let signature = attestation::sign(result, &attestation::secret_key(&self.identity));
+        let key_id = keys::key_id(&self.identity.verifying_key());
+        self.receive_attestation(result.round_id, &self.client_id, &key_id, &signature, Utc::now())?;
+        Ok(())
+    }
+
+    /// The signatures collected for `result`'s round that are over `result`,
+    /// one per signer.
+    fn endorsements(&self, result: &RoundResult) -> BTreeMap<Signer, String> {
+        let digest = attestation::digest(result);
+        self.with_round(result.round_id, |round| {
+            round
+                .attestations
+                .iter()
+                .filter(|((_, over), _)| *over == digest)
+                .map(|((signer, _), signature)| (signer.clone(), signature.clone()))
+                .collect()
+        })
+    }
+
+    async fn wait(&self, deadline: Duration, done: impl Fn() -> bool) {
+        let closes_at = tokio::time::Instant::now() + deadline;
+        while !done() {
+            if tokio::time::timeout_at(closes_at, self.activity.notified()).await.is_err() {
+                break;
+            }
+        }
+    }
+
+    fn with_round<T>(&self, round_id: u64, f: impl FnOnce(&mut GossipRound) -> T) -> T {
+        f(self.rounds.lock().unwrap().entry(round_id).or_default())
+    }
+
+    fn finish(&self, round_id: u64) -> GossipRound {
+        let mut finished = self.finished.lock().unwrap();
+        *finished = (*finished).max(round_id);
+        self.rounds.lock().unwrap().remove(&round_id).unwrap_or_default()
+    }
+
+    /// The result of the averages collected so far, as the aggregator would
+    /// compute it.
+    fn aggregate(&self, round_id: u64) -> RoundResult {
+        let (averages, last_signed): (BTreeMap<String, f64>, Option<i64>) = self.with_round(round_id, |round| {
+            let averages = round
+                .inputs
+                .iter()
+                .filter_map(|(client_id, input)| Some((client_id.clone(), input.average_value().ok()?)))
+                .collect();
+            (averages, round.inputs.values().map(|input| input.timestamp).max())
+        });
+        let aggregate = (averages.len() >= self.quorum)
+            .then(|| robust::aggregate(self.strategy, &averages))
+            .flatten();
+        let (average, excluded) = match aggregate {
+            Some(aggregate) => (Some(aggregate.value), aggregate.excluded),
+            None => (None, Vec::new()),
+        };
+        // Peers that collected the same inputs must sign the same closing
+        // time, so it is that of the latest input rather than the clock's.
+        let closed_at = last_signed.and_then(DateTime::from_timestamp_millis).unwrap_or_else(Utc::now);
+        RoundResult {
+            round_id,
+            closed_at,
+            contributors: averages.into_keys().collect(),
+            average,
+            strategy: self.strategy.to_string(),
+            excluded,
+            audit_head: None,
+            attestation: None,
+        }
+    }
+
+    /// Whether gossip about `round_id` matters: it must be about the current
+    /// round or the next, see [`Peer::join_round`], so that peers cannot
+    /// make this one keep state for any round they like. Late messages for a
+    /// round this peer has finished are dropped.
+    fn is_open(&self, round_id: u64) -> bool {
+        let current = *self.current.lock().unwrap();
+        round_id > *self.finished.lock().unwrap() && (round_id == current || round_id == current + 1)
+    }
+
+    /// Records a signed average and relays it to the other peers. Returns
+    /// whether it was new; a second, different average from the same client
+    /// for a round is refused.
+    fn receive_input(&self, signed: SignedMessage, now: DateTime<Utc>) -> Result<bool, String> {
+        if !self.is_open(signed.round_id) {
+            return Ok(false);
+        }
+        let verifying_key = self.keys.read().unwrap().key(&signed.key_id, &signed.client_id, now)?.verifying_key;
+        signed.verify(&verifying_key)?;
+        signed.average_value()?;
+        let signed_at = DateTime::from_timestamp_millis(signed.timestamp).ok_or("timestamp out of range")?;
+        if (now - signed_at).abs() > MAX_CLOCK_SKEW {
+            return Err(format!("stale message signed at {}", signed_at.to_rfc3339()));
+        }
+
+        let mut rounds = self.rounds.lock().unwrap();
+        let round = rounds.entry(signed.round_id).or_default();
+        match round.inputs.get(&signed.client_id) {
+            Some(known) if *known == signed => return Ok(false),
+            Some(_) => {
+                return Err(format!("{} sent two different averages for round {}", signed.client_id, signed.round_id));
+            }
+            None => {}
+        }
+        round.inputs.insert(signed.client_id.clone(), signed.clone());
+        drop(rounds);
+        let _ = self.outbound.send(Message::SignedAverage(signed));
+        self.activity.notify_one();
+        Ok(true)
+    }
+
+    /// Records a peer's signature over a result and relays it, keeping one
+    /// per signer and result. Only signatures over a result this peer signed
+    /// or was proposed are kept, with a key valid when that result's round
+    /// closed; others are dropped unheard, as the peer may just not have the
+    /// result yet. Hence a new signature over a result this peer signed is
+    /// answered with this peer's own again, for a signer that dropped it.
+    fn receive_attestation(
+        &self,
+        round_id: u64,
+        client_id: &str,
+        key_id: &str,
+        signature: &str,
+        now: DateTime<Utc>,
+    ) -> Result<bool, String> {
+        if !self.is_open(round_id) {
+            return Ok(false);
+        }
+        self.keys.read().unwrap().key(key_id, client_id, now)?;
+        let signer = Signer {
+            client_id: client_id.to_string(),
+            key_id: key_id.to_string(),
+        };
+        let mut rounds = self.rounds.lock().unwrap();
+        let round = rounds.entry(round_id).or_default();
+        let keys = self.keys.read().unwrap();
+        let signed = round.known_results().find(|result| {
+            keys.key(key_id, client_id, result.closed_at)
+                .is_ok_and(|record| attestation::check_signature(result, signature, &record.bls_key).is_ok())
+        });
+        let Some(digest) = signed.map(attestation::digest) else {
+            return Ok(false);
+        };
+        drop(keys);
+        if round.attestations.contains_key(&(signer.clone(), digest.clone())) {
+            return Ok(false);
+        }
+        let own_key_id = keys::key_id(&self.identity.verifying_key());
+        let own = Signer {
+            client_id: self.client_id.clone(),
+            key_id: own_key_id.clone(),
+        };
+        let echo = (signer != own)
+            .then(|| round.attestations.get(&(own, digest.clone())).cloned())
+            .flatten();
+        round.attestations.insert((signer, digest), signature.to_string());
+        drop(rounds);
+        let _ = self.outbound.send(Message::Attest {
+            round_id,
+            client_id: client_id.to_string(),
+            key_id: key_id.to_string(),
+            signature: signature.to_string(),
+        });
+        if let Some(own_signature) = echo {
+            let _ = self.outbound.send(Message::Attest {
+                round_id,
+                client_id: self.client_id.clone(),
+                key_id: own_key_id,
+                signature: own_signature,
+            });
+        }
+        self.activity.notify_one();
+        Ok(true)
+    }
+
+    /// Records and relays a result announced by another peer, provided it is
+    /// attested by the quorum. A result the quorum did not endorse is not
+    /// taken, even from the leader: with leases out of step, two peers may
+    /// both lead for a while and announce different results.
+    fn receive_result(&self, result: RoundResult) -> Result<bool, String> {
+        if !self.is_open(result.round_id) {
+            return Ok(false);
+        }
+        attestation::verify(&result, &self.keys.read().unwrap(), self.quorum)?;
+        let mut rounds = self.rounds.lock().unwrap();
+        let round = rounds.entry(result.round_id).or_default();
+        if round.result.is_some() {
+            return Ok(false);
+        }
+        round.result = Some(result.clone());
+        drop(rounds);
+        let _ = self.outbound.send(Message::RoundResult(result));
+        self.activity.notify_one();
+        Ok(true)
+    }
+
+    /// Takes in what another peer gossips. Peers only ever send, so nothing
+    /// is answered; rejected messages are reported here.
+    async fn handle_connection(&self, stream: TcpStream) {
+        let handshake = transport::accept(stream, &self.identity, |static_public| {
+            self.keys
+                .read()
+                .unwrap()
+                .by_static(static_public, Utc::now())
+                .filter(|record| self.remotes.contains_key(&record.client_id))
+                .map(|record| record.client_id.clone())
+        });
+        let (mut framed, peer_id) = match tokio::time::timeout(HANDSHAKE_TIMEOUT, handshake).await {
+            Ok(Ok(accepted)) => accepted,
+            Ok(Err(e)) => {
+                println!("❌ {} dropping a peer connection: {}", self.client_id, e);
+                return;
+            }
+            Err(_) => {
+                println!("❌ {} dropping a peer connection: handshake timed out", self.client_id);
+                return;
+            }
+        };
+        framed.codec_mut().set_max_frame_length(self.max_frame_length());
+        while let Some(frame) = framed.next().await {
+            if frame.is_ok() {
+                self.liveness.lock().unwrap().seen(&peer_id, Instant::now());
+            }
+            let received = match frame {
+                Ok(Message::Heartbeat { client_id }) if client_id == peer_id => Ok(false),
+                Ok(Message::SignedAverage(signed)) => self.receive_input(signed, Utc::now()),
+                Ok(Message::Attest { round_id, client_id, key_id, signature }) => {
+                    self.receive_attestation(round_id, &client_id, &key_id, &signature, Utc::now())
+                }
+                Ok(Message::Proposal(proposal)) if self.is_open(proposal.result.round_id) => {
+                    let mut rounds = self.rounds.lock().unwrap();
+                    let round = rounds.entry(proposal.result.round_id).or_default();
+                    if round.proposals.insert(peer_id.clone(), proposal).is_some() {
+                        round.prune_attestations();
+                    }
+                    drop(rounds);
+                    self.activity.notify_one();
+                    Ok(true)
+                }
+                Ok(Message::Proposal(_)) => Ok(false),
+                Ok(Message::RoundResult(result)) => self.receive_result(result),
+                Ok(other) => Err(format!("unexpected {:?} from a peer", other)),
+                Err(e) => {
+                    println!("❌ {} dropping the connection from {}: {}", self.client_id, peer_id, e);
+                    return;
+                }
+            };
+            if let Err(reason) = received {
+                println!("❌ {} rejected gossip from {}: {}", self.client_id, peer_id, reason);
+            }
+        }
+    }
+}
+
+/// Forwards everything the peer gossips, and its heartbeats, to `remote`,
+/// dialling it again whenever the connection is lost, until the peer is
+/// dropped.
+async fn dial(
+    identity: SigningKey,
+    peer_id: String,
+    remote: Remote,
+    max_frame_length: usize,
+    mut outbound: broadcast::Receiver<Message>,
+    mut heartbeats: broadcast::Receiver<Message>,
+) {
+    loop {
+        let connected = match TcpStream::connect(&remote.address).await {
+            Ok(stream) => transport::connect(stream, &identity, &remote.key).await.map_err(|e| e.to_string()),
+            Err(e) => Err(e.to_string()),
+        };
+        let mut framed = match connected {
+            Ok(mut framed) => {
+                framed.codec_mut().set_max_frame_length(max_frame_length);
+                framed
+            }
+            Err(_) if outbound.is_closed() => return,
+            Err(_) => {
+                tokio::time::sleep(REDIAL_INTERVAL).await;
+                continue;
+            }
+        };
+        loop {
+            let next = tokio::select! {
+                message = outbound.recv() => message,
+                heartbeat = heartbeats.recv() => match heartbeat {
+                    // Only the latest heartbeat matters.
+                    Err(broadcast::error::RecvError::Lagged(_)) => continue,
+                    heartbeat => heartbeat,
+                },
+            };
+            match next {
+                Ok(message) => {
+                    if let Err(e) = framed.send(message).await {
+                        println!("❌ lost the connection to {}: {}", peer_id, e);
+                        break;
+                    }
+                }
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => return,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keys::RegistryEntry;
+    use chrono::TimeDelta;
+
+    /// Binds `clients` peers to free localhost ports and starts them, the
+    /// last `odd_ones` with `odd_strategy` instead of the plain mean.
+    async fn mesh(clients: usize, quorum: usize, odd_ones: usize, odd_strategy: Strategy) -> Vec<Arc<Peer>> {
+        let signing_keys: Vec<SigningKey> = (1..=clients).map(|id| SigningKey::from_bytes(&[id as u8; 32])).collect();
+        let registry = Registry {
+            aggregator: hex::encode([0; 32]),
+            clients: signing_keys
+                .iter()
+                .enumerate()
+                .map(|(i, key)| RegistryEntry::new(&format!("client{}", i + 1), key, Utc::now() - TimeDelta::hours(1)))
+                .collect(),
+            revoked: vec![],
+        };
+        let mut listeners = Vec::new();
+        let mut addresses = BTreeMap::new();
+        for i in 1..=clients {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            addresses.insert(format!("client{}", i), listener.local_addr().unwrap().to_string());
+            listeners.push(listener);
+        }
+        let mut peers = Vec::new();
+        for (i, (listener, signing_key)) in listeners.into_iter().zip(signing_keys).enumerate() {
+            let strategy = if i >= clients - odd_ones { odd_strategy } else { Strategy::Mean };
+            let peer = Peer::new(&format!("client{}", i + 1), signing_key, &registry, &addresses, quorum).unwrap();
+            let peer = Arc::new(peer.with_strategy(strategy));
+            peer.start(listener);
+            peers.push(peer);
+        }
+        peers
+    }
+
+    async fn run(peers: &[Arc<Peer>], averages: &[f64]) -> Vec<Outcome> {
+        let rounds: Vec<_> = peers
+            .iter()
+            .zip(averages)
+            .map(|(peer, average)| {
+                let (peer, average) = (peer.clone(), *average);
+                tokio::spawn(async move { peer.run_round(1, average, Duration::from_secs(5)).await })
+            })
+            .collect();
+        let mut outcomes = Vec::new();
+        for round in rounds {
+            outcomes.push(round.await.unwrap().unwrap());
+        }
+        outcomes
+    }
+
+    #[tokio::test]
+    async fn test_peers_agree_on_an_attested_result_without_an_aggregator() {
+        let peers = mesh(5, 5, 0, Strategy::Mean).await;
+        let outcomes = run(&peers, &[100.0, 101.0, 102.0, 103.0, 104.0]).await;
+        for outcome in &outcomes {
+            assert_eq!(outcome.result.average, Some(102.0));
+            assert_eq!(outcome.result.contributors.len(), 5);
+            assert!(outcome.disagreeing.is_empty());
+            assert_eq!(outcome.result.attestation.as_ref().unwrap().signers.len(), 5);
+            assert_eq!(attestation::verify(&outcome.result, &peers[0].keys.read().unwrap(), 5), Ok(()));
+        }
+        // Any peer's result can stand in for the others'.
+        assert!(outcomes.iter().all(|outcome| outcome.result.attestation == outcomes[0].result.attestation));
+        let late = SignedMessage::sign("client1", 1.0, 1, &peers[0].identity);
+        assert_eq!(peers[0].receive_input(late, Utc::now()), Ok(false));
+    }
+
+    #[tokio::test]
+    async fn test_peers_report_those_that_computed_a_different_result() {
+        let peers = mesh(5, 3, 1, Strategy::Median).await;
+        let outcomes = run(&peers, &[100.0, 100.0, 100.0, 100.0, 200.0]).await;
+        for outcome in &outcomes[..4] {
+            assert_eq!(outcome.result.average, Some(120.0));
+            assert_eq!(outcome.disagreeing, vec!["client5"]);
+            assert_eq!(outcome.result.attestation.as_ref().unwrap().signers.len(), 4);
+        }
+        assert_eq!(outcomes[4].result.average, Some(100.0));
+        assert_eq!(outcomes[4].disagreeing.len(), 4);
+        assert_eq!(outcomes[4].result.attestation, None);
+    }
+
+    #[tokio::test]
+    async fn test_the_elected_leader_decides_led_rounds() {
+        let peers = mesh(3, 3, 0, Strategy::Mean).await;
+        let rounds: Vec<_> = peers
+            .iter()
+            .zip([100.0, 101.0, 102.0])
+            .map(|(peer, average)| {
+                let peer = peer.clone();
+                tokio::spawn(async move { peer.run_led_round(1, average, Duration::from_secs(5)).await })
+            })
+            .collect();
+        for round in rounds {
+            let outcome = round.await.unwrap().unwrap();
+            assert_eq!(outcome.leader.as_deref(), Some("client3"));
+            assert_eq!(outcome.result.average, Some(101.0));
+            assert_eq!(attestation::verify(&outcome.result, &peers[0].keys.read().unwrap(), 3), Ok(()));
+        }
+    }
+
+    /// Chaos test: the leader dies while it waits for the last average, and
+    /// the next peer in line finishes the round with everything gossiped so
+    /// far, the dead leader's average included.
+    #[tokio::test(start_paused = true)]
+    async fn test_a_new_leader_completes_the_round_when_the_leader_dies() {
+        let peers = mesh(5, 3, 0, Strategy::Mean).await;
+        let deadline = Duration::from_secs(3);
+        let averages = [100.0, 101.0, 102.0, 103.0, 104.0];
+        let mut rounds: Vec<_> = peers[1..]
+            .iter()
+            .zip(&averages[1..])
+            .map(|(peer, average)| {
+                let (peer, average) = (peer.clone(), *average);
+                tokio::spawn(async move { peer.run_led_round(1, average, deadline).await })
+            })
+            .collect();
+        while peers[4].with_round(1, |round| round.inputs.len()) < 4 || peers[4].leader().is_none() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(peers[4].leader().as_deref(), Some("client5"));
+        rounds.pop().unwrap().abort();
+        peers[4].stop();
+
+        let late = peers[0].clone();
+        rounds.insert(0, tokio::spawn(async move { late.run_led_round(1, 100.0, deadline).await }));
+        for round in rounds {
+            let outcome = round.await.unwrap().unwrap();
+            assert_eq!(outcome.leader.as_deref(), Some("client4"));
+            assert_eq!(outcome.result.average, Some(102.0));
+            assert_eq!(outcome.result.contributors.len(), 5);
+            let attestation = outcome.result.attestation.as_ref().unwrap();
+            assert_eq!(attestation.signer_ids(), vec!["client1", "client2", "client3", "client4"]);
+            assert_eq!(attestation::verify(&outcome.result, &peers[0].keys.read().unwrap(), 3), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_conflicting_averages_are_refused() {
+        let signing_key = SigningKey::from_bytes(&[1; 32]);
+        let registry = Registry {
+            aggregator: hex::encode([0; 32]),
+            clients: vec![RegistryEntry::new("client1", &signing_key, Utc::now() - TimeDelta::hours(1))],
+            revoked: vec![],
+        };
+        let peer = Peer::new("client1", signing_key.clone(), &registry, &BTreeMap::new(), 1).unwrap();
+        peer.join_round(3);
+        // A NaN would make every peer's result NaN, so it is neither kept
+        // nor relayed.
+        let nan = SignedMessage::sign("client1", f64::NAN, 3, &signing_key);
+        assert_eq!(peer.receive_input(nan, Utc::now()), Err("average 'NaN' is not a finite number".to_string()));
+        assert_eq!(peer.aggregate(3).contributors, Vec::<String>::new());
+        let first = SignedMessage::sign("client1", 100.0, 3, &signing_key);
+        assert_eq!(peer.receive_input(first.clone(), Utc::now()), Ok(true));
+        assert_eq!(peer.receive_input(first, Utc::now()), Ok(false));
+        let second = SignedMessage::sign("client1", 150.0, 3, &signing_key);
+        assert_eq!(
+            peer.receive_input(second, Utc::now()),
+            Err("client1 sent two different averages for round 3".to_string())
+        );
+    }
+
+    #[test]
+    fn test_only_checked_signatures_and_attested_results_are_kept() {
+        let (own_key, other_key) = (SigningKey::from_bytes(&[1; 32]), SigningKey::from_bytes(&[2; 32]));
+        let registry = Registry {
+            aggregator: hex::encode([0; 32]),
+            clients: vec![
+                RegistryEntry::new("client1", &own_key, Utc::now() - TimeDelta::hours(1)),
+                RegistryEntry::new("client2", &other_key, Utc::now() - TimeDelta::hours(1)),
+            ],
+            revoked: vec![],
+        };
+        let peer = Peer::new("client1", own_key.clone(), &registry, &BTreeMap::new(), 1).unwrap();
+        peer.receive_input(SignedMessage::sign("client1", 100.0, 1, &own_key), Utc::now()).unwrap();
+        let result = peer.aggregate(1);
+        peer.endorse(&result).unwrap();
+
+        // However often a signature comes in, and whatever else is signed, one
+        // entry per signer and known result is kept.
+        let other_key_id = keys::key_id(&other_key.verifying_key());
+        let signature = attestation::sign(&result, &attestation::secret_key(&other_key));
+        assert_eq!(peer.receive_attestation(1, "client2", &other_key_id, &signature, Utc::now()), Ok(true));
+        assert_eq!(peer.receive_attestation(1, "client2", &other_key_id, &signature, Utc::now()), Ok(false));
+        for average in 0..10 {
+            let other = RoundResult { average: Some(average as f64), ..result.clone() };
+            let signature = attestation::sign(&other, &attestation::secret_key(&other_key));
+            assert_eq!(peer.receive_attestation(1, "client2", &other_key_id, &signature, Utc::now()), Ok(false));
+        }
+        assert_eq!(peer.with_round(1, |round| round.attestations.len()), 2);
+
+        // Even from the leader, only a result attested by the quorum counts.
+        assert_eq!(peer.receive_result(result.clone()), Err("round 1 is not attested".to_string()));
+        let attested = RoundResult {
+            attestation: Some(attestation::combine(&peer.endorsements(&result)).unwrap()),
+            ..result
+        };
+        assert_eq!(peer.receive_result(attested), Ok(true));
+    }
+
+    #[test]
+    fn test_reloading_the_registry_drops_revoked_keys() {
+        let (own_key, other_key) = (SigningKey::from_bytes(&[1; 32]), SigningKey::from_bytes(&[2; 32]));
+        let mut registry = Registry {
+            aggregator: hex::encode([0; 32]),
+            clients: vec![
+                RegistryEntry::new("client1", &own_key, Utc::now() - TimeDelta::hours(1)),
+                RegistryEntry::new("client2", &other_key, Utc::now() - TimeDelta::hours(1)),
+            ],
+            revoked: vec![],
+        };
+        let peer = Peer::new("client1", own_key, &registry, &BTreeMap::new(), 1).unwrap();
+        registry.revoked.push(keys::Revocation {
+            key_id: keys::key_id(&other_key.verifying_key()),
+            revoked_at: Utc::now() - TimeDelta::minutes(1),
+            reason: "compromised".to_string(),
+        });
+        peer.reload(&registry).unwrap();
+        let signed = SignedMessage::sign("client2", 100.0, 1, &other_key);
+        assert!(peer.receive_input(signed, Utc::now()).unwrap_err().ends_with("compromised"));
+    }
+
+    #[test]
+    fn test_forged_attestations_and_far_off_rounds_are_dropped() {
+        let (own_key, other_key) = (SigningKey::from_bytes(&[1; 32]), SigningKey::from_bytes(&[2; 32]));
+        let registry = Registry {
+            aggregator: hex::encode([0; 32]),
+            clients: vec![
+                RegistryEntry::new("client1", &own_key, Utc::now() - TimeDelta::hours(1)),
+                RegistryEntry::new("client2", &other_key, Utc::now() - TimeDelta::hours(1)),
+            ],
+            revoked: vec![],
+        };
+        let peer = Peer::new("client1", own_key.clone(), &registry, &BTreeMap::new(), 1).unwrap();
+        peer.receive_input(SignedMessage::sign("client1", 100.0, 1, &own_key), Utc::now()).unwrap();
+        let result = peer.aggregate(1);
+        peer.endorse(&result).unwrap();
+
+        // A peer signing in client2's name with its own key does not keep
+        // client2's real signature out.
+        let other_key_id = keys::key_id(&other_key.verifying_key());
+        let forged = attestation::sign(&result, &attestation::secret_key(&own_key));
+        assert_eq!(peer.receive_attestation(1, "client2", &other_key_id, &forged, Utc::now()), Ok(false));
+        let signature = attestation::sign(&result, &attestation::secret_key(&other_key));
+        assert_eq!(peer.receive_attestation(1, "client2", &other_key_id, &signature, Utc::now()), Ok(true));
+        assert_eq!(peer.endorsements(&result).len(), 2);
+
+        // Only the current round and the next are kept track of.
+        for round_id in [2, 3, 1_000_000] {
+            let signed = SignedMessage::sign("client2", 100.0, round_id, &other_key);
+            assert_eq!(peer.receive_input(signed, Utc::now()), Ok(round_id == 2));
+        }
+        peer.join_round(2);
+        assert_eq!(peer.rounds.lock().unwrap().keys().collect::<Vec<_>>(), vec![&2]);
+        peer.finish(2);
+        assert!(peer.rounds.lock().unwrap().is_empty());
+        let late = SignedMessage::sign("client2", 100.0, 2, &other_key);
+        assert_eq!(peer.receive_input(late, Utc::now()), Ok(false));
+    }
+}
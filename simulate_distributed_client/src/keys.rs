@@ -1,21 +1,605 @@
+use crate::{attestation, transport};
+use argon2::{Algorithm, Argon2, Params, Version};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, Payload};
+use chacha20poly1305::ChaCha20Poly1305;
+use chrono::{DateTime, Utc};
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::RngCore;
+use rand::rngs::OsRng;
 use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
 use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+use zeroize::Zeroizing;
 
-#[derive(Serialize, Deserialize, Debug)]
+/// A client's average for one round. The round, timestamp and nonce are
+/// signed along with the average so a captured message cannot be replayed
+/// later or in another round.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct SignedMessage {
     pub client_id: String,
+    /// Registry key the message was signed with, see [`key_id`].
+    pub key_id: String,
     pub average: String,
+    pub round_id: u64,
+    /// Milliseconds since the Unix epoch when the message was signed.
+    pub timestamp: i64,
+    /// 16 random bytes, hex-encoded.
+    pub nonce: String,
     pub signature: String,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-pub struct KeyData {
+impl SignedMessage {
+    pub fn sign(client_id: &str, average: f64, round_id: u64, signing_key: &SigningKey) -> Self {
+        Self::sign_at(client_id, average, round_id, Utc::now(), signing_key)
+    }
+
+    pub fn sign_at(client_id: &str, average: f64, round_id: u64, at: DateTime<Utc>, signing_key: &SigningKey) -> Self {
+        let mut nonce = [0u8; 16];
+        OsRng.fill_bytes(&mut nonce);
+        let mut signed = Self {
+            client_id: client_id.to_string(),
+            key_id: key_id(&signing_key.verifying_key()),
+            average: average.to_string(),
+            round_id,
+            timestamp: at.timestamp_millis(),
+            nonce: hex::encode(nonce),
+            signature: String::new(),
+        };
+        signed.signature = hex::encode(signing_key.sign(signed.payload().as_bytes()).to_bytes());
+        signed
+    }
+
+    /// The signed average as a number. NaN and infinities are refused: one
+    /// of them among the inputs would turn every aggregate into NaN.
+    pub fn average_value(&self) -> Result<f64, String> {
+        match self.average.parse::<f64>() {
+            Ok(average) if average.is_finite() => Ok(average),
+            _ => Err(format!("average '{}' is not a finite number", self.average)),
+        }
+    }
+
+    /// The bytes covered by the signature.
+    fn payload(&self) -> String {
+        format!(
+            "{}:{}:{}:{}:{}:{}",
+            self.client_id, self.key_id, self.average, self.round_id, self.timestamp, self.nonce
+        )
+    }
+
+    pub fn verify(&self, verifying_key: &VerifyingKey) -> Result<(), String> {
+        let bytes: [u8; 64] = hex::decode(&self.signature)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or("signature is not 64 hex-encoded bytes")?;
+        verifying_key
+            .verify(self.payload().as_bytes(), &Signature::from_bytes(&bytes))
+            .map_err(|_| "invalid signature".to_string())
+    }
+}
+
+/// Registry the server and every client load the public keys from.
+pub const REGISTRY_PATH: &str = "registry.json";
+/// Directory holding one encrypted private-key file per client.
+pub const KEY_DIR: &str = "keys";
+/// Environment variable the aggregator's key password is read from instead
+/// of prompting. Client keys each have a password of their own, see
+/// [`key_password`].
+pub const PASSWORD_ENV: &str = "CLIENT_KEY_PASSWORD";
+/// Name of the aggregator's key file.
+pub const AGGREGATOR_ID: &str = "aggregator";
+
+const KEY_LENGTH: usize = 32;
+
+/// Identifies a client key: the first 8 bytes of its ed25519 public key.
+pub fn key_id(verifying_key: &VerifyingKey) -> String {
+    hex::encode(&verifying_key.to_bytes()[..8])
+}
+
+/// The public keys everyone is set up with beforehand. A client may have
+/// several keys, e.g. an old and a new one overlapping during a rotation.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Registry {
+    /// The aggregator's ed25519 identity, which clients authenticate it by.
+    pub aggregator: String,
+    pub clients: Vec<RegistryEntry>,
+    #[serde(default)]
+    pub revoked: Vec<Revocation>,
+}
+
+impl Registry {
+    pub fn aggregator_key(&self) -> Result<VerifyingKey, String> {
+        parse_verifying_key(&self.aggregator, AGGREGATOR_ID)
+    }
+
+    /// The newest key of `client_id` that has no end date.
+    pub fn current_key(&self, client_id: &str) -> Option<&RegistryEntry> {
+        self.clients
+            .iter()
+            .filter(|entry| entry.client_id == client_id && entry.not_after.is_none())
+            .max_by_key(|entry| entry.not_before)
+    }
+}
+
+/// A key that must no longer be accepted, whatever its validity window.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Revocation {
+    pub key_id: String,
+    pub revoked_at: DateTime<Utc>,
+    #[serde(default)]
+    pub reason: String,
+}
+
+fn parse_verifying_key(value: &str, owner: &str) -> Result<VerifyingKey, String> {
+    let bytes: [u8; 32] = hex::decode(value)
+        .ok()
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or_else(|| format!("public key of {} is not 32 hex-encoded bytes", owner))?;
+    VerifyingKey::from_bytes(&bytes).map_err(|e| format!("public key of {}: {}", owner, e))
+}
+
+/// One key of a client, as listed in the registry.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RegistryEntry {
     pub client_id: String,
+    pub key_id: String,
     pub public: String,
-    pub private: String,
+    /// Public half of the BLS key the client attests round results with.
+    pub bls_public: String,
+    pub not_before: DateTime<Utc>,
+    /// `None` until the key is rotated out.
+    #[serde(default)]
+    pub not_after: Option<DateTime<Utc>>,
+}
+
+impl RegistryEntry {
+    pub fn new(client_id: &str, signing_key: &SigningKey, not_before: DateTime<Utc>) -> Self {
+        Self {
+            client_id: client_id.to_string(),
+            key_id: key_id(&signing_key.verifying_key()),
+            public: hex::encode(signing_key.verifying_key().to_bytes()),
+            bls_public: attestation::public_key_hex(signing_key),
+            not_before,
+            not_after: None,
+        }
+    }
+
+    pub fn verifying_key(&self) -> Result<VerifyingKey, String> {
+        parse_verifying_key(&self.public, &self.client_id)
+    }
+
+    pub fn bls_public_key(&self) -> Result<blst::min_pk::PublicKey, String> {
+        attestation::parse_public_key(&self.bls_public).map_err(|e| format!("{}: {}", self.client_id, e))
+    }
+}
+
+/// Argon2id cost parameters, stored with each key so they can be raised
+/// later without breaking existing files.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub struct KdfParams {
+    pub memory_kib: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    fn default() -> Self {
+        let params = Params::default();
+        Self {
+            memory_kib: params.m_cost(),
+            iterations: params.t_cost(),
+            parallelism: params.p_cost(),
+        }
+    }
+}
+
+/// A client's private key at rest: the ed25519 seed encrypted with
+/// ChaCha20-Poly1305 under a key derived from a password with Argon2id. The
+/// client id is authenticated along with it, so a file renamed to another
+/// client's name does not open.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EncryptedKey {
+    pub client_id: String,
+    pub kdf: KdfParams,
+    pub salt: String,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+impl EncryptedKey {
+    pub fn seal(client_id: &str, signing_key: &SigningKey, password: &str, kdf: KdfParams) -> Result<Self, String> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let cipher = cipher(password, &salt, kdf)?;
+        let nonce = ChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let payload = Payload {
+            msg: signing_key.as_bytes(),
+            aad: client_id.as_bytes(),
+        };
+        let ciphertext = cipher.encrypt(&nonce, payload).map_err(|_| "encryption failed".to_string())?;
+        Ok(Self {
+            client_id: client_id.to_string(),
+            kdf,
+            salt: hex::encode(salt),
+            nonce: hex::encode(nonce),
+            ciphertext: hex::encode(ciphertext),
+        })
+    }
+
+    pub fn open(&self, password: &str) -> Result<SigningKey, String> {
+        let salt = hex::decode(&self.salt).map_err(|e| format!("salt is not hex: {}", e))?;
+        let nonce: [u8; 12] = hex::decode(&self.nonce)
+            .ok()
+            .and_then(|bytes| bytes.try_into().ok())
+            .ok_or("nonce is not 12 hex-encoded bytes")?;
+        let ciphertext = hex::decode(&self.ciphertext).map_err(|e| format!("ciphertext is not hex: {}", e))?;
+        let payload = Payload {
+            msg: &ciphertext,
+            aad: self.client_id.as_bytes(),
+        };
+        let seed = Zeroizing::new(
+            cipher(password, &salt, self.kdf)?
+                .decrypt(&nonce.into(), payload)
+                .map_err(|_| format!("cannot decrypt the key of {}: wrong password or corrupted file", self.client_id))?,
+        );
+        let seed: &[u8; 32] = seed.as_slice().try_into().map_err(|_| "decrypted key is not 32 bytes")?;
+        Ok(SigningKey::from_bytes(seed))
+    }
+}
+
+fn cipher(password: &str, salt: &[u8], kdf: KdfParams) -> Result<ChaCha20Poly1305, String> {
+    let params = Params::new(kdf.memory_kib, kdf.iterations, kdf.parallelism, Some(KEY_LENGTH))
+        .map_err(|e| format!("invalid key derivation parameters: {}", e))?;
+    let mut key = Zeroizing::new([0u8; KEY_LENGTH]);
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password.as_bytes(), salt, key.as_mut_slice())
+        .map_err(|e| format!("key derivation failed: {}", e))?;
+    Ok(ChaCha20Poly1305::new(key.as_slice().into()))
+}
+
+pub fn key_path(dir: &Path, client_id: &str) -> PathBuf {
+    dir.join(format!("{}.key", client_id))
 }
 
-pub fn load_keys() -> Vec<KeyData> {
-    let data = fs::read_to_string("keys.json").unwrap();
-    serde_json::from_str(&data).unwrap()
-}
\ No newline at end of file
+pub fn load_registry(path: &Path) -> Result<Registry, String> {
+    let data = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    serde_json::from_str(&data).map_err(|e| format!("invalid registry {}: {}", path.display(), e))
+}
+
+/// Replaces the registry in one step, so a server reloading it never sees a
+/// half-written file. The file is written under a name of its own first, so
+/// two concurrent saves cannot write into each other's file.
+pub fn save_registry(path: &Path, registry: &Registry) -> Result<(), String> {
+    let json = serde_json::to_string_pretty(registry).map_err(|e| e.to_string())?;
+    let temporary = path.with_extension(format!("json.{}.{:016x}.tmp", std::process::id(), OsRng.next_u64()));
+    fs::write(&temporary, json).map_err(|e| format!("cannot write {}: {}", temporary.display(), e))?;
+    fs::rename(&temporary, path).map_err(|e| {
+        let _ = fs::remove_file(&temporary);
+        format!("cannot replace {}: {}", path.display(), e)
+    })
+}
+
+fn modified(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|metadata| metadata.modified()).ok()
+}
+
+/// Hands the registry to `reload` whenever the file changes, so keys can be
+/// rotated or revoked without a restart. A registry that fails to load or
+/// that `reload` refuses is reported and the keys in use are kept.
+pub async fn watch_registry(path: &Path, every: Duration, reload: impl Fn(&Registry) -> Result<(), String>) {
+    let mut loaded = modified(path);
+    let mut interval = tokio::time::interval(every);
+    loop {
+        interval.tick().await;
+        let current = modified(path);
+        if current == loaded {
+            continue;
+        }
+        loaded = current;
+        match load_registry(path).and_then(|registry| {
+            reload(&registry)?;
+            Ok(registry)
+        }) {
+            Ok(registry) => println!(
+                "Reloaded {}: {} keys, {} revoked",
+                path.display(),
+                registry.clients.len(),
+                registry.revoked.len()
+            ),
+            Err(e) => println!("Error:: keeping the current keys, cannot reload {}: {}", path.display(), e),
+        }
+    }
+}
+
+/// A registry key ready for use.
+#[derive(Debug, Clone)]
+pub struct KeyRecord {
+    pub client_id: String,
+    pub key_id: String,
+    pub verifying_key: VerifyingKey,
+    pub bls_key: blst::min_pk::PublicKey,
+    /// The X25519 key the client authenticates the transport with.
+    pub static_public: [u8; 32],
+    pub not_before: DateTime<Utc>,
+    pub not_after: Option<DateTime<Utc>>,
+}
+
+/// The registry's keys indexed by key id. Validity windows and revocations
+/// are checked whenever a key is used, so a key expires on time without the
+/// set being rebuilt.
+#[derive(Debug, Clone)]
+pub struct KeySet {
+    keys: HashMap<String, KeyRecord>,
+    revoked: HashMap<String, Revocation>,
+}
+
+impl KeySet {
+    pub fn new(registry: &Registry) -> Result<Self, String> {
+        let mut keys = HashMap::new();
+        for entry in &registry.clients {
+            let verifying_key = entry.verifying_key()?;
+            if entry.key_id != key_id(&verifying_key) {
+                return Err(format!("key id {} of {} does not match its public key", entry.key_id, entry.client_id));
+            }
+            let record = KeyRecord {
+                client_id: entry.client_id.clone(),
+                key_id: entry.key_id.clone(),
+                verifying_key,
+                bls_key: entry.bls_public_key()?,
+                static_public: transport::static_public(&verifying_key),
+                not_before: entry.not_before,
+                not_after: entry.not_after,
+            };
+            if keys.insert(entry.key_id.clone(), record).is_some() {
+                return Err(format!("key id {} is listed twice", entry.key_id));
+            }
+        }
+        let revoked = registry
+            .revoked
+            .iter()
+            .map(|revocation| (revocation.key_id.clone(), revocation.clone()))
+            .collect();
+        Ok(Self { keys, revoked })
+    }
+
+    /// The key `key_id`, provided it belongs to `client_id` and may be used
+    /// at `at`.
+    pub fn key(&self, key_id: &str, client_id: &str, at: DateTime<Utc>) -> Result<&KeyRecord, String> {
+        let record = self.registered(key_id, client_id)?;
+        self.check(record, at)?;
+        Ok(record)
+    }
+
+    /// The key `key_id` of `client_id` whether or not it may be used any
+    /// more, for checking what was signed with it in the past.
+    pub fn registered(&self, key_id: &str, client_id: &str) -> Result<&KeyRecord, String> {
+        let record = self.keys.get(key_id).ok_or_else(|| format!("unknown key {}", key_id))?;
+        if record.client_id != client_id {
+            return Err(format!("key {} belongs to {}, not {}", key_id, record.client_id, client_id));
+        }
+        Ok(record)
+    }
+
+    /// A revoked key stays usable for what is checked at times before its
+    /// revocation, such as the closing time of an earlier round.
+    fn check(&self, record: &KeyRecord, at: DateTime<Utc>) -> Result<(), String> {
+        if let Some(revocation) = self.revoked.get(&record.key_id)
+            && at >= revocation.revoked_at
+        {
+            return Err(format!(
+                "key {} was revoked at {}: {}",
+                record.key_id,
+                revocation.revoked_at.to_rfc3339(),
+                revocation.reason
+            ));
+        }
+        if at < record.not_before {
+            return Err(format!("key {} is not valid before {}", record.key_id, record.not_before.to_rfc3339()));
+        }
+        if let Some(not_after) = record.not_after
+            && at >= not_after
+        {
+            return Err(format!("key {} expired at {}", record.key_id, not_after.to_rfc3339()));
+        }
+        Ok(())
+    }
+
+    /// The key a transport peer authenticated with, if it may be used at `at`.
+    pub fn by_static(&self, static_public: &[u8; 32], at: DateTime<Utc>) -> Option<&KeyRecord> {
+        self.keys
+            .values()
+            .find(|record| &record.static_public == static_public && self.check(record, at).is_ok())
+    }
+
+    /// Clients with at least one key usable at `at`.
+    pub fn clients(&self, at: DateTime<Utc>) -> BTreeSet<&str> {
+        self.keys
+            .values()
+            .filter(|record| self.check(record, at).is_ok())
+            .map(|record| record.client_id.as_str())
+            .collect()
+    }
+}
+
+/// Decrypts the key file of `client_id`, or of [`AGGREGATOR_ID`], in `dir`.
+/// Only that one file is read.
+pub fn load_signing_key(path: &Path, client_id: &str, password: &str) -> Result<SigningKey, String> {
+    let data = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    let encrypted: EncryptedKey =
+        serde_json::from_str(&data).map_err(|e| format!("invalid key file {}: {}", path.display(), e))?;
+    if encrypted.client_id != client_id {
+        return Err(format!("{} holds the key of {}, not {}", path.display(), encrypted.client_id, client_id));
+    }
+    encrypted.open(password)
+}
+
+/// The key password from [`PASSWORD_ENV`], or else asked for on the terminal.
+pub fn password(prompt: &str) -> Result<Zeroizing<String>, String> {
+    match std::env::var(PASSWORD_ENV) {
+        Ok(password) => Ok(Zeroizing::new(password)),
+        Err(_) => rpassword::prompt_password(prompt)
+            .map(Zeroizing::new)
+            .map_err(|e| format!("cannot read the key password (or set {}): {}", PASSWORD_ENV, e)),
+    }
+}
+
+/// The password of `owner`'s key file: the first line of `password_file`,
+/// which nobody but its owner may read, or else asked for on the terminal.
+pub fn key_password(owner: &str, password_file: Option<&Path>) -> Result<Zeroizing<String>, String> {
+    let Some(path) = password_file else {
+        return rpassword::prompt_password(format!("Password for the key file of {}: ", owner))
+            .map(Zeroizing::new)
+            .map_err(|e| format!("cannot read the key password of {}: {}", owner, e));
+    };
+    let file = fs::File::open(path).map_err(|e| format!("cannot read {}: {}", path.display(), e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = file.metadata().map_err(|e| format!("cannot read {}: {}", path.display(), e))?.permissions().mode();
+        if mode & 0o077 != 0 {
+            return Err(format!("{} can be read by others, restrict it to its owner (chmod 600)", path.display()));
+        }
+    }
+    read_password(&mut std::io::BufReader::new(file)).map_err(|e| format!("cannot read {}: {}", path.display(), e))
+}
+
+/// The first line of `reader`, as a password is handed over on a pipe or
+/// kept in a file.
+pub fn read_password(reader: &mut impl std::io::BufRead) -> Result<Zeroizing<String>, String> {
+    let mut line = Zeroizing::new(String::new());
+    reader.read_line(&mut line).map_err(|e| e.to_string())?;
+    let password = Zeroizing::new(line.trim_end_matches(['\r', '\n']).to_string());
+    if password.is_empty() {
+        return Err("no password given".to_string());
+    }
+    Ok(password)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeDelta;
+
+    /// Far below the defaults, to keep the tests fast.
+    const CHEAP: KdfParams = KdfParams {
+        memory_kib: 64,
+        iterations: 1,
+        parallelism: 1,
+    };
+
+    #[test]
+    fn test_key_files_open_with_the_right_password_only() {
+        let signing_key = SigningKey::from_bytes(&[7; 32]);
+        let sealed = EncryptedKey::seal("client1", &signing_key, "hunter2", CHEAP).unwrap();
+        assert!(!sealed.ciphertext.contains(&hex::encode(signing_key.to_bytes())));
+        assert_eq!(sealed.open("hunter2").unwrap().to_bytes(), signing_key.to_bytes());
+        assert!(sealed.open("hunter3").unwrap_err().contains("wrong password"));
+
+        let mut renamed = sealed;
+        renamed.client_id = "client2".to_string();
+        assert!(renamed.open("hunter2").is_err());
+    }
+
+    #[test]
+    fn test_key_set_honours_windows_and_revocations() {
+        let now = Utc::now();
+        let old = SigningKey::from_bytes(&[1; 32]);
+        let new = SigningKey::from_bytes(&[2; 32]);
+        let mut rotated = RegistryEntry::new("client1", &old, now - TimeDelta::days(30));
+        rotated.not_after = Some(now + TimeDelta::minutes(5));
+        let registry = Registry {
+            aggregator: hex::encode([0; 32]),
+            clients: vec![rotated, RegistryEntry::new("client1", &new, now)],
+            revoked: vec![],
+        };
+        let (old_id, new_id) = (key_id(&old.verifying_key()), key_id(&new.verifying_key()));
+        let keys = KeySet::new(&registry).unwrap();
+        assert!(keys.key(&old_id, "client1", now).is_ok());
+        assert!(keys.key(&new_id, "client1", now).is_ok());
+        assert!(keys.key(&old_id, "client1", now + TimeDelta::minutes(6)).unwrap_err().contains("expired"));
+        assert!(keys.key(&new_id, "client1", now - TimeDelta::seconds(1)).unwrap_err().contains("not valid before"));
+        assert!(keys.key(&new_id, "client2", now).unwrap_err().contains("belongs to client1"));
+        assert_eq!(registry.current_key("client1").unwrap().key_id, new_id);
+
+        let mut revoked = registry;
+        revoked.revoked.push(Revocation {
+            key_id: new_id.clone(),
+            revoked_at: now,
+            reason: "laptop stolen".to_string(),
+        });
+        revoked.revoked.push(Revocation {
+            key_id: old_id.clone(),
+            revoked_at: now + TimeDelta::minutes(1),
+            reason: "retired early".to_string(),
+        });
+        let keys = KeySet::new(&revoked).unwrap();
+        assert!(keys.key(&new_id, "client1", now).unwrap_err().ends_with("laptop stolen"));
+        // What was checked before a revocation stays valid.
+        assert!(keys.key(&old_id, "client1", now).is_ok());
+        assert!(keys.key(&old_id, "client1", now + TimeDelta::minutes(2)).unwrap_err().ends_with("retired early"));
+        assert!(keys.by_static(&transport::static_public(&new.verifying_key()), now).is_none());
+        assert!(keys.by_static(&transport::static_public(&old.verifying_key()), now).is_some());
+        assert_eq!(keys.clients(now + TimeDelta::minutes(6)).len(), 0);
+    }
+
+    #[test]
+    fn test_clients_load_only_their_own_key_file() {
+        let dir = std::env::temp_dir().join(format!("keys-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let signing_key = SigningKey::from_bytes(&[1; 32]);
+        let sealed = EncryptedKey::seal("client1", &signing_key, "pw", CHEAP).unwrap();
+        fs::write(key_path(&dir, "client1"), serde_json::to_string(&sealed).unwrap()).unwrap();
+        // client1's file copied into client2's place.
+        fs::copy(key_path(&dir, "client1"), key_path(&dir, "client2")).unwrap();
+
+        let load = |client_id: &str| load_signing_key(&key_path(&dir, client_id), client_id, "pw");
+        assert_eq!(load("client1").unwrap().to_bytes(), signing_key.to_bytes());
+        assert!(load("client2").unwrap_err().contains("holds the key of client1"));
+        assert!(load("client3").unwrap_err().starts_with("cannot read"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_registry_saves_leave_a_whole_registry() {
+        let dir = std::env::temp_dir().join(format!("registry-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(REGISTRY_PATH);
+        let writers: Vec<_> = (1..=8u8)
+            .map(|i| {
+                let path = path.clone();
+                std::thread::spawn(move || {
+                    let registry = Registry {
+                        aggregator: hex::encode([0; 32]),
+                        clients: vec![RegistryEntry::new("client1", &SigningKey::from_bytes(&[i; 32]), Utc::now())],
+                        revoked: vec![],
+                    };
+                    save_registry(&path, &registry)
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap().unwrap();
+        }
+        assert_eq!(load_registry(&path).unwrap().clients.len(), 1);
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_password_files_must_be_private() {
+        let path = std::env::temp_dir().join(format!("password-{}", std::process::id()));
+        fs::write(&path, "hunter2\nignored\n").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+            assert!(key_password("client1", Some(&path)).unwrap_err().contains("can be read by others"));
+            fs::set_permissions(&path, fs::Permissions::from_mode(0o600)).unwrap();
+        }
+        assert_eq!(*key_password("client1", Some(&path)).unwrap(), "hunter2");
+        fs::write(&path, "\n").unwrap();
+        assert!(key_password("client1", Some(&path)).unwrap_err().ends_with("no password given"));
+        fs::remove_file(&path).unwrap();
+    }
+}
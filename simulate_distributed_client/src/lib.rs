@@ -1 +1,14 @@
-pub mod keys;
\ No newline at end of file
+pub mod api;
+pub mod aggregator;
+pub mod attestation;
+pub mod audit;
+pub mod config;
+pub mod election;
+pub mod gossip;
+pub mod keys;
+pub mod protocol;
+pub mod robust;
+pub mod schedule;
+#[cfg(any(test, feature = "sim"))]
+pub mod sim;
+pub mod transport;
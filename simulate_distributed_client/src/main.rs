@@ -1,13 +1,22 @@
-pub mod keys;
-
 use clap::Parser;
-use ed25519_dalek::{Signer, SigningKey};
+use ed25519_dalek::{SigningKey, VerifyingKey};
 use futures_util::StreamExt;
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncWriteExt;
-use tokio::net::TcpStream;
+use simulate_distributed_client::config::ClusterConfig;
+use simulate_distributed_client::gossip::Peer;
+use simulate_distributed_client::keys::{self, KeySet, Registry};
+use simulate_distributed_client::protocol::{self, Message, RoundUpdate};
+use simulate_distributed_client::robust::Strategy;
+use simulate_distributed_client::{attestation, schedule, transport};
+use tokio::net::{TcpListener, TcpStream};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use std::process::ExitCode;
+use std::sync::{Arc, RwLock};
 
-const NUM_OF_CLIENT: usize = 5;
+/// Upper bound on waiting for a round to close; the aggregator's own deadline
+/// should be well below it.
+const RESULT_TIMEOUT: tokio::time::Duration = tokio::time::Duration::from_secs(120);
 const WEB_SOCKET_STREAM: &str = "wss://stream.binance.com:443/ws/btcusdt@miniTicker";
 
 #[derive(Debug, Parser)]
@@ -16,10 +25,157 @@ struct Cli {
     mode: String,
     #[arg(short, long, default_value = "10", help = "Number of seconds to fetch the price for")]
     times: u64,
+    #[arg(long, help = "Run only this client, e.g. client3, in this process instead of all configured ones as tasks")]
+    id: Option<String>,
+    #[arg(long, conflicts_with = "start_at_next", help = "Start fetching at this wall-clock time, e.g. 10:01:01, or an RFC 3339 timestamp")]
+    start_at: Option<String>,
+    #[arg(long, help = "Start fetching at the next multiple of this interval since the epoch, e.g. 5s")]
+    start_at_next: Option<String>,
+    #[arg(long, help = "Cluster config [default: cluster.toml if present, else five local clients]")]
+    config: Option<PathBuf>,
+    #[arg(long, help = "Directory holding the encrypted key files [default: from the cluster config]")]
+    key_dir: Option<PathBuf>,
+    #[arg(long, help = "Public keys of the aggregator and all clients [default: from the cluster config]")]
+    registry: Option<PathBuf>,
+    #[arg(long, help = "Address of the aggregator [default: from the cluster config]")]
+    server: Option<String>,
+    #[arg(long, requires = "id", help = "Read the key password from the first line of standard input, as the launcher hands it over")]
+    password_stdin: bool,
+    #[arg(long, help = "Aggregate among the clients, at their peer addresses in the cluster config, instead of through the aggregator")]
+    gossip: bool,
+    #[arg(long, requires = "gossip", help = "Peers needed for a gossiped round to count [default: all of them]")]
+    quorum: Option<usize>,
+    #[arg(long, default_value = "mean", requires = "gossip", help = "How gossiped averages are combined, as for the server")]
+    strategy: Strategy,
+    #[arg(long, requires = "gossip", help = "Elect a leader among the peers to aggregate each round, taking over if it dies")]
+    elect_leader: bool,
+    #[arg(long, default_value = "10s", requires = "gossip", help = "How long a peer waits for the others in each step of a gossiped round")]
+    round_timeout: String,
+    #[arg(long, default_value = "2s", help = "How often the registry file is checked for rotated or revoked keys")]
+    reload_interval: String,
+}
+
+/// The peers this process runs in gossip mode, see [`Peer`].
+struct Gossip {
+    /// Peers started on the same tick share a round: the tick's Unix time.
+    round_id: u64,
+    deadline: std::time::Duration,
+    elect_leader: bool,
+    peers: HashMap<String, Arc<Peer>>,
+}
+
+impl Gossip {
+    /// Binds and starts a peer for each client this process runs, so the
+    /// others can reach it before anyone has an average.
+    async fn start(
+        cli: &Cli,
+        config: &ClusterConfig,
+        credentials: &Credentials,
+        start: Option<chrono::DateTime<chrono::Utc>>,
+    ) -> Result<Self, String> {
+        let addresses = config.peers()?;
+        let quorum = cli.quorum.unwrap_or(addresses.len());
+        let round_id = start.map_or(1, |start| start.timestamp() as u64);
+        let mut peers = HashMap::new();
+        for (client_id, signing_key) in &credentials.signing_keys {
+            let address = &addresses[client_id];
+            let listener = TcpListener::bind(address)
+                .await
+                .map_err(|e| format!("cannot listen on {}: {}", address, e))?;
+            let peer = Peer::new(client_id, signing_key.clone(), &credentials.registry, &addresses, quorum)?;
+            let peer = Arc::new(peer.with_strategy(cli.strategy));
+            peer.join_round(round_id);
+            peer.start(listener);
+            peers.insert(client_id.clone(), peer);
+        }
+        Ok(Self {
+            round_id,
+            deadline: schedule::parse_interval(&cli.round_timeout)?,
+            elect_leader: cli.elect_leader,
+            peers,
+        })
+    }
+
+    async fn run(&self, client_id: &str, average: f64) -> Result<protocol::RoundResult, String> {
+        let peer = &self.peers[client_id];
+        let outcome = if self.elect_leader {
+            peer.run_led_round(self.round_id, average, self.deadline).await?
+        } else {
+            peer.run_round(self.round_id, average, self.deadline).await?
+        };
+        if let Some(leader) = &outcome.leader {
+            println!("Round {} was decided by {} for {}", self.round_id, leader, client_id);
+        }
+        if !outcome.disagreeing.is_empty() {
+            println!(
+                "❌ {} computed a different result for round {} than {}",
+                client_id,
+                self.round_id,
+                outcome.disagreeing.join(", ")
+            );
+        }
+        Ok(outcome.result)
+    }
+}
+
+/// The keys a client process works with: the private keys of the clients it
+/// runs, the aggregator's key to authenticate it by, and the registry's
+/// client keys, to check proposals with, reloaded as the registry changes.
+struct Credentials {
+    server: String,
+    aggregator_key: VerifyingKey,
+    signing_keys: HashMap<String, SigningKey>,
+    registry: Registry,
+    keys: RwLock<KeySet>,
+    /// Set when the clients aggregate among themselves.
+    gossip: Option<Gossip>,
+}
+
+impl Credentials {
+    fn load(cli: &Cli, config: &ClusterConfig) -> Result<Self, String> {
+        let registry = keys::load_registry(&config.registry)?;
+        let keys = KeySet::new(&registry)?;
+        let ids: Vec<&str> = match &cli.id {
+            Some(id) => vec![config.client(id)?.id.as_str()],
+            None => config.client_ids().collect(),
+        };
+        let signing_keys = ids
+            .into_iter()
+            .map(|client_id| {
+                let password = if cli.password_stdin {
+                    keys::read_password(&mut std::io::stdin().lock()).map_err(|e| format!("cannot read the key password: {}", e))?
+                } else {
+                    keys::key_password(client_id, config.password_file(client_id))?
+                };
+                let signing_key = keys::load_signing_key(&config.key_path(client_id), client_id, &password)?;
+                Ok((client_id.to_string(), signing_key))
+            })
+            .collect::<Result<_, String>>()?;
+        Ok(Self {
+            server: cli.server.clone().unwrap_or_else(|| config.aggregator.clone()),
+            aggregator_key: registry.aggregator_key()?,
+            signing_keys,
+            registry,
+            keys: RwLock::new(keys),
+            gossip: None,
+        })
+    }
+
+    /// Takes in a changed registry, so a revoked key stops counting in
+    /// proposals and gossip without restarting the clients.
+    fn reload(&self, registry: &Registry) -> Result<(), String> {
+        *self.keys.write().unwrap() = KeySet::new(registry)?;
+        if let Some(gossip) = &self.gossip {
+            for peer in gossip.peers.values() {
+                peer.reload(registry)?;
+            }
+        }
+        Ok(())
+    }
 }
 
 #[derive(Debug, Deserialize, Serialize)]
-struct BTC {
+struct Btc {
     s: String,
     #[serde(deserialize_with = "de_string_to_f64")]
     c: f64,
@@ -35,82 +191,246 @@ where
 
 
 #[tokio::main(flavor = "multi_thread")]
-async fn main() {
+async fn main() -> ExitCode {
     let cli = Cli::parse();
     match cli.mode.as_str() {
         "cache" => {
-            client_process(cli.times).await;
+            let config = match ClusterConfig::load(cli.config.as_deref()) {
+                Ok(mut config) => {
+                    config.override_paths(cli.key_dir.as_deref(), cli.registry.as_deref());
+                    config
+                }
+                Err(e) => {
+                    println!("Error:: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            // Keys are unlocked before the start tick, so a password prompt
+            // cannot make this client late.
+            let mut credentials = match Credentials::load(&cli, &config) {
+                Ok(credentials) => credentials,
+                Err(e) => {
+                    println!("Error:: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            let start = match start_time(&cli) {
+                Ok(start) => start,
+                Err(e) => {
+                    println!("Error:: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            if cli.gossip {
+                match Gossip::start(&cli, &config, &credentials, start).await {
+                    Ok(gossip) => credentials.gossip = Some(gossip),
+                    Err(e) => {
+                        println!("Error:: {}", e);
+                        return ExitCode::FAILURE;
+                    }
+                }
+            }
+            let reload_interval = match schedule::parse_interval(&cli.reload_interval) {
+                Ok(reload_interval) => reload_interval,
+                Err(e) => {
+                    println!("Error:: {}", e);
+                    return ExitCode::FAILURE;
+                }
+            };
+            let credentials = Arc::new(credentials);
+            let watcher = credentials.clone();
+            tokio::spawn(async move {
+                keys::watch_registry(&config.registry, reload_interval, |registry| watcher.reload(registry)).await;
+            });
+            if let Some(start) = start {
+                println!("Waiting for the start tick at {}", start.with_timezone(&chrono::Local).format("%H:%M:%S%.3f"));
+                schedule::sleep_until(start).await;
+            }
+            let result = match cli.id {
+                Some(client_id) => single_client_process(cli.times, client_id, credentials).await,
+                None => client_process(cli.times, credentials).await,
+            };
+            if let Err(e) = result {
+                println!("Error:: {}", e);
+                return ExitCode::FAILURE;
+            }
         }
         _ => {
             println!("Invalid mode");
+            return ExitCode::FAILURE;
         }
     }
+    ExitCode::SUCCESS
+}
+
+fn start_time(cli: &Cli) -> Result<Option<chrono::DateTime<chrono::Utc>>, String> {
+    if let Some(start_at) = &cli.start_at {
+        return schedule::parse_start_at(start_at, chrono::Local::now()).map(Some);
+    }
+    if let Some(every) = &cli.start_at_next {
+        return Ok(Some(schedule::next_tick(chrono::Utc::now(), schedule::parse_interval(every)?)));
+    }
+    Ok(None)
 }
 
-async fn client_process(times: u64) {
-    let (tx, rx) = tokio::sync::mpsc::channel::<(usize, f64)>(NUM_OF_CLIENT);
-    for id in 1..=NUM_OF_CLIENT {
+/// One client per OS process, as started by the launcher. Fails the process
+/// when no price could be fetched or the average was not accepted, so the
+/// launcher can report it.
+async fn single_client_process(times: u64, client_id: String, credentials: Arc<Credentials>) -> Result<(), String> {
+    let (tx, rx) = tokio::sync::mpsc::channel::<(String, f64)>(1);
+    get_price_from_socket_stream(times, client_id, tx).await.map_err(|e| e.to_string())?;
+    send_to_server(rx, credentials).await
+}
+
+async fn client_process(times: u64, credentials: Arc<Credentials>) -> Result<(), String> {
+    let (tx, rx) = tokio::sync::mpsc::channel::<(String, f64)>(credentials.signing_keys.len());
+    for client_id in credentials.signing_keys.keys() {
         let tx_clone = tx.clone();
+        let client_id = client_id.clone();
         tokio::spawn(async move {
-            let result = get_price_from_socket_stream(times, id, tx_clone).await;
+            let result = get_price_from_socket_stream(times, client_id, tx_clone).await;
             if result.is_err() {
                 println!("Error:: {}", result.err().unwrap());
             }
         });
     }
-    send_to_server(rx).await;
+    // Only the fetch tasks hold senders now, so a client whose fetch failed
+    // ends the wait instead of being waited for forever.
+    drop(tx);
+    send_to_server(rx, credentials).await
 }
 
-async fn get_price_from_socket_stream(times: u64, id: usize, tx: tokio::sync::mpsc::Sender<(usize, f64)>) -> Result<(), Box<dyn std::error::Error>> {
+async fn get_price_from_socket_stream(times: u64, id: String, tx: tokio::sync::mpsc::Sender<(String, f64)>) -> Result<(), Box<dyn std::error::Error>> {
     let (stream, _response) = tokio_tungstenite::connect_async(WEB_SOCKET_STREAM).await?;
     let (_write, mut read) = stream.split();
     let start_time = tokio::time::Instant::now();
     let fetch_time = tokio::time::Duration::from_secs(times);
     let mut prices = Vec::new();
-    println!("Client {id} connected to WebSocket.");
+    println!("{id} connected to WebSocket.");
     while start_time.elapsed() < fetch_time {
         if let Some(message) = read.next().await {
             let message = message?.to_string();
-            let btc = serde_json::from_str::<BTC>(&message);
-            if btc.is_ok() {
-                let price = btc.unwrap().c;
-                prices.push(price);
+            if let Ok(btc) = serde_json::from_str::<Btc>(&message) {
+                prices.push(btc.c);
             }
         }
     }
+    if prices.is_empty() {
+        return Err(format!("{} received no prices to average", id).into());
+    }
     let avg = prices.iter().sum::<f64>() / prices.len() as f64;
     println!("The average price is: {} for client id: {}", avg, id);
     tx.send((id, avg)).await?;
     Ok(())
 }
 
-async fn send_to_server(mut rx: tokio::sync::mpsc::Receiver<(usize, f64)>) {
-    let mut count = 0;
-    let keys = keys::load_keys();
-    while let Some(value) = rx.recv().await {
-        let client_id = format!("client{}", value.0);
-        let average_price = value.1;
-        let key_data = keys.iter().find(|data| { data.client_id == client_id });
-        if key_data.is_some() {
-            let key_data = key_data.unwrap();
-            let secret_key = hex::decode(key_data.private.clone()).unwrap().try_into().unwrap();
-            let signing_key = SigningKey::from_bytes(&secret_key);
-            let message = format!("{}:{}", client_id, average_price);
-            let signature = signing_key.sign(message.as_bytes());
-            let signature_hex = hex::encode(signature.to_bytes());
-            let signed_message = keys::SignedMessage {
-                client_id,
-                average: average_price.to_string(),
-                signature: signature_hex,
-            };
-            let encoded = serde_json::to_vec(&signed_message).unwrap();
-            let mut stream = TcpStream::connect("127.0.0.1:8080").await.expect("Failed to connect");
-            stream.write_all(&encoded).await.expect("Failed to send");
+/// Submits each average as it arrives, to the aggregator or, in gossip mode,
+/// to the other peers. Submissions run concurrently since each one waits for
+/// its round to close. Fails if any client never produced an average, could
+/// not submit it or had it rejected.
+async fn send_to_server(mut rx: tokio::sync::mpsc::Receiver<(String, f64)>, credentials: Arc<Credentials>) -> Result<(), String> {
+    let expected = credentials.signing_keys.len();
+    let mut received = HashSet::new();
+    let mut submissions = Vec::new();
+    while let Some((client_id, average_price)) = rx.recv().await {
+        if credentials.signing_keys.contains_key(&client_id) && received.insert(client_id.clone()) {
+            let credentials = credentials.clone();
+            submissions.push(tokio::spawn(async move {
+                let signing_key = &credentials.signing_keys[&client_id];
+                let result = match &credentials.gossip {
+                    Some(gossip) => gossip.run(&client_id, average_price).await.map_err(Into::into),
+                    None => submit(&client_id, average_price, signing_key, &credentials).await,
+                };
+                match result {
+                    Ok(result) => match result.average {
+                        Some(average) => {
+                            println!(
+                                "Round {} closed for {}: aggregated {} {} from {} clients, {} excluded",
+                                result.round_id,
+                                client_id,
+                                result.strategy,
+                                average,
+                                result.contributors.len(),
+                                result.excluded.len()
+                            );
+                            match &result.attestation {
+                                Some(attestation) => println!("Round {} attested by {}", result.round_id, attestation.signer_ids().join(", ")),
+                                None => println!("Round {} is not attested", result.round_id),
+                            }
+                            if let Some(exclusion) = result.excluded.iter().find(|exclusion| exclusion.client_id == client_id) {
+                                println!("{}'s average was excluded: {}", client_id, exclusion.reason);
+                            }
+                        }
+                        None => println!("Round {} failed for {}: quorum not reached", result.round_id, client_id),
+                    },
+                    Err(e) => {
+                        println!("Error:: {} could not submit its average: {}", client_id, e);
+                        return Err(client_id);
+                    }
+                }
+                Ok(())
+            }));
 
-            count += 1;
-            if count == 5 {
+            if received.len() == expected {
                 break;
             }
         }
     };
+    let mut failed: Vec<String> = credentials
+        .signing_keys
+        .keys()
+        .filter(|client_id| !received.contains(*client_id))
+        .cloned()
+        .collect();
+    failed.sort();
+    for submission in submissions {
+        match submission.await {
+            Ok(Ok(())) => {}
+            Ok(Err(client_id)) => failed.push(client_id),
+            Err(e) => failed.push(e.to_string()),
+        }
+    }
+    if !failed.is_empty() {
+        return Err(format!("no accepted submission from {}", failed.join(", ")));
+    }
+    Ok(())
+}
+
+/// Signs the average for the round the aggregator announces, submits it,
+/// endorses the proposed result if it follows from the signed inputs and
+/// waits for that round's final result.
+async fn submit(
+    client_id: &str,
+    average: f64,
+    signing_key: &SigningKey,
+    credentials: &Credentials,
+) -> Result<protocol::RoundResult, Box<dyn std::error::Error + Send + Sync>> {
+    let stream = TcpStream::connect(&credentials.server).await?;
+    let mut framed = transport::connect(stream, signing_key, &credentials.aggregator_key).await?;
+    let clients = credentials.keys.read().unwrap().clients(chrono::Utc::now()).len();
+    framed.codec_mut().set_max_frame_length(protocol::max_frame_length(clients));
+    let round_id = protocol::hello(&mut framed, client_id).await?;
+    let signed_message = keys::SignedMessage::sign(client_id, average, round_id, signing_key);
+    protocol::request(&mut framed, Message::SignedAverage(signed_message.clone())).await?;
+    println!("Aggregator accepted the average of {} for round {}", client_id, round_id);
+    loop {
+        match tokio::time::timeout(RESULT_TIMEOUT, protocol::round_update(&mut framed, round_id)).await?? {
+            RoundUpdate::Proposal(proposal) => {
+                let checked = attestation::check_proposal(&proposal, &signed_message, &credentials.keys.read().unwrap());
+                if let Err(e) = checked {
+                    println!("❌ {} refuses to endorse round {}: {}", client_id, round_id, e);
+                    continue;
+                }
+                let signature = attestation::sign(&proposal.result, &attestation::secret_key(signing_key));
+                let attest = Message::Attest {
+                    round_id,
+                    client_id: client_id.to_string(),
+                    key_id: keys::key_id(&signing_key.verifying_key()),
+                    signature,
+                };
+                protocol::request(&mut framed, attest).await?;
+            }
+            RoundUpdate::Result(result) => return Ok(result),
+        }
+    }
 }
\ No newline at end of file
@@ -0,0 +1,411 @@
+use crate::attestation::Attestation;
+use crate::keys::SignedMessage;
+use crate::robust::Exclusion;
+use bytes::{Buf, Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+use futures_util::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::io;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Decoder, Encoder, Framed, LengthDelimitedCodec, LengthDelimitedCodecError};
+
+/// Bumped whenever the message layout changes incompatibly.
+pub const PROTOCOL_VERSION: u8 = 10;
+/// Largest frame either side accepts, version byte included, until
+/// [`MessageCodec::set_max_frame_length`] sizes it for the cluster.
+pub const MAX_FRAME_LENGTH: usize = 16 * 1024;
+/// What each client adds to the largest message, a proposal: its signed
+/// input, and its id among the contributors and the signers.
+const FRAME_BYTES_PER_CLIENT: usize = 1024;
+/// Authentication tag added to every encrypted chunk.
+const TAG_LENGTH: usize = 16;
+/// Largest message Noise encrypts in one go, tag included; longer frames are
+/// encrypted in chunks of this size.
+const NOISE_MESSAGE_LENGTH: usize = 65535;
+
+/// Largest frame a cluster of `clients` needs, see [`FRAME_BYTES_PER_CLIENT`].
+pub fn max_frame_length(clients: usize) -> usize {
+    MAX_FRAME_LENGTH + clients * FRAME_BYTES_PER_CLIENT
+}
+
+/// The plaintext of the frame carrying `message`: the protocol version, then
+/// the message as JSON.
+pub fn frame(message: &Message) -> Result<Vec<u8>, ProtocolError> {
+    let mut body = vec![PROTOCOL_VERSION];
+    serde_json::to_writer(&mut body, message).map_err(ProtocolError::Malformed)?;
+    Ok(body)
+}
+
+/// Reads the message out of the plaintext of a frame, see [`frame`].
+pub fn parse_frame(mut frame: &[u8]) -> Result<Message, ProtocolError> {
+    if !frame.has_remaining() {
+        return Err(ProtocolError::Empty);
+    }
+    let version = frame.get_u8();
+    if version != PROTOCOL_VERSION {
+        return Err(ProtocolError::UnsupportedVersion(version));
+    }
+    serde_json::from_slice(frame).map_err(ProtocolError::Malformed)
+}
+
+/// Everything that travels between clients and the aggregator, or between
+/// peers in [`crate::gossip`] mode. On the wire
+/// each message is a frame: a 4-byte big-endian length, then the protocol
+/// version and the message as JSON, encrypted once a [`crate::transport`]
+/// handshake has set up the channel.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Message {
+    /// First message on a connection; names the client that will submit.
+    Hello { client_id: String },
+    /// Reply to `Hello`: the round the aggregator is collecting for.
+    Welcome { round_id: u64 },
+    SignedAverage(SignedMessage),
+    /// The previous message was accepted.
+    Ack,
+    /// The previous message was rejected; the connection may be closed.
+    Error { reason: String },
+    /// Pushed to every connected client when a round closes, asking its
+    /// contributors to endorse the result with an `Attest`.
+    Proposal(Proposal),
+    /// A contributor's BLS signature over the proposed result of a round,
+    /// made with its registry key `key_id`.
+    Attest { round_id: u64, client_id: String, key_id: String, signature: String },
+    /// Pushed to every connected client once a round's result is final.
+    RoundResult(RoundResult),
+    /// Sent by every peer at a fixed interval, see [`crate::election`].
+    Heartbeat { client_id: String },
+}
+
+/// A round result before it is final, with the signed inputs it was derived
+/// from so that contributors can check it before endorsing it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Proposal {
+    pub result: RoundResult,
+    pub inputs: Vec<SignedMessage>,
+}
+
+/// What a client waiting on a round hears next.
+#[derive(Debug, Clone, PartialEq)]
+pub enum RoundUpdate {
+    Proposal(Proposal),
+    Result(RoundResult),
+}
+
+/// Outcome of a closed round.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RoundResult {
+    pub round_id: u64,
+    pub closed_at: DateTime<Utc>,
+    pub contributors: Vec<String>,
+    /// `None` when fewer than the quorum submitted before the deadline.
+    pub average: Option<f64>,
+    /// How the contributions were combined, e.g. `mad:3.5`.
+    pub strategy: String,
+    /// Contributors the strategy left out of the average.
+    #[serde(default)]
+    pub excluded: Vec<Exclusion>,
+    /// The aggregator's [`crate::audit`] log head when the round closed,
+    /// endorsed with the result; `None` without an audit log.
+    #[serde(default)]
+    pub audit_head: Option<String>,
+    /// Endorsement by at least a quorum of clients; `None` for failed rounds
+    /// and for rounds too few contributors endorsed in time.
+    #[serde(default)]
+    pub attestation: Option<Attestation>,
+}
+
+#[derive(Debug, thiserror::Error)]
+pub enum ProtocolError {
+    #[error("I/O error: {0}")]
+    Io(#[from] io::Error),
+    #[error("frame exceeds the {0} byte limit")]
+    Oversized(usize),
+    #[error("empty frame")]
+    Empty,
+    #[error("unsupported protocol version {0}, expected {PROTOCOL_VERSION}")]
+    UnsupportedVersion(u8),
+    #[error("malformed message: {0}")]
+    Malformed(serde_json::Error),
+    #[error("connection closed before a reply arrived")]
+    Closed,
+    #[error("rejected by peer: {0}")]
+    Rejected(String),
+    #[error("unexpected reply: {0:?}")]
+    Unexpected(Box<Message>),
+    #[error("handshake failed: {0}")]
+    Handshake(String),
+    #[error("cannot encrypt frame: {0}")]
+    Encrypt(String),
+    #[error("cannot decrypt frame")]
+    Decrypt,
+}
+
+impl ProtocolError {
+    pub(crate) fn from_codec(e: io::Error, max_frame_length: usize) -> Self {
+        if e.get_ref().is_some_and(|inner| inner.is::<LengthDelimitedCodecError>()) {
+            ProtocolError::Oversized(max_frame_length)
+        } else {
+            ProtocolError::Io(e)
+        }
+    }
+}
+
+/// Length-delimited, versioned JSON framing for [`Message`], optionally
+/// encrypted with an established Noise session.
+#[derive(Debug)]
+pub struct MessageCodec {
+    frames: LengthDelimitedCodec,
+    session: Option<snow::TransportState>,
+    max_frame_length: usize,
+    /// The last frame decoded, readable or not, see [`MessageCodec::take_last_frame`].
+    last_frame: Option<Bytes>,
+}
+
+impl MessageCodec {
+    /// Unencrypted framing; on the network use [`crate::transport`], which
+    /// returns a secured codec after the handshake.
+    pub fn new() -> Self {
+        Self {
+            frames: frame_codec(),
+            session: None,
+            max_frame_length: MAX_FRAME_LENGTH,
+            last_frame: None,
+        }
+    }
+
+    pub(crate) fn secure(frames: LengthDelimitedCodec, session: snow::TransportState) -> Self {
+        Self {
+            max_frame_length: frames.max_frame_length(),
+            frames,
+            session: Some(session),
+            last_frame: None,
+        }
+    }
+
+    /// The bytes of the message, or error, decoded last: the version byte
+    /// and the JSON after decryption, or the ciphertext of a frame that did
+    /// not decrypt. `None` if the frame could not even be delimited.
+    pub fn take_last_frame(&mut self) -> Option<Bytes> {
+        self.last_frame.take()
+    }
+
+    /// Raises, or lowers, the largest frame sent or accepted, e.g. to
+    /// [`max_frame_length`] of the cluster once it is known.
+    pub fn set_max_frame_length(&mut self, max_frame_length: usize) {
+        self.frames.set_max_frame_length(max_frame_length);
+        self.max_frame_length = max_frame_length;
+    }
+}
+
+/// The length-delimited framing shared by handshake and message frames.
+pub(crate) fn frame_codec() -> LengthDelimitedCodec {
+    LengthDelimitedCodec::builder().max_frame_length(MAX_FRAME_LENGTH).new_codec()
+}
+
+impl Default for MessageCodec {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = Message;
+    type Error = ProtocolError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Message>, ProtocolError> {
+        let max_frame_length = self.max_frame_length;
+        self.last_frame = None;
+        let Some(frame) = self.frames.decode(src).map_err(|e| ProtocolError::from_codec(e, max_frame_length))? else {
+            return Ok(None);
+        };
+        let frame = frame.freeze();
+        let frame = match &mut self.session {
+            Some(session) => {
+                let mut plaintext = Vec::with_capacity(frame.len());
+                let mut buffer = vec![0; NOISE_MESSAGE_LENGTH];
+                for chunk in frame.chunks(NOISE_MESSAGE_LENGTH) {
+                    let Ok(length) = session.read_message(chunk, &mut buffer) else {
+                        self.last_frame = Some(frame);
+                        return Err(ProtocolError::Decrypt);
+                    };
+                    plaintext.extend_from_slice(&buffer[..length]);
+                }
+                Bytes::from(plaintext)
+            }
+            None => frame,
+        };
+        self.last_frame = Some(frame.clone());
+        parse_frame(&frame).map(Some)
+    }
+}
+
+impl Encoder<Message> for MessageCodec {
+    type Error = ProtocolError;
+
+    fn encode(&mut self, message: Message, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+        let mut body = frame(&message)?;
+        if let Some(session) = &mut self.session {
+            let chunks = body.chunks(NOISE_MESSAGE_LENGTH - TAG_LENGTH);
+            if body.len() + chunks.len() * TAG_LENGTH > self.max_frame_length {
+                return Err(ProtocolError::Oversized(self.max_frame_length));
+            }
+            let mut ciphertext = Vec::with_capacity(body.len() + chunks.len() * TAG_LENGTH);
+            let mut buffer = vec![0; NOISE_MESSAGE_LENGTH];
+            for chunk in chunks {
+                let length = session
+                    .write_message(chunk, &mut buffer)
+                    .map_err(|e| ProtocolError::Encrypt(e.to_string()))?;
+                ciphertext.extend_from_slice(&buffer[..length]);
+            }
+            body = ciphertext;
+        }
+        let max_frame_length = self.max_frame_length;
+        self.frames
+            .encode(Bytes::from(body), dst)
+            .map_err(|e| ProtocolError::from_codec(e, max_frame_length))
+    }
+}
+
+/// Sends `message` and returns the peer's reply; an `Error` reply becomes
+/// [`ProtocolError::Rejected`]. Proposals and round results pushed in the
+/// meantime are not replies and are skipped.
+async fn exchange<S>(framed: &mut Framed<S, MessageCodec>, message: Message) -> Result<Message, ProtocolError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    framed.send(message).await?;
+    loop {
+        match framed.next().await {
+            Some(Ok(Message::RoundResult(_) | Message::Proposal(_))) => continue,
+            Some(Ok(Message::Error { reason })) => return Err(ProtocolError::Rejected(reason)),
+            Some(reply) => return reply,
+            None => return Err(ProtocolError::Closed),
+        }
+    }
+}
+
+/// Sends `message` and expects an `Ack`.
+pub async fn request<S>(framed: &mut Framed<S, MessageCodec>, message: Message) -> Result<(), ProtocolError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match exchange(framed, message).await? {
+        Message::Ack => Ok(()),
+        other => Err(ProtocolError::Unexpected(Box::new(other))),
+    }
+}
+
+/// Introduces `client_id` and returns the round the aggregator is collecting.
+pub async fn hello<S>(framed: &mut Framed<S, MessageCodec>, client_id: &str) -> Result<u64, ProtocolError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match exchange(framed, Message::Hello { client_id: client_id.to_string() }).await? {
+        Message::Welcome { round_id } => Ok(round_id),
+        other => Err(ProtocolError::Unexpected(Box::new(other))),
+    }
+}
+
+/// Waits for the proposal or the result of `round_id`, skipping those of
+/// other rounds.
+pub async fn round_update<S>(framed: &mut Framed<S, MessageCodec>, round_id: u64) -> Result<RoundUpdate, ProtocolError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        match framed.next().await {
+            Some(Ok(Message::Proposal(proposal))) if proposal.result.round_id == round_id => {
+                return Ok(RoundUpdate::Proposal(proposal));
+            }
+            Some(Ok(Message::RoundResult(result))) if result.round_id == round_id => return Ok(RoundUpdate::Result(result)),
+            Some(Ok(Message::RoundResult(_) | Message::Proposal(_))) => continue,
+            Some(Ok(other)) => return Err(ProtocolError::Unexpected(Box::new(other))),
+            Some(Err(e)) => return Err(e),
+            None => return Err(ProtocolError::Closed),
+        }
+    }
+}
+
+/// Waits for the result of `round_id` without endorsing it.
+pub async fn round_result<S>(framed: &mut Framed<S, MessageCodec>, round_id: u64) -> Result<RoundResult, ProtocolError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    loop {
+        if let RoundUpdate::Result(result) = round_update(framed, round_id).await? {
+            return Ok(result);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio_util::codec::FramedRead;
+
+    fn encoded(message: Message) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        MessageCodec::new().encode(message, &mut buffer).unwrap();
+        buffer
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let messages = [
+            Message::Hello { client_id: "client1".to_string() },
+            Message::Welcome { round_id: 3 },
+            Message::Ack,
+            Message::Error { reason: "nope".to_string() },
+        ];
+        for message in messages {
+            let mut buffer = encoded(message.clone());
+            assert_eq!(MessageCodec::new().decode(&mut buffer).unwrap(), Some(message));
+            assert!(buffer.is_empty());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fragmented_writes_are_reassembled() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        let mut bytes = encoded(Message::Hello { client_id: "client1".to_string() });
+        bytes.extend_from_slice(&encoded(Message::Ack));
+        tokio::spawn(async move {
+            for byte in bytes {
+                writer.write_all(&[byte]).await.unwrap();
+                writer.flush().await.unwrap();
+                tokio::task::yield_now().await;
+            }
+        });
+        let mut frames = FramedRead::new(reader, MessageCodec::new());
+        assert_eq!(frames.next().await.unwrap().unwrap(), Message::Hello { client_id: "client1".to_string() });
+        assert_eq!(frames.next().await.unwrap().unwrap(), Message::Ack);
+        assert!(frames.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_oversized_frames_are_rejected() {
+        let (mut writer, reader) = tokio::io::duplex(64);
+        writer.write_all(&((MAX_FRAME_LENGTH as u32 + 1).to_be_bytes())).await.unwrap();
+        let mut frames = FramedRead::new(reader, MessageCodec::new());
+        assert!(matches!(frames.next().await, Some(Err(ProtocolError::Oversized(MAX_FRAME_LENGTH)))));
+
+        let reason = "x".repeat(MAX_FRAME_LENGTH);
+        let result = MessageCodec::new().encode(Message::Error { reason }, &mut BytesMut::new());
+        assert!(matches!(result, Err(ProtocolError::Oversized(MAX_FRAME_LENGTH))));
+    }
+
+    #[test]
+    fn test_rejects_other_versions_and_garbage() {
+        let mut buffer = encoded(Message::Ack);
+        buffer[4] = PROTOCOL_VERSION + 1;
+        assert!(matches!(
+            MessageCodec::new().decode(&mut buffer),
+            Err(ProtocolError::UnsupportedVersion(v)) if v == PROTOCOL_VERSION + 1
+        ));
+
+        let mut buffer = BytesMut::from(&[0, 0, 0, 3, PROTOCOL_VERSION, b'{', b'x'][..]);
+        assert!(matches!(MessageCodec::new().decode(&mut buffer), Err(ProtocolError::Malformed(_))));
+    }
+}
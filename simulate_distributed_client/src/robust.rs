@@ -0,0 +1,233 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::str::FromStr;
+
+/// Scales the MAD so that it estimates the standard deviation of normally
+/// distributed values; with it the usual 3.5 cut-off applies (Iglewicz and
+/// Hoaglin's modified z-score).
+const MAD_SCALE: f64 = 0.6745;
+
+/// How the aggregator combines the averages of one round.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Strategy {
+    /// Plain mean; one lying client moves it arbitrarily far.
+    Mean,
+    /// Middle value (mean of the two middle values for an even count).
+    Median,
+    /// Mean after dropping this fraction of values from each end.
+    TrimmedMean(f64),
+    /// Mean after dropping values whose modified z-score exceeds this.
+    Mad(f64),
+}
+
+impl FromStr for Strategy {
+    type Err = String;
+
+    /// `mean`, `median`, `trimmed-mean[:FRACTION]` (default 0.2) or
+    /// `mad[:THRESHOLD]` (default 3.5).
+    fn from_str(value: &str) -> Result<Self, String> {
+        let (name, parameter) = match value.split_once(':') {
+            Some((name, parameter)) => {
+                let parameter = parameter
+                    .parse::<f64>()
+                    .map_err(|_| format!("invalid parameter '{}' in strategy '{}'", parameter, value))?;
+                (name, Some(parameter))
+            }
+            None => (value, None),
+        };
+        match (name, parameter) {
+            ("mean", None) => Ok(Strategy::Mean),
+            ("median", None) => Ok(Strategy::Median),
+            ("trimmed-mean", trim) => match trim.unwrap_or(0.2) {
+                trim if (0.0..0.5).contains(&trim) => Ok(Strategy::TrimmedMean(trim)),
+                trim => Err(format!("trimmed-mean fraction must be in [0, 0.5), got {}", trim)),
+            },
+            ("mad", threshold) => match threshold.unwrap_or(3.5) {
+                threshold if threshold > 0.0 => Ok(Strategy::Mad(threshold)),
+                threshold => Err(format!("mad threshold must be positive, got {}", threshold)),
+            },
+            _ => Err(format!(
+                "unknown strategy '{}', expected mean, median, trimmed-mean[:FRACTION] or mad[:THRESHOLD]",
+                value
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Strategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Strategy::Mean => write!(f, "mean"),
+            Strategy::Median => write!(f, "median"),
+            Strategy::TrimmedMean(trim) => write!(f, "trimmed-mean:{}", trim),
+            Strategy::Mad(threshold) => write!(f, "mad:{}", threshold),
+        }
+    }
+}
+
+/// A client whose average did not count towards the result.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Exclusion {
+    pub client_id: String,
+    pub average: f64,
+    pub reason: String,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Aggregate {
+    pub value: f64,
+    pub excluded: Vec<Exclusion>,
+}
+
+fn median(sorted: &[f64]) -> f64 {
+    let middle = sorted.len() / 2;
+    if sorted.len().is_multiple_of(2) {
+        (sorted[middle - 1] + sorted[middle]) / 2.0
+    } else {
+        sorted[middle]
+    }
+}
+
+fn mean<'a>(values: impl Iterator<Item = &'a f64>) -> f64 {
+    let (sum, count) = values.fold((0.0, 0usize), |(sum, count), value| (sum + value, count + 1));
+    sum / count as f64
+}
+
+/// Combines the averages of one round. Returns `None` when there are none.
+/// NaN and infinities are skipped, as they would poison every strategy; the
+/// callers refuse them before they get here.
+pub fn aggregate(strategy: Strategy, averages: &BTreeMap<String, f64>) -> Option<Aggregate> {
+    let mut sorted: Vec<(&String, f64)> = averages
+        .iter()
+        .filter(|(_, average)| average.is_finite())
+        .map(|(client_id, average)| (client_id, *average))
+        .collect();
+    if sorted.is_empty() {
+        return None;
+    }
+    sorted.sort_by(|a, b| a.1.total_cmp(&b.1));
+    let values: Vec<f64> = sorted.iter().map(|(_, average)| *average).collect();
+    let exclusion = |client_id: &String, average: f64, reason: String| Exclusion {
+        client_id: client_id.clone(),
+        average,
+        reason,
+    };
+
+    let aggregate = match strategy {
+        Strategy::Mean => Aggregate { value: mean(values.iter()), excluded: Vec::new() },
+        Strategy::Median => Aggregate { value: median(&values), excluded: Vec::new() },
+        Strategy::TrimmedMean(trim) => {
+            let cut = (values.len() as f64 * trim).floor() as usize;
+            let kept = &values[cut..values.len() - cut];
+            let mut excluded = Vec::new();
+            for (client_id, average) in &sorted[..cut] {
+                excluded.push(exclusion(client_id, *average, format!("among the lowest {} trimmed", cut)));
+            }
+            for (client_id, average) in &sorted[values.len() - cut..] {
+                excluded.push(exclusion(client_id, *average, format!("among the highest {} trimmed", cut)));
+            }
+            Aggregate { value: mean(kept.iter()), excluded }
+        }
+        Strategy::Mad(threshold) => {
+            let center = median(&values);
+            let mut deviations: Vec<f64> = values.iter().map(|value| (value - center).abs()).collect();
+            deviations.sort_by(f64::total_cmp);
+            let mad = median(&deviations);
+            let mut kept = Vec::new();
+            let mut excluded = Vec::new();
+            for (client_id, average) in &sorted {
+                // With MAD 0 most clients agree exactly; anyone else is an outlier.
+                let score = if mad == 0.0 {
+                    if *average == center { 0.0 } else { f64::INFINITY }
+                } else {
+                    MAD_SCALE * (average - center).abs() / mad
+                };
+                if score > threshold {
+                    let reason = format!("modified z-score {:.1} above {} (median {}, MAD {})", score, threshold, center, mad);
+                    excluded.push(exclusion(client_id, *average, reason));
+                } else {
+                    kept.push(*average);
+                }
+            }
+            Aggregate { value: mean(kept.iter()), excluded }
+        }
+    };
+    Some(aggregate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Four honest clients around 100k and one reporting $1.
+    fn with_liar() -> BTreeMap<String, f64> {
+        [("client1", 100_010.0), ("client2", 99_990.0), ("client3", 100_000.0), ("client4", 100_020.0), ("client5", 1.0)]
+            .into_iter()
+            .map(|(client_id, average)| (client_id.to_string(), average))
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_strategies() {
+        assert_eq!("mean".parse(), Ok(Strategy::Mean));
+        assert_eq!("median".parse(), Ok(Strategy::Median));
+        assert_eq!("trimmed-mean".parse(), Ok(Strategy::TrimmedMean(0.2)));
+        assert_eq!("trimmed-mean:0.1".parse(), Ok(Strategy::TrimmedMean(0.1)));
+        assert_eq!("mad:3".parse(), Ok(Strategy::Mad(3.0)));
+        assert!("trimmed-mean:0.5".parse::<Strategy>().is_err());
+        assert!("median:2".parse::<Strategy>().is_err());
+        assert!("vote".parse::<Strategy>().is_err());
+    }
+
+    #[test]
+    fn test_mean_is_dragged_by_a_liar() {
+        let result = aggregate(Strategy::Mean, &with_liar()).unwrap();
+        assert!(result.value < 81_000.0);
+        assert!(result.excluded.is_empty());
+    }
+
+    #[test]
+    fn test_median_ignores_a_liar() {
+        assert_eq!(aggregate(Strategy::Median, &with_liar()).unwrap().value, 100_000.0);
+    }
+
+    #[test]
+    fn test_trimmed_mean_drops_both_ends() {
+        let result = aggregate(Strategy::TrimmedMean(0.2), &with_liar()).unwrap();
+        assert_eq!(result.value, 100_000.0);
+        let excluded: Vec<&str> = result.excluded.iter().map(|exclusion| exclusion.client_id.as_str()).collect();
+        assert_eq!(excluded, vec!["client5", "client4"]);
+    }
+
+    #[test]
+    fn test_mad_rejects_only_the_outlier() {
+        let result = aggregate(Strategy::Mad(3.5), &with_liar()).unwrap();
+        assert_eq!(result.value, 100_005.0);
+        assert_eq!(result.excluded.len(), 1);
+        assert_eq!(result.excluded[0].client_id, "client5");
+        assert!(result.excluded[0].reason.starts_with("modified z-score"));
+    }
+
+    #[test]
+    fn test_non_finite_averages_are_skipped() {
+        let mut averages = with_liar();
+        averages.insert("client6".to_string(), f64::NAN);
+        averages.insert("client7".to_string(), f64::INFINITY);
+        for strategy in [Strategy::Mean, Strategy::Median, Strategy::TrimmedMean(0.0), Strategy::Mad(3.5)] {
+            let result = aggregate(strategy, &averages).unwrap();
+            assert_eq!(result, aggregate(strategy, &with_liar()).unwrap(), "{}", strategy);
+        }
+        let only_nan = BTreeMap::from([("client1".to_string(), f64::NAN)]);
+        assert_eq!(aggregate(Strategy::Mean, &only_nan), None);
+    }
+
+    #[test]
+    fn test_mad_with_colluding_minority() {
+        let mut averages = with_liar();
+        averages.insert("client4".to_string(), 1.0);
+        let result = aggregate(Strategy::Mad(3.5), &averages).unwrap();
+        assert_eq!(result.value, 100_000.0);
+        assert_eq!(result.excluded.len(), 2);
+    }
+}
@@ -0,0 +1,87 @@
+use chrono::{DateTime, Local, NaiveTime, TimeZone, Utc};
+use std::time::Duration;
+
+/// Parses `--start-at`: a wall-clock time today such as `10:01:01` (local
+/// time), or a full RFC 3339 timestamp as handed out by the launcher.
+pub fn parse_start_at(value: &str, now: DateTime<Local>) -> Result<DateTime<Utc>, String> {
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+    let time = NaiveTime::parse_from_str(value, "%H:%M:%S")
+        .map_err(|_| format!("invalid start time '{}', expected HH:MM:SS or RFC 3339", value))?;
+    let start = Local
+        .from_local_datetime(&now.date_naive().and_time(time))
+        .earliest()
+        .ok_or_else(|| format!("start time '{}' does not exist today", value))?;
+    if start < now {
+        return Err(format!("start time '{}' has already passed", value));
+    }
+    Ok(start.with_timezone(&Utc))
+}
+
+/// Parses an interval such as `5s`, `500ms` or `1m`; a bare number is seconds.
+pub fn parse_interval(value: &str) -> Result<Duration, String> {
+    let invalid = || format!("invalid interval '{}', expected e.g. 5s, 500ms or 1m", value);
+    let (number, unit) = match value.find(|c: char| !c.is_ascii_digit()) {
+        Some(index) => value.split_at(index),
+        None => (value, "s"),
+    };
+    let number = number.parse::<u64>().map_err(|_| invalid())?;
+    let interval = match unit {
+        "ms" => Duration::from_millis(number),
+        "s" => Duration::from_secs(number),
+        "m" => Duration::from_secs(number * 60),
+        _ => return Err(invalid()),
+    };
+    if interval.is_zero() {
+        return Err(invalid());
+    }
+    Ok(interval)
+}
+
+/// The first multiple of `every` since the Unix epoch strictly after `now`,
+/// so independent processes asking at slightly different moments agree.
+pub fn next_tick(now: DateTime<Utc>, every: Duration) -> DateTime<Utc> {
+    let every = every.as_millis() as i64;
+    let next = (now.timestamp_millis().div_euclid(every) + 1) * every;
+    DateTime::from_timestamp_millis(next).expect("tick is within chrono's range")
+}
+
+/// Sleeps until `start`; returns immediately if it is already behind us.
+pub async fn sleep_until(start: DateTime<Utc>) {
+    if let Ok(wait) = (start - Utc::now()).to_std() {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_interval() {
+        assert_eq!(parse_interval("5s"), Ok(Duration::from_secs(5)));
+        assert_eq!(parse_interval("250ms"), Ok(Duration::from_millis(250)));
+        assert_eq!(parse_interval("2m"), Ok(Duration::from_secs(120)));
+        assert_eq!(parse_interval("7"), Ok(Duration::from_secs(7)));
+        assert!(parse_interval("0s").is_err());
+        assert!(parse_interval("5h").is_err());
+    }
+
+    #[test]
+    fn test_next_tick_is_aligned_and_in_the_future() {
+        let now = DateTime::parse_from_rfc3339("2025-01-01T10:01:03.400Z").unwrap().with_timezone(&Utc);
+        let tick = next_tick(now, Duration::from_secs(5));
+        assert_eq!(tick.to_rfc3339(), "2025-01-01T10:01:05+00:00");
+        assert_eq!(next_tick(tick, Duration::from_secs(5)).to_rfc3339(), "2025-01-01T10:01:10+00:00");
+    }
+
+    #[test]
+    fn test_parse_start_at_rejects_past_times() {
+        let now = Local.with_ymd_and_hms(2025, 1, 1, 10, 0, 0).unwrap();
+        let start = parse_start_at("10:01:01", now).unwrap();
+        assert_eq!(start, Local.with_ymd_and_hms(2025, 1, 1, 10, 1, 1).unwrap());
+        assert!(parse_start_at("09:59:59", now).is_err());
+        assert!(parse_start_at("2025-01-01T10:01:01Z", now).is_ok());
+    }
+}
@@ -0,0 +1,542 @@
+use crate::aggregator::Aggregator;
+use crate::attestation;
+use crate::keys::{self, KeySet, Registry, RegistryEntry, SignedMessage};
+use crate::protocol::{self, Message, RoundResult, RoundUpdate};
+use crate::robust::{self, Strategy};
+use crate::transport;
+use chrono::{TimeDelta, Utc};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use std::collections::{BTreeMap, BTreeSet};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinSet;
+
+/// Price the mock feed starts from.
+const START_PRICE: f64 = 100_000.0;
+/// Largest relative move of the mock market between two ticks.
+const VOLATILITY: f64 = 0.001;
+/// Largest relative difference between what two clients see on one tick.
+const QUOTE_NOISE: f64 = 0.0002;
+/// How long a client waits for the next proposal or result, as the client
+/// binary does.
+const RESULT_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Something that goes wrong for one client during a simulated round.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Fault {
+    /// Every message the client sends after the handshake arrives this late.
+    Latency(Duration),
+    /// The client's signed average is lost on the way to the aggregator.
+    DropSubmission,
+    /// The client's endorsement is lost on the way to the aggregator.
+    DropAttestation,
+    /// The aggregator's proposal is lost on the way to the client, so the
+    /// client cannot endorse the result.
+    DropProposal,
+    /// The round result is lost on the way to the client.
+    DropResult,
+    /// The proposal and the result reach the client this late.
+    DownstreamLatency(Duration),
+    /// The average is changed after it was signed.
+    BadSignature,
+    /// The client dies before it submits.
+    CrashBeforeSubmit,
+    /// The client dies after submitting, before it endorses the result.
+    CrashBeforeAttest,
+    /// The client's clock is off by this much when it signs.
+    ClockSkew(TimeDelta),
+}
+
+/// How far one client got with the aggregator.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClientOutcome {
+    /// The average never reached the aggregator.
+    NotSubmitted,
+    /// The aggregator refused the average, for this reason.
+    Rejected(String),
+    /// The aggregator took the average; `endorsed` if it also took the
+    /// client's endorsement of the result.
+    Accepted { endorsed: bool },
+}
+
+/// What a simulated round produced, next to what went into it.
+#[derive(Debug, Clone)]
+pub struct Report {
+    /// The round's result as the aggregator broadcast it; `None` if no
+    /// average got through, so the round never closed.
+    pub result: Option<RoundResult>,
+    pub quorum: usize,
+    pub strategy: Strategy,
+    /// The average each client computed from the mock feed.
+    pub averages: BTreeMap<String, f64>,
+    pub faults: BTreeMap<String, Vec<Fault>>,
+    pub outcomes: BTreeMap<String, ClientOutcome>,
+    /// The round result each client received from the aggregator.
+    pub received: BTreeMap<String, RoundResult>,
+    pub keys: KeySet,
+}
+
+impl Report {
+    /// The averages the aggregator accepted.
+    pub fn accepted(&self) -> BTreeMap<String, f64> {
+        self.outcomes
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome, ClientOutcome::Accepted { .. }))
+            .map(|(client_id, _)| (client_id.clone(), self.averages[client_id]))
+            .collect()
+    }
+
+    /// The clients whose endorsement the aggregator accepted.
+    pub fn endorsed(&self) -> BTreeSet<&str> {
+        self.outcomes
+            .iter()
+            .filter(|(_, outcome)| matches!(outcome, ClientOutcome::Accepted { endorsed: true }))
+            .map(|(client_id, _)| client_id.as_str())
+            .collect()
+    }
+
+    /// The average the aggregator should have arrived at from the accepted
+    /// averages, `None` below the quorum.
+    pub fn expected_average(&self) -> Option<f64> {
+        let accepted = self.accepted();
+        (accepted.len() >= self.quorum)
+            .then(|| robust::aggregate(self.strategy, &accepted))
+            .flatten()
+            .map(|aggregate| aggregate.value)
+    }
+}
+
+/// One client of a simulated round.
+#[derive(Debug)]
+struct SimClient {
+    client_id: String,
+    /// Clients in the simulated cluster, which sizes the frames.
+    cluster: usize,
+    signing_key: SigningKey,
+    average: f64,
+    faults: Vec<Fault>,
+}
+
+impl SimClient {
+    fn latency(&self) -> Duration {
+        self.faults
+            .iter()
+            .map(|fault| match fault {
+                Fault::Latency(latency) => *latency,
+                _ => Duration::ZERO,
+            })
+            .sum()
+    }
+
+    fn downstream_latency(&self) -> Duration {
+        self.faults
+            .iter()
+            .map(|fault| match fault {
+                Fault::DownstreamLatency(latency) => *latency,
+                _ => Duration::ZERO,
+            })
+            .sum()
+    }
+
+    fn skew(&self) -> TimeDelta {
+        self.faults
+            .iter()
+            .map(|fault| match fault {
+                Fault::ClockSkew(skew) => *skew,
+                _ => TimeDelta::zero(),
+            })
+            .sum()
+    }
+
+    fn has(&self, fault: Fault) -> bool {
+        self.faults.contains(&fault)
+    }
+
+    /// Plays the client side of a round the way the client binary does,
+    /// over an in-memory connection, with the faults applied. Returns how far
+    /// the client got, and the round result if it received one.
+    async fn run(self, aggregator: Arc<Aggregator>, aggregator_key: VerifyingKey) -> (ClientOutcome, Option<RoundResult>) {
+        if self.has(Fault::CrashBeforeSubmit) {
+            return (ClientOutcome::NotSubmitted, None);
+        }
+        let (client, server) = tokio::io::duplex(16 * 1024);
+        tokio::spawn(async move { aggregator.handle_connection(server).await });
+        let mut framed = match transport::connect(client, &self.signing_key, &aggregator_key).await {
+            Ok(framed) => framed,
+            Err(e) => return (ClientOutcome::Rejected(e.to_string()), None),
+        };
+        framed.codec_mut().set_max_frame_length(protocol::max_frame_length(self.cluster));
+        let round_id = match protocol::hello(&mut framed, &self.client_id).await {
+            Ok(round_id) => round_id,
+            Err(e) => return (ClientOutcome::Rejected(e.to_string()), None),
+        };
+
+        tokio::time::sleep(self.latency()).await;
+        if self.has(Fault::DropSubmission) {
+            return (ClientOutcome::NotSubmitted, None);
+        }
+        let mut signed = SignedMessage::sign_at(&self.client_id, self.average, round_id, Utc::now() + self.skew(), &self.signing_key);
+        if self.has(Fault::BadSignature) {
+            signed.average = (self.average * 2.0).to_string();
+        }
+        match protocol::request(&mut framed, Message::SignedAverage(signed)).await {
+            Ok(()) => {}
+            Err(protocol::ProtocolError::Rejected(reason)) => return (ClientOutcome::Rejected(reason), None),
+            Err(e) => return (ClientOutcome::Rejected(e.to_string()), None),
+        }
+
+        let mut endorsed = false;
+        loop {
+            let update = tokio::time::timeout(RESULT_TIMEOUT, protocol::round_update(&mut framed, round_id)).await;
+            tokio::time::sleep(self.downstream_latency()).await;
+            let proposal = match update {
+                Ok(Ok(RoundUpdate::Proposal(_))) if self.has(Fault::DropProposal) => continue,
+                Ok(Ok(RoundUpdate::Proposal(proposal))) => proposal,
+                Ok(Ok(RoundUpdate::Result(result))) if !self.has(Fault::DropResult) => {
+                    return (ClientOutcome::Accepted { endorsed }, Some(result));
+                }
+                _ => return (ClientOutcome::Accepted { endorsed }, None),
+            };
+            if self.has(Fault::CrashBeforeAttest) {
+                return (ClientOutcome::Accepted { endorsed: false }, None);
+            }
+            tokio::time::sleep(self.latency()).await;
+            if self.has(Fault::DropAttestation) {
+                continue;
+            }
+            let attest = Message::Attest {
+                round_id,
+                client_id: self.client_id.clone(),
+                key_id: keys::key_id(&self.signing_key.verifying_key()),
+                signature: attestation::sign(&proposal.result, &attestation::secret_key(&self.signing_key)),
+            };
+            endorsed = protocol::request(&mut framed, attest).await.is_ok();
+        }
+    }
+}
+
+/// A single aggregator round played out in-process: `client1..=clientN`
+/// average a mock price feed, connect to a real [`Aggregator`] over
+/// in-memory streams and go through the same handshake, submission and
+/// endorsement as the client binary, with [`Fault`]s injected along the way.
+///
+/// Keys, prices and chaos all come from one RNG seeded with `seed`, so a
+/// failing seed replays the same round. Run it on a runtime with paused time
+/// (`#[tokio::test(start_paused = true)]`) and deadlines and latencies cost
+/// no wall-clock time.
+pub struct Simulation {
+    rng: StdRng,
+    clients: usize,
+    quorum: usize,
+    strategy: Strategy,
+    deadline: Duration,
+    ticks: usize,
+    faults: BTreeMap<String, Vec<Fault>>,
+}
+
+impl Simulation {
+    pub fn new(seed: u64, clients: usize) -> Self {
+        Self {
+            rng: StdRng::seed_from_u64(seed),
+            clients,
+            quorum: clients,
+            strategy: Strategy::Mean,
+            deadline: Duration::from_secs(10),
+            ticks: 10,
+            faults: BTreeMap::new(),
+        }
+    }
+
+    pub fn with_quorum(mut self, quorum: usize) -> Self {
+        self.quorum = quorum;
+        self
+    }
+
+    pub fn with_strategy(mut self, strategy: Strategy) -> Self {
+        self.strategy = strategy;
+        self
+    }
+
+    /// How long the aggregator waits for submissions, and again for
+    /// endorsements, as the server's `--deadline`.
+    pub fn with_deadline(mut self, deadline: Duration) -> Self {
+        self.deadline = deadline;
+        self
+    }
+
+    pub fn with_fault(mut self, client_id: &str, fault: Fault) -> Self {
+        self.faults.entry(client_id.to_string()).or_default().push(fault);
+        self
+    }
+
+    /// Gives each client a random fault with probability `rate`.
+    pub fn with_chaos(mut self, rate: f64) -> Self {
+        for i in 1..=self.clients {
+            if !self.rng.gen_bool(rate) {
+                continue;
+            }
+            let deadline = self.deadline.as_millis() as u64;
+            let fault = match self.rng.gen_range(0..10) {
+                0 => Fault::Latency(Duration::from_millis(self.rng.gen_range(0..deadline * 2))),
+                1 => Fault::DropSubmission,
+                2 => Fault::DropAttestation,
+                3 => Fault::BadSignature,
+                4 => Fault::CrashBeforeSubmit,
+                5 => Fault::CrashBeforeAttest,
+                6 => Fault::DropProposal,
+                7 => Fault::DropResult,
+                8 => Fault::DownstreamLatency(Duration::from_millis(self.rng.gen_range(0..deadline * 2))),
+                _ => Fault::ClockSkew(TimeDelta::seconds(self.rng.gen_range(-60..=60))),
+            };
+            self.faults.entry(format!("client{}", i)).or_default().push(fault);
+        }
+        self
+    }
+
+    /// Plays the round and returns once every client is done and the
+    /// aggregator has decided the round.
+    pub async fn run(mut self) -> Result<Report, String> {
+        let aggregator_key = SigningKey::generate(&mut self.rng);
+        let signing_keys: Vec<SigningKey> = (0..self.clients).map(|_| SigningKey::generate(&mut self.rng)).collect();
+        let registry = Registry {
+            aggregator: hex::encode(aggregator_key.verifying_key().to_bytes()),
+            clients: signing_keys
+                .iter()
+                .enumerate()
+                .map(|(i, signing_key)| RegistryEntry::new(&format!("client{}", i + 1), signing_key, Utc::now() - TimeDelta::hours(1)))
+                .collect(),
+            revoked: vec![],
+        };
+        let keys = KeySet::new(&registry)?;
+        let verifying_key = aggregator_key.verifying_key();
+        let aggregator = Arc::new(Aggregator::new(aggregator_key, &registry, self.quorum)?.with_strategy(self.strategy));
+
+        let market = self.market();
+        let mut clients = Vec::new();
+        for (i, signing_key) in signing_keys.into_iter().enumerate() {
+            let client_id = format!("client{}", i + 1);
+            let quotes: Vec<f64> = market
+                .iter()
+                .map(|price| price * (1.0 + self.rng.gen_range(-QUOTE_NOISE..=QUOTE_NOISE)))
+                .collect();
+            clients.push(SimClient {
+                faults: self.faults.get(&client_id).cloned().unwrap_or_default(),
+                client_id,
+                cluster: self.clients,
+                signing_key,
+                average: quotes.iter().sum::<f64>() / quotes.len() as f64,
+            });
+        }
+        let averages = clients.iter().map(|client| (client.client_id.clone(), client.average)).collect();
+
+        let mut results = aggregator.subscribe();
+        let driver = aggregator.clone();
+        let deadline = self.deadline;
+        let rounds = tokio::spawn(async move { driver.run_rounds(deadline, None).await });
+        let mut running = JoinSet::new();
+        for client in clients {
+            let aggregator = aggregator.clone();
+            running.spawn(async move { (client.client_id.clone(), client.run(aggregator, verifying_key).await) });
+        }
+        let mut outcomes = BTreeMap::new();
+        let mut received = BTreeMap::new();
+        while let Some(joined) = running.join_next().await {
+            let (client_id, (outcome, result)) = joined.map_err(|e| e.to_string())?;
+            if let Some(result) = result {
+                received.insert(client_id.clone(), result);
+            }
+            outcomes.insert(client_id, outcome);
+        }
+        let result = if outcomes.values().any(|outcome| matches!(outcome, ClientOutcome::Accepted { .. })) {
+            Some(results.recv().await.map_err(|e| format!("no round result: {}", e))?)
+        } else {
+            None
+        };
+        rounds.abort();
+
+        Ok(Report {
+            result,
+            quorum: self.quorum,
+            strategy: self.strategy,
+            averages,
+            faults: self.faults,
+            outcomes,
+            received,
+            keys,
+        })
+    }
+
+    /// A random walk of `ticks` prices, the market every client samples.
+    fn market(&mut self) -> Vec<f64> {
+        let mut price = START_PRICE;
+        (0..self.ticks)
+            .map(|_| {
+                price *= 1.0 + self.rng.gen_range(-VOLATILITY..=VOLATILITY);
+                price
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What must hold for any round, whatever went wrong in it.
+    fn check_invariants(report: &Report) {
+        let accepted = report.accepted();
+        let Some(result) = &report.result else {
+            assert!(accepted.is_empty());
+            return;
+        };
+        assert_eq!(result.contributors, accepted.keys().cloned().collect::<Vec<_>>());
+        assert_eq!(result.average, report.expected_average());
+        for (client_id, faults) in &report.faults {
+            if faults.iter().any(|fault| matches!(fault, Fault::BadSignature | Fault::DropSubmission | Fault::CrashBeforeSubmit)) {
+                assert!(!accepted.contains_key(client_id), "{} should not have contributed", client_id);
+            }
+            if faults.contains(&Fault::DropProposal) {
+                assert!(!report.endorsed().contains(client_id.as_str()), "{} endorsed a proposal it never got", client_id);
+            }
+            if faults.contains(&Fault::DropResult) {
+                assert!(!report.received.contains_key(client_id), "{} got a result that was lost", client_id);
+            }
+        }
+        // The average went through JSON on the way, so it is only compared
+        // by what decided it.
+        for received in report.received.values() {
+            assert_eq!((received.round_id, &received.contributors), (result.round_id, &result.contributors));
+        }
+        match &result.attestation {
+            Some(attestation) => {
+                assert_eq!(attestation.signer_ids().into_iter().collect::<BTreeSet<_>>(), report.endorsed());
+                assert_eq!(attestation::verify(result, &report.keys, report.quorum), Ok(()));
+            }
+            None => assert!(result.average.is_none() || report.endorsed().len() < report.quorum),
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_honest_round_is_aggregated_and_attested() {
+        let report = Simulation::new(1, 5).run().await.unwrap();
+        check_invariants(&report);
+        assert_eq!(report.result.as_ref().unwrap().contributors.len(), 5);
+        assert!((report.result.as_ref().unwrap().average.unwrap() - START_PRICE).abs() < START_PRICE * 0.02);
+        assert_eq!(report.endorsed().len(), 5);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_each_fault_has_the_expected_effect() {
+        let report = Simulation::new(2, 7)
+            .with_quorum(3)
+            .with_deadline(Duration::from_secs(5))
+            .with_fault("client1", Fault::BadSignature)
+            .with_fault("client2", Fault::ClockSkew(TimeDelta::minutes(2)))
+            .with_fault("client3", Fault::Latency(Duration::from_secs(60)))
+            .with_fault("client4", Fault::CrashBeforeSubmit)
+            .with_fault("client5", Fault::DropAttestation)
+            .with_fault("client6", Fault::ClockSkew(TimeDelta::seconds(-10)))
+            .with_fault("client6", Fault::Latency(Duration::from_secs(2)))
+            .run()
+            .await
+            .unwrap();
+        check_invariants(&report);
+        assert_eq!(report.outcomes["client1"], ClientOutcome::Rejected("invalid signature".to_string()));
+        assert!(matches!(&report.outcomes["client2"], ClientOutcome::Rejected(reason) if reason.starts_with("stale message")));
+        assert_eq!(
+            report.outcomes["client3"],
+            ClientOutcome::Rejected("message is for round 1 but round 2 is open".to_string())
+        );
+        assert_eq!(report.outcomes["client4"], ClientOutcome::NotSubmitted);
+        assert_eq!(report.outcomes["client5"], ClientOutcome::Accepted { endorsed: false });
+        assert_eq!(report.outcomes["client6"], ClientOutcome::Accepted { endorsed: true });
+        assert_eq!(report.result.as_ref().unwrap().contributors, vec!["client5", "client6", "client7"]);
+        assert_eq!(report.result.as_ref().unwrap().attestation, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_lost_or_late_frames_to_clients() {
+        let report = Simulation::new(4, 5)
+            .with_quorum(3)
+            .with_deadline(Duration::from_secs(5))
+            .with_fault("client1", Fault::DropProposal)
+            .with_fault("client2", Fault::DropResult)
+            .with_fault("client3", Fault::DownstreamLatency(Duration::from_secs(60)))
+            .with_fault("client4", Fault::DownstreamLatency(Duration::from_secs(1)))
+            .run()
+            .await
+            .unwrap();
+        check_invariants(&report);
+        // Losing frames on the way back costs the endorsement or the result,
+        // never the contribution.
+        assert_eq!(report.accepted().len(), 5);
+        assert_eq!(report.outcomes["client1"], ClientOutcome::Accepted { endorsed: false });
+        assert_eq!(report.outcomes["client2"], ClientOutcome::Accepted { endorsed: true });
+        assert_eq!(report.outcomes["client3"], ClientOutcome::Accepted { endorsed: false });
+        assert_eq!(report.outcomes["client4"], ClientOutcome::Accepted { endorsed: true });
+        assert_eq!(report.endorsed(), BTreeSet::from(["client2", "client4", "client5"]));
+        // client3's late endorsement is refused, and the result went by
+        // while it waited for that reply.
+        assert_eq!(report.received.keys().collect::<Vec<_>>(), vec!["client1", "client4", "client5"]);
+        assert!(report.result.as_ref().unwrap().attestation.is_some());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_round_fails_below_quorum() {
+        let report = Simulation::new(3, 3)
+            .with_fault("client1", Fault::CrashBeforeSubmit)
+            .with_fault("client2", Fault::DropSubmission)
+            .run()
+            .await
+            .unwrap();
+        check_invariants(&report);
+        assert_eq!(report.result.as_ref().unwrap().contributors, vec!["client3"]);
+        assert_eq!(report.result.as_ref().unwrap().average, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_same_seed_replays_the_same_round() {
+        let run = |seed| Simulation::new(seed, 5).with_quorum(3).with_chaos(0.5).run();
+        let (first, second) = (run(42).await.unwrap(), run(42).await.unwrap());
+        assert!(!first.faults.is_empty());
+        assert_eq!(first.faults, second.faults);
+        assert_eq!(first.averages, second.averages);
+        assert_eq!(first.outcomes, second.outcomes);
+        // Only the wall-clock closing time, and the signatures over it, differ.
+        let decided = |report: &Report| {
+            let result = report.result.clone().unwrap();
+            (result.contributors, result.average, result.attestation.map(|attestation| attestation.signers))
+        };
+        assert_eq!(decided(&first), decided(&second));
+        assert_ne!(run(43).await.unwrap().averages, first.averages);
+    }
+
+    /// A proposal carries every input, so it outgrows the frames of a small
+    /// cluster: 50 clients come close to [`protocol::MAX_FRAME_LENGTH`],
+    /// 100 are well past it.
+    #[tokio::test(start_paused = true)]
+    async fn test_large_clusters_fit_in_a_frame() {
+        for clients in [50, 100] {
+            let report = Simulation::new(50, clients).with_quorum(clients * 4 / 5).run().await.unwrap();
+            check_invariants(&report);
+            assert_eq!(report.result.as_ref().unwrap().contributors.len(), clients);
+            assert_eq!(report.endorsed().len(), clients);
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_chaos_never_breaks_the_invariants() {
+        for seed in 0..20 {
+            let report = Simulation::new(seed, 5)
+                .with_quorum(3)
+                .with_strategy(Strategy::Median)
+                .with_chaos(0.6)
+                .run()
+                .await
+                .unwrap();
+            check_invariants(&report);
+        }
+    }
+}
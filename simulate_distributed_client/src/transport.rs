@@ -0,0 +1,180 @@
+use crate::protocol::{self, MessageCodec, PROTOCOL_VERSION, ProtocolError};
+use bytes::Bytes;
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+use zeroize::Zeroizing;
+
+/// Noise IK: clients know the aggregator's key from the registry, and their
+/// own static key travels encrypted in the first message, so a single round
+/// trip authenticates both sides.
+const PATTERN: &str = "Noise_IK_25519_ChaChaPoly_BLAKE2s";
+/// Comfortably above the 96 and 48 byte IK handshake messages.
+const MAX_HANDSHAKE_MESSAGE: usize = 1024;
+
+/// Binds the handshake to the protocol version, so peers speaking different
+/// versions fail to connect instead of misreading each other.
+fn prologue() -> Vec<u8> {
+    format!("simulate_distributed_client protocol {}", PROTOCOL_VERSION).into_bytes()
+}
+
+/// The X25519 private key of an ed25519 identity, so that the key a client
+/// signs with is also the one it is authenticated by.
+pub fn static_private(signing_key: &SigningKey) -> Zeroizing<[u8; 32]> {
+    Zeroizing::new(signing_key.to_scalar_bytes())
+}
+
+pub fn static_public(verifying_key: &VerifyingKey) -> [u8; 32] {
+    verifying_key.to_montgomery().to_bytes()
+}
+
+fn builder<'a>(private: &'a [u8], prologue: &'a [u8]) -> snow::Builder<'a> {
+    snow::Builder::new(PATTERN.parse().expect("valid Noise pattern"))
+        .local_private_key(private)
+        .prologue(prologue)
+}
+
+fn handshake_error(e: snow::Error) -> ProtocolError {
+    ProtocolError::Handshake(e.to_string())
+}
+
+async fn receive<S>(framed: &mut Framed<S, LengthDelimitedCodec>) -> Result<Bytes, ProtocolError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    match framed.next().await {
+        Some(frame) => Ok(frame.map_err(|e| ProtocolError::from_codec(e, protocol::MAX_FRAME_LENGTH))?.freeze()),
+        None => Err(ProtocolError::Closed),
+    }
+}
+
+fn secure<S>(framed: Framed<S, LengthDelimitedCodec>, handshake: snow::HandshakeState) -> Result<Framed<S, MessageCodec>, ProtocolError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let session = handshake.into_transport_mode().map_err(handshake_error)?;
+    Ok(framed.map_codec(|frames| MessageCodec::secure(frames, session)))
+}
+
+/// Opens an encrypted channel to the aggregator, or to another peer in
+/// gossip mode, authenticating as `identity` and accepting only a peer that
+/// holds the `remote` key.
+pub async fn connect<S>(stream: S, identity: &SigningKey, remote: &VerifyingKey) -> Result<Framed<S, MessageCodec>, ProtocolError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let private = static_private(identity);
+    let remote = static_public(remote);
+    let prologue = prologue();
+    let mut handshake = builder(private.as_slice(), &prologue)
+        .remote_public_key(&remote)
+        .build_initiator()
+        .map_err(handshake_error)?;
+    let mut framed = Framed::new(stream, protocol::frame_codec());
+    let mut buffer = [0u8; MAX_HANDSHAKE_MESSAGE];
+
+    let length = handshake.write_message(&[], &mut buffer).map_err(handshake_error)?;
+    framed.send(Bytes::copy_from_slice(&buffer[..length])).await?;
+    let reply = receive(&mut framed).await?;
+    handshake
+        .read_message(&reply, &mut buffer)
+        .map_err(|_| ProtocolError::Handshake("the peer did not prove its identity".to_string()))?;
+    secure(framed, handshake)
+}
+
+/// Accepts an encrypted channel as `identity` from a client that `authorize`
+/// maps from its [`static_public`] key to a client id, and returns it with
+/// that id. The lookup happens during the handshake, so it sees the registry
+/// as it is when the client connects.
+pub async fn accept<S, F>(stream: S, identity: &SigningKey, authorize: F) -> Result<(Framed<S, MessageCodec>, String), ProtocolError>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+    F: Fn(&[u8; 32]) -> Option<String>,
+{
+    let private = static_private(identity);
+    let prologue = prologue();
+    let mut handshake = builder(private.as_slice(), &prologue)
+        .build_responder()
+        .map_err(handshake_error)?;
+    let mut framed = Framed::new(stream, protocol::frame_codec());
+    let mut buffer = [0u8; MAX_HANDSHAKE_MESSAGE];
+
+    let hello = receive(&mut framed).await?;
+    handshake.read_message(&hello, &mut buffer).map_err(handshake_error)?;
+    let client_id = handshake
+        .get_remote_static()
+        .and_then(|key| <[u8; 32]>::try_from(key).ok())
+        .and_then(|key| authorize(&key))
+        .ok_or_else(|| ProtocolError::Handshake("the client key is not registered".to_string()))?;
+    let length = handshake.write_message(&[], &mut buffer).map_err(handshake_error)?;
+    framed.send(Bytes::copy_from_slice(&buffer[..length])).await?;
+    Ok((secure(framed, handshake)?, client_id))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::protocol::Message;
+
+    fn identity(id: u8) -> SigningKey {
+        SigningKey::from_bytes(&[id; 32])
+    }
+
+    fn registered(ids: &[u8]) -> impl Fn(&[u8; 32]) -> Option<String> {
+        let ids = ids.to_vec();
+        move |key| {
+            ids.iter()
+                .find(|id| &static_public(&identity(**id).verifying_key()) == key)
+                .map(|id| format!("client{}", id))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_both_sides_are_authenticated_and_messages_encrypted() {
+        let (client, server) = tokio::io::duplex(4096);
+        let aggregator = identity(100);
+        let server = tokio::spawn(async move {
+            let (mut framed, client_id) = accept(server, &aggregator, &registered(&[1, 2])).await.unwrap();
+            let message = framed.next().await.unwrap().unwrap();
+            framed.send(Message::Ack).await.unwrap();
+            (client_id, message)
+        });
+        let mut framed = connect(client, &identity(2), &identity(100).verifying_key()).await.unwrap();
+        let hello = Message::Hello { client_id: "client2".to_string() };
+        framed.send(hello.clone()).await.unwrap();
+        assert_eq!(framed.next().await.unwrap().unwrap(), Message::Ack);
+        assert_eq!(server.await.unwrap(), ("client2".to_string(), hello));
+    }
+
+    #[tokio::test]
+    async fn test_unregistered_clients_and_impostor_aggregators_are_refused() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move { accept(server, &identity(100), &registered(&[1])).await.map(|(_, id)| id) });
+        let result = connect(client, &identity(9), &identity(100).verifying_key()).await;
+        assert!(matches!(server.await.unwrap(), Err(ProtocolError::Handshake(_))));
+        assert!(result.is_err());
+
+        // Someone without the aggregator's key cannot complete the handshake.
+        let (client, server) = tokio::io::duplex(4096);
+        tokio::spawn(async move { accept(server, &identity(66), &registered(&[1])).await.map(|(_, id)| id) });
+        let result = connect(client, &identity(1), &identity(100).verifying_key()).await;
+        assert!(matches!(result, Err(ProtocolError::Handshake(_)) | Err(ProtocolError::Closed)));
+    }
+
+    #[tokio::test]
+    async fn test_frames_longer_than_a_noise_message_are_sent_in_chunks() {
+        let (client, server) = tokio::io::duplex(4096);
+        let server = tokio::spawn(async move {
+            let (mut framed, _) = accept(server, &identity(100), &registered(&[1])).await.unwrap();
+            framed.codec_mut().set_max_frame_length(protocol::max_frame_length(200));
+            framed.next().await.unwrap()
+        });
+        let mut framed = connect(client, &identity(1), &identity(100).verifying_key()).await.unwrap();
+        let long = Message::Error { reason: "x".repeat(150_000) };
+        assert!(matches!(framed.send(long.clone()).await, Err(ProtocolError::Oversized(_))));
+        framed.codec_mut().set_max_frame_length(protocol::max_frame_length(200));
+        framed.send(long.clone()).await.unwrap();
+        assert_eq!(server.await.unwrap().unwrap(), long);
+    }
+}